@@ -1,5 +1,5 @@
 #![feature(allocator_api)]
-use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
 
 extern crate lock_free_buddy_allocator;
 
@@ -9,7 +9,6 @@ use lock_free_buddy_allocator::cpuid;
 use std::{
     alloc::{Allocator, Global},
     num::NonZeroU64,
-    sync::Arc,
     thread::{self, ThreadId},
 };
 
@@ -27,13 +26,11 @@ impl cpuid::Cpu for Cpu {
     }
 }
 
-fn buddy_alloc_test<A: Allocator>(n: usize, buddy: BuddyAlloc<PAGE_SIZE, Cpu, A>) {
-    let b = Arc::new(buddy);
-
+fn buddy_alloc_test<A: Allocator>(n: usize, buddy: &BuddyAlloc<PAGE_SIZE, Cpu, A>) {
     std::thread::scope(|s| {
         let w_ths: Vec<_> = (0..n)
             .map(|_| {
-                let b = b.clone();
+                let b = buddy;
                 s.spawn(move || {
                     for _ in 0..512 {
                         b.alloc(8).unwrap();
@@ -48,18 +45,148 @@ fn buddy_alloc_test<A: Allocator>(n: usize, buddy: BuddyAlloc<PAGE_SIZE, Cpu, A>
     });
 }
 
+fn buddy_free_test<A: Allocator>(buddy: &BuddyAlloc<PAGE_SIZE, Cpu, A>, blocks: Vec<Vec<usize>>) {
+    std::thread::scope(|s| {
+        let w_ths: Vec<_> = blocks
+            .into_iter()
+            .map(|addrs| {
+                let b = buddy;
+                s.spawn(move || {
+                    for i in addrs {
+                        b.free(i, 8);
+                    }
+                })
+            })
+            .collect();
+
+        for th in w_ths {
+            th.join().unwrap();
+        }
+    });
+}
+
+// The allocators below are built once and reset before each iteration, so construction
+// isn't measured. Resets have to run right before their iteration, hence `PerIteration`.
+// Setup and routine run one after the other and the threads of the routine are joined
+// before it returns, so nothing else touches an allocator while it's reset.
 pub fn criterion_benchmark(c: &mut Criterion) {
     for s in &[1, 5, 10] {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, *s * 4096, &Global).unwrap();
+
         c.bench_with_input(BenchmarkId::new("lf_buddy_single", s), s, |b, i| {
-            b.iter(|| {
-                buddy_alloc_test(
-                    *i,
-                    BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, *s * 4096, &Global).unwrap(),
-                )
-            });
+            b.iter_batched(
+                || unsafe { buddy.bench_reset() },
+                |()| buddy_alloc_test(*i, &buddy),
+                BatchSize::PerIteration,
+            );
+        });
+    }
+}
+
+pub fn page_benchmark(c: &mut Criterion) {
+    // Bottom level nodes of a 4096 page arena are container roots, so every alloc here
+    // goes through the fast path
+    let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 4096, &Global).unwrap();
+
+    c.bench_function("lf_buddy_page", |b| {
+        b.iter_batched(
+            || unsafe { buddy.bench_reset() },
+            |()| {
+                for _ in 0..512 {
+                    buddy.alloc(1).unwrap();
+                }
+            },
+            BatchSize::PerIteration,
+        );
+    });
+}
+
+pub fn free_benchmark(c: &mut Criterion) {
+    for s in &[1, 5, 10] {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, *s * 4096, &Global).unwrap();
+
+        c.bench_with_input(BenchmarkId::new("lf_buddy_free", s), s, |b, i| {
+            // Allocation happens in the setup closure, so only frees are measured
+            b.iter_batched(
+                || {
+                    unsafe { buddy.bench_reset() };
+
+                    (0..*i)
+                        .map(|_| (0..512).map(|_| buddy.alloc(8).unwrap()).collect())
+                        .collect()
+                },
+                |blocks| buddy_free_test(&buddy, blocks),
+                BatchSize::PerIteration,
+            );
         });
     }
 }
 
-criterion_group!(benches, criterion_benchmark);
+pub fn ping_pong_benchmark(c: &mut Criterion) {
+    for depth in [0, 8] {
+        c.bench_with_input(
+            BenchmarkId::new("lf_buddy_ping_pong", depth),
+            &depth,
+            |b, d| {
+                let mut buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 4096, &Global).unwrap();
+                buddy.set_order_cache(*d);
+
+                // Only one 256 page block stays free, so without the cache every free of a
+                // page merges it back into that block
+                let _others: Vec<_> = (0..15).map(|_| buddy.alloc(256).unwrap()).collect();
+
+                b.iter(|| {
+                    for _ in 0..512 {
+                        let addr = buddy.alloc(1).unwrap();
+                        buddy.free(addr, 1);
+                    }
+                });
+            },
+        );
+    }
+}
+
+// Compare with `--features lazy-init`, which doesn't fill in the nodes
+pub fn construction_benchmark(c: &mut Criterion) {
+    c.bench_function("lf_buddy_new_order_20", |b| {
+        b.iter(|| BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1 << 20, &Global).unwrap());
+    });
+}
+
+// Allocation throughput against the arena size, the id carries the metadata footprint
+pub fn footprint_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lf_buddy_footprint");
+
+    for order in [5, 9, 13, 17] {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1 << order, &Global).unwrap();
+        let count = (1usize << order).min(256);
+        let mut addrs = Vec::with_capacity(count);
+
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_function(
+            BenchmarkId::new(format!("order_{order}"), buddy.metadata_bytes()),
+            |b| {
+                b.iter(|| {
+                    addrs.extend((0..count).map(|_| buddy.alloc(1).unwrap()));
+
+                    for addr in addrs.drain(..) {
+                        buddy.free(addr, 1);
+                    }
+                });
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    criterion_benchmark,
+    page_benchmark,
+    free_benchmark,
+    ping_pong_benchmark,
+    construction_benchmark,
+    footprint_benchmark
+);
 criterion_main!(benches);
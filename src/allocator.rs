@@ -0,0 +1,96 @@
+use core::alloc::{AllocError, Allocator, Layout};
+use core::ptr::NonNull;
+
+use crate::buddy_alloc::BuddyAlloc;
+use crate::cpuid::Cpu;
+use crate::oom::OomHandler;
+
+/// [`Allocator`] serving blocks from the arena, see [`BuddyAlloc::as_allocator`].
+///
+/// Every request takes a whole block of the order picked by
+/// [`BuddyAlloc::order_for_layout`], so an over-aligned small layout gets a block as large
+/// as its alignment.
+pub struct ArenaAllocator<'b, 'a, const PAGE_SIZE: usize, C: Cpu, A: Allocator, H: OomHandler> {
+    buddy: &'b BuddyAlloc<'a, PAGE_SIZE, C, A, H>,
+}
+
+impl<'b, 'a, const PAGE_SIZE: usize, C: Cpu, A: Allocator, H: OomHandler>
+    ArenaAllocator<'b, 'a, PAGE_SIZE, C, A, H>
+{
+    pub(crate) fn new(buddy: &'b BuddyAlloc<'a, PAGE_SIZE, C, A, H>) -> Self {
+        Self { buddy }
+    }
+
+    fn order(&self, layout: Layout) -> Result<usize, AllocError> {
+        self.buddy.order_for_layout(layout).map_err(|_| AllocError)
+    }
+
+    // Keeps the block if the new layout maps to the same order, moves the data otherwise
+    unsafe fn resize(
+        &self,
+        ptr: NonNull<u8>,
+        old: Layout,
+        new: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let order = self.order(new)?;
+
+        if order == self.order(old)? && (ptr.as_ptr() as usize).is_multiple_of(new.align()) {
+            return Ok(NonNull::slice_from_raw_parts(ptr, PAGE_SIZE << order));
+        }
+
+        let new_ptr = self.allocate(new)?;
+
+        core::ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.cast().as_ptr(),
+            old.size().min(new.size()),
+        );
+        self.deallocate(ptr, old);
+
+        Ok(new_ptr)
+    }
+}
+
+unsafe impl<const PAGE_SIZE: usize, C: Cpu, A: Allocator, H: OomHandler> Allocator
+    for ArenaAllocator<'_, '_, PAGE_SIZE, C, A, H>
+{
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let pages = 1 << self.order(layout)?;
+        let addr = self.buddy.alloc(pages).ok_or(AllocError)?;
+
+        // Blocks are aligned relative to the arena start only
+        match NonNull::new(addr as *mut u8) {
+            Some(ptr) if addr.is_multiple_of(layout.align()) => {
+                Ok(NonNull::slice_from_raw_parts(ptr, pages * PAGE_SIZE))
+            }
+            _ => {
+                self.buddy.free(addr, pages);
+                Err(AllocError)
+            }
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if let Ok(order) = self.order(layout) {
+            self.buddy.free(ptr.as_ptr() as usize, 1 << order);
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
+}
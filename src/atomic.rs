@@ -0,0 +1,13 @@
+// Atomics used throughout the crate. With `portable-atomic` they come from the crate of the
+// same name, which emulates the operations a target lacks, e.g. compare-and-swap on
+// some RISC-V and Cortex-M0 cores. The tests built with `--cfg loom` take them from loom,
+// which then sees every access to the tree.
+
+#[cfg(all(not(all(test, loom)), not(feature = "portable-atomic")))]
+pub use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+#[cfg(all(not(all(test, loom)), feature = "portable-atomic"))]
+pub use portable_atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+#[cfg(all(test, loom))]
+pub use loom::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
@@ -1,348 +1,1830 @@
-use core::alloc::Allocator;
-use core::sync::atomic::Ordering;
-
-use crate::cpuid::Cpu;
-use crate::tree::{Node, Tree};
+use crate::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::alloc::{Allocator, Layout};
+use core::fmt;
+
+use crate::allocator::ArenaAllocator;
+use crate::cpuid::{BuddyToken, Cpu};
+use crate::error::{AllocError, BuddyInitError, FreeError, InvariantError};
+use crate::numa::NumaMap;
+use crate::oom::{FailFast, OomAction, OomHandler};
+#[cfg(feature = "oplog")]
+use crate::oplog::{LoggedOp, Op, OpLog};
+use crate::owned::OwnedPages;
+use crate::region::RegionHandle;
+use crate::slots::Slots;
+use crate::state::{NodeState, CONTAINER_LEVELS, FIRST_LEAF};
+#[cfg(feature = "stats")]
+use crate::stats::{BuddyStats, Stats};
+use crate::tree::{Node, NodeContainer, Tree};
+use crate::units::{order_for_bytes, ByteAddr};
+use crate::view::BuddyView;
 use core::marker::PhantomData;
 
-const COALESCE_LEFT: usize = 0x8;
-const COALESCE_RIGHT: usize = 0x4;
+const LAZY_FREE_SLOTS: usize = 64;
+const LAZY_FREE_PER_CPU: usize = 8;
+const ORDER_CACHE_ORDERS: usize = 8;
+const ORDER_CACHE_DEPTH: usize = 8;
+const FREE_PAGE_SHARDS: usize = 8;
+#[cfg(feature = "yield")]
+const YIELD_AFTER: usize = 4;
+
+// Transitions applied to nodes above container leaves and to container leaves
+type SetBits = (
+    fn(NodeState, u8) -> NodeState,
+    fn(NodeState, u8) -> NodeState,
+);
+
+const LOCK: SetBits = (NodeState::lock_not_leaf, NodeState::lock_leaf);
+const UNLOCK: SetBits = (NodeState::unlock_not_leaf, NodeState::unlock_leaf);
+
+// A shard of the free page counter on a cache line of its own. Shards wrap around, only
+// their sum is meaningful.
+#[repr(align(64))]
+struct FreeShard(AtomicUsize);
+
+/// How often `alloc` continues from the start of the arena after its scan reached the end.
+///
+/// Scans start at a block picked by the CPU, so without a restart the blocks before it
+/// aren't visited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Give up at the end of the arena, bounding the scan for real-time callers
+    Never,
+    /// Visit every block once
+    #[default]
+    Once,
+    /// Run over the whole arena this many times, retrying blocks that were busy
+    Times(usize),
+}
 
-pub struct BuddyAlloc<'a, const PAGE_SIZE: usize, C: Cpu, A: Allocator + 'a> {
+impl RestartPolicy {
+    const fn max_wraps(self) -> usize {
+        match self {
+            RestartPolicy::Never => 0,
+            RestartPolicy::Once => 1,
+            RestartPolicy::Times(n) => n,
+        }
+    }
+}
+
+/// Lock-free buddy allocator over `PAGE_SIZE` pages of some address range.
+///
+/// `PAGE_SIZE` must be a power of two, anything else fails to compile:
+///
+/// ```compile_fail,E0080
+/// #![feature(allocator_api)]
+/// use lock_free_buddy_allocator::{buddy_alloc::BuddyAlloc, cpuid::Cpu};
+///
+/// struct Cpu0;
+///
+/// impl Cpu for Cpu0 {
+///     fn current_cpu() -> usize {
+///         0
+///     }
+/// }
+///
+/// BuddyAlloc::<4000, Cpu0, _>::new(0, 16, &std::alloc::Global);
+/// ```
+pub struct BuddyAlloc<
+    'a,
+    const PAGE_SIZE: usize,
+    C: Cpu,
+    A: Allocator + 'a,
+    H: OomHandler = FailFast,
+> {
     tree: Tree<'a, PAGE_SIZE, A>,
     start: usize,
     size: usize,
     num_pages: usize,
+    lazy: Slots<LAZY_FREE_SLOTS>,
+    cache: Slots<{ ORDER_CACHE_ORDERS * ORDER_CACHE_DEPTH }>,
+    cache_depth: usize,
+    free_shards: [FreeShard; FREE_PAGE_SHARDS],
+    restart: RestartPolicy,
+    attempt_limit: Option<usize>,
+    low_watermark: AtomicUsize,
+    poisoned: AtomicBool,
+    oom: H,
+    #[cfg(feature = "stats")]
+    stats: Stats,
+    #[cfg(feature = "oplog")]
+    oplog: OpLog,
+    #[cfg(test)]
+    panic_in_commit: AtomicBool,
+    #[cfg(test)]
+    no_fast_path: AtomicBool,
+    #[cfg(test)]
+    failing_updates: AtomicUsize,
     _d: PhantomData<C>,
 }
 
+// Poisons the allocator if dropped while an allocation is half committed. The tree
+// can't be rolled back at that point, since other threads may already rely on the
+// ancestor states set so far.
+struct CommitGuard<'p>(&'p AtomicBool);
+
+impl Drop for CommitGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
 impl<'a, const PAGE_SIZE: usize, C: Cpu, A: Allocator + 'a> BuddyAlloc<'a, PAGE_SIZE, C, A> {
+    /// Creates an allocator over `pages` pages starting at `start`.
+    ///
+    /// `pages` is rounded up to a power of two and the whole rounded arena is handed out,
+    /// see [`Self::pages`]. If less memory is really there, use [`Self::new_sized`], reserve
+    /// the tail with [`crate::builder::BuddyAllocBuilder::reserved`] or use
+    /// [`Self::for_region`], which refuses to round.
+    ///
+    /// `backend` only provides the metadata, see [`Self::metadata_bytes`]. The arena is
+    /// never touched by the allocator itself, it may come from anywhere else, e.g. a frame
+    /// pool with the metadata on a general heap.
+    pub fn new(start: usize, pages: usize, backend: &'a A) -> Option<Self> {
+        Self::with_oom_handler(start, pages, backend, FailFast)
+    }
+
+    /// Same as [`Self::new`], but never splits blocks below `1 << min_order` pages.
+    ///
+    /// Smaller requests are served by blocks of that order, and the tree only has a leaf
+    /// per such block, which cuts the metadata by about `1 << min_order` as well. Fails if
+    /// the arena is smaller than one block.
+    pub fn with_min_order(
+        start: usize,
+        pages: usize,
+        min_order: usize,
+        backend: &'a A,
+    ) -> Option<Self> {
+        Self::create(start, pages, min_order, backend, FailFast)
+    }
+
+    /// Same as [`Self::new`], but fails before allocating anything if the metadata for
+    /// `pages` pages, see [`Self::metadata_bytes`], takes more than `max_metadata_bytes`.
+    pub fn new_within_budget(
+        start: usize,
+        pages: usize,
+        max_metadata_bytes: usize,
+        backend: &'a A,
+    ) -> Result<Self, BuddyInitError> {
+        pages
+            .checked_next_power_of_two()
+            .filter(|p| p.checked_mul(PAGE_SIZE * 2).is_some())
+            .ok_or(BuddyInitError::InvalidOrder)?;
+
+        if Tree::<PAGE_SIZE, A>::metadata_bytes(pages, 0) > max_metadata_bytes {
+            return Err(BuddyInitError::MetadataTooLarge);
+        }
+
+        Self::new(start, pages, backend).ok_or(BuddyInitError::OutOfMemory)
+    }
+
+    /// Same as [`Self::new`], but reserves the pages added by rounding up, so only the first
+    /// `pages` pages are ever handed out.
+    ///
+    /// Unless `pages` is a power of two, the top order of the tree can't be allocated then
+    /// and the largest block is at most half the rounded arena.
+    pub fn new_sized(start: usize, pages: usize, backend: &'a A) -> Option<Self> {
+        let buddy = Self::new(start, pages, backend)?;
+
+        buddy
+            .reserve_range(pages, buddy.pages() - pages)
+            .then_some(buddy)
+    }
+
+    /// Creates an allocator over `pages` pages at `start` with the pages whose bits are set
+    /// in `used` taken.
+    ///
+    /// Bit `i` of word `w` stands for page `w * usize::BITS + i`, pages past the end of
+    /// `used` are free. Every run of set bits is taken as the fewest aligned blocks covering
+    /// it, which stay allocated until freed with the same blocks. Fails if a set bit lies
+    /// outside of the arena.
+    pub fn new_from_bitmap(
+        start: usize,
+        pages: usize,
+        used: &[usize],
+        backend: &'a A,
+    ) -> Option<Self> {
+        let buddy = Self::new(start, pages, backend)?;
+        let bits = usize::BITS as usize;
+        let is_used = |page: usize| used[page / bits] & (1 << (page % bits)) != 0;
+        let mut page = 0;
+
+        while page < used.len() * bits {
+            if !is_used(page) {
+                page += 1;
+                continue;
+            }
+
+            let first = page;
+
+            while page < used.len() * bits && is_used(page) {
+                page += 1;
+            }
+
+            if page > buddy.num_pages || !buddy.reserve_range(first, page - first) {
+                return None;
+            }
+        }
+
+        Some(buddy)
+    }
+
+    /// Combines two allocators managing adjacent arenas of the same size into one twice
+    /// as large.
+    ///
+    /// Allocations of both are preserved and have to be freed with the sizes they were
+    /// allocated with. Metadata of the new tree comes from the backend of `lower`.
+    pub fn merge(lower: Self, upper: Self) -> Option<Self> {
+        Self::merge_with_oom_handler(lower, upper, FailFast)
+    }
+
+    /// Same as [`Self::new`] with the arena start typed as an address.
+    pub fn new_at(start: ByteAddr, pages: usize, backend: &'a A) -> Option<Self> {
+        Self::new(start.0, pages, backend)
+    }
+
+    /// Creates an allocator managing `size_bytes` bytes at `base`.
+    ///
+    /// `size_bytes` has to be a power of two number of pages, `None` is returned otherwise.
+    pub fn for_region(base: usize, size_bytes: usize, backend: &'a A) -> Option<Self> {
+        let pages = size_bytes / PAGE_SIZE;
+
+        if !size_bytes.is_multiple_of(PAGE_SIZE) || !pages.is_power_of_two() {
+            return None;
+        }
+
+        Self::new(base, pages, backend)
+    }
+
+    /// Creates an allocator handing out physical addresses starting at `phys_base`.
+    ///
+    /// Metadata still comes from `backend` in the virtual address space. Addresses returned
+    /// by `alloc` are physical and must not be dereferenced, e.g. `alloc_zeroed` can't be
+    /// used on such an arena.
+    pub fn new_phys(phys_base: usize, pages: usize, backend: &'a A) -> Option<Self> {
+        Self::new(phys_base, pages, backend)
+    }
+}
+
+impl<'a, const PAGE_SIZE: usize, C: Cpu, A: Allocator + 'a, H: OomHandler>
+    BuddyAlloc<'a, PAGE_SIZE, C, A, H>
+{
+    // Block sizes and positions are derived with shifts and `ilog2`, which only works out
+    // for power of two page sizes
+    const PAGE_SIZE_OK: () = assert!(
+        PAGE_SIZE.is_power_of_two(),
+        "PAGE_SIZE must be a power of two"
+    );
+
     #[inline]
     fn level(&self, node: &Node) -> usize {
-        self.tree.height() - (node.size / PAGE_SIZE).ilog2() as usize
+        self.tree.height() + self.min_order() - (node.size / PAGE_SIZE).ilog2() as usize
     }
 
+    // Rounds a request up to the size of the block serving it
     #[inline]
-    fn is_allocable(val: usize, pos: u8) -> bool {
-        if pos < 8 {
-            (val & (0x1 << (pos - 1))) == 0
-        } else {
-            val & ((0x1F << 7) << (5 * (pos - 8))) == 0
+    fn block_pages(&self, pages: usize) -> usize {
+        pages.next_power_of_two().max(1 << self.min_order())
+    }
+
+    // Updates the state of the container holding `node`
+    #[inline]
+    fn update(&self, node: &Node, old: NodeState, new: NodeState) -> bool {
+        let done = self.cas(self.tree.container_of(node), old, new);
+
+        #[cfg(feature = "oplog")]
+        if done {
+            let root = self.tree.container_root_pos(node);
+
+            self.oplog.record(Op::Alloc, root, old.0, new.0);
+        }
+
+        done
+    }
+
+    #[inline]
+    fn cas(&self, container: &NodeContainer, old: NodeState, new: NodeState) -> bool {
+        #[cfg(feature = "stats")]
+        self.stats.cas.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(test)]
+        if self
+            .failing_updates
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            return false;
+        }
+
+        // An allocation acquires what the free it takes the block from did before, e.g. the
+        // cleared page order, which an allocation recording its own would race with otherwise
+        container
+            .nodes
+            .compare_exchange(old.0, new.0, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    #[inline]
+    fn free_update(&self, node: &Node, old: NodeState, new: NodeState) -> bool {
+        let done = self.cas(self.tree.container_of(node), old, new);
+
+        #[cfg(feature = "oplog")]
+        if done {
+            let root = self.tree.container_root_pos(node);
+
+            self.oplog.record(Op::Free, root, old.0, new.0);
+        }
+
+        #[cfg(feature = "stats")]
+        if !done {
+            self.stats.free_retries.fetch_add(1, Ordering::Relaxed);
+        }
+
+        done
+    }
+
+    /// Same as [`BuddyAlloc::merge`], the merged allocator asks `oom` what to do when an
+    /// allocation doesn't fit.
+    pub fn merge_with_oom_handler(lower: Self, upper: Self, oom: H) -> Option<Self> {
+        if lower.num_pages != upper.num_pages
+            || lower.min_order() != upper.min_order()
+            || lower.start.checked_add(lower.size) != Some(upper.start)
+            || lower.is_poisoned()
+            || upper.is_poisoned()
+        {
+            return None;
+        }
+
+        lower.flush();
+        upper.flush();
+
+        let mut merged = Self::create(
+            lower.start,
+            lower.num_pages * 2,
+            lower.min_order(),
+            lower.tree.backend(),
+            oom,
+        )?;
+        merged.restart = lower.restart;
+        merged.attempt_limit = lower.attempt_limit;
+        merged.set_low_watermark(lower.low_watermark.load(Ordering::Relaxed));
+        merged.cache_depth = lower.cache_depth;
+        let mut reserved = true;
+
+        for (half, old) in [&lower, &upper].into_iter().enumerate() {
+            let base = half * old.num_pages;
+
+            for page in (0..old.num_pages).filter(|p| !old.tree.is_clean(*p, 1)) {
+                merged.tree.mark_dirty(base + page, 1);
+            }
+
+            for page in 0..old.num_pages {
+                if let Some(order) = old.tree.order_at(page) {
+                    reserved &= merged.reserve(old.start + page * PAGE_SIZE, 1 << order);
+
+                    if reserved && old.tree.reserved_at(page).is_some() {
+                        merged.tree.mark_reserved(base + page);
+                    }
+                }
+            }
+        }
+
+        reserved.then_some(merged)
+    }
+
+    /// Creates an independent allocator over the same arena with the same blocks allocated,
+    /// taking its metadata from `backend`.
+    ///
+    /// Blocks allocated at this point are owned by both allocators afterwards, and freeing
+    /// one in both lets them hand it out twice. Pending lazy frees are flushed first. States
+    /// are copied container by container, so the copy is only consistent if nothing
+    /// allocates or frees meanwhile.
+    pub fn duplicate_in(&self, backend: &'a A) -> Option<Self>
+    where
+        H: Clone,
+    {
+        if self.is_poisoned() {
+            return None;
+        }
+
+        self.flush();
+
+        let mut dup = Self::create(
+            self.start,
+            self.num_pages,
+            self.min_order(),
+            backend,
+            self.oom.clone(),
+        )?;
+        dup.tree.copy_from(&self.tree);
+        dup.restart = self.restart;
+        dup.attempt_limit = self.attempt_limit;
+        dup.set_low_watermark(self.low_watermark.load(Ordering::Relaxed));
+        dup.cache_depth = self.cache_depth;
+        dup.free_shards[0]
+            .0
+            .store(self.free_pages(), Ordering::Relaxed);
+
+        Some(dup)
+    }
+
+    /// Creates an allocator that asks `oom` what to do when an allocation doesn't fit.
+    ///
+    /// Fails if the arena doesn't fit into the address space, so that no address computed
+    /// later wraps around. It may end at the very top.
+    pub fn with_oom_handler(start: usize, pages: usize, backend: &'a A, oom: H) -> Option<Self> {
+        Self::create(start, pages, 0, backend, oom)
+    }
+
+    fn create(
+        start: usize,
+        pages: usize,
+        min_order: usize,
+        backend: &'a A,
+        oom: H,
+    ) -> Option<Self> {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::PAGE_SIZE_OK;
+        let num_pages = pages.checked_next_power_of_two()?;
+        let size = num_pages.checked_mul(PAGE_SIZE)?;
+
+        start.checked_add(size - 1)?;
+
+        if min_order > num_pages.ilog2() as usize {
+            return None;
+        }
+
+        Some(Self {
+            tree: Tree::<PAGE_SIZE, A>::new(num_pages, min_order, backend)?,
+            num_pages,
+            start,
+            size,
+            lazy: Slots::new(),
+            cache: Slots::new(),
+            cache_depth: 0,
+            free_shards: core::array::from_fn(|i| {
+                FreeShard(AtomicUsize::new(if i == 0 { num_pages } else { 0 }))
+            }),
+            restart: RestartPolicy::Once,
+            attempt_limit: None,
+            low_watermark: AtomicUsize::new(0),
+            poisoned: AtomicBool::new(false),
+            oom,
+            #[cfg(feature = "stats")]
+            stats: Stats::new(),
+            #[cfg(feature = "oplog")]
+            oplog: OpLog::new(),
+            #[cfg(test)]
+            panic_in_commit: AtomicBool::new(false),
+            #[cfg(test)]
+            no_fast_path: AtomicBool::new(false),
+            #[cfg(test)]
+            failing_updates: AtomicUsize::new(0),
+            _d: PhantomData,
+        })
+    }
+
+    /// Returns the alignment of blocks served by `alloc(pages)`.
+    ///
+    /// Blocks are aligned to their own size relative to the start of the arena, so the
+    /// returned address is aligned this way as long as `start` is aligned to the arena size.
+    pub const fn align_of_alloc(pages: usize) -> usize {
+        pages.next_power_of_two() * PAGE_SIZE
+    }
+
+    /// Returns the order of the smallest block that satisfies both size and alignment
+    /// of `layout`.
+    ///
+    /// Alignment is only guaranteed relative to the arena start, see [`Self::align_of_alloc`].
+    pub fn order_for_layout(&self, layout: Layout) -> Result<usize, AllocError> {
+        let order = order_for_bytes(layout.size().max(layout.align()), PAGE_SIZE);
+
+        if order > self.num_pages.ilog2() as usize {
+            return Err(AllocError::OrderTooLarge);
+        }
+
+        Ok(order)
+    }
+
+    /// Returns a snapshot of the operation counters.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> BuddyStats {
+        self.stats.snapshot()
+    }
+
+    /// Zeroes the operation counters.
+    ///
+    /// Counters are cleared one by one, so operations running meanwhile may be partially
+    /// accounted.
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
+    /// Returns how many times a scan reached the end of the arena and continued from its
+    /// start, see [`RestartPolicy`].
+    ///
+    /// Scans start at the block of their CPU's stripe, so restarts grow when the arena is
+    /// nearly full or the CPUs' stripes are taken by others.
+    #[cfg(feature = "stats")]
+    pub fn scan_restarts(&self) -> usize {
+        self.stats.scan_restarts.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many times a free joined two free buddies into a free parent.
+    ///
+    /// A free that climbs several levels counts once per level.
+    #[cfg(feature = "stats")]
+    pub fn coalesce_count(&self) -> usize {
+        self.stats.coalesces.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many allocations took 1, 2-3, 4-7, ... container updates, the last
+    /// bucket collects everything from 128 on.
+    ///
+    /// Updates failing under contention are retried, so the upper buckets show allocations
+    /// that lost races.
+    #[cfg(feature = "stats")]
+    pub fn retry_histogram(&self) -> [usize; 8] {
+        self.stats.histogram()
+    }
+
+    /// Returns the counts accumulated since `prev` was taken with [`Self::stats`].
+    #[cfg(feature = "stats")]
+    pub fn stats_delta(&self, prev: &BuddyStats) -> BuddyStats {
+        self.stats().since(prev)
+    }
+
+    /// Returns the last [`crate::oplog::OPLOG_LEN`] successful container updates, oldest
+    /// first.
+    ///
+    /// Entries written concurrently with the call may be mixed from two updates.
+    #[cfg(feature = "oplog")]
+    pub fn recent_ops(&self) -> impl Iterator<Item = LoggedOp> + '_ {
+        self.oplog.recent()
+    }
+
+    /// Returns true if a panic interrupted an allocation after it started to commit.
+    ///
+    /// The tree state is inconsistent then, so `alloc` and every other way of allocating
+    /// refuse to serve anything. Frees are still accepted.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of pages managed, after rounding up to a power of two.
+    pub fn pages(&self) -> usize {
+        self.num_pages
+    }
+
+    /// Same as [`Self::pages`], the requested page count rounded up to a power of two.
+    pub fn managed_pages(&self) -> usize {
+        self.num_pages
+    }
+
+    /// Returns the addresses `alloc` can hand out, including pages added by the rounding.
+    pub fn managed_range(&self) -> core::ops::Range<usize> {
+        self.start..self.start + self.size
+    }
+
+    /// Returns the number of pages not handed out, blocks held by the order cache count as
+    /// free.
+    ///
+    /// The counter is sharded by CPU and the shards are summed one by one, so the result
+    /// is only an approximate snapshot while other threads allocate and free.
+    pub fn free_pages(&self) -> usize {
+        self.free_shards
+            .iter()
+            .fold(0, |sum, s| sum.wrapping_add(s.0.load(Ordering::Relaxed)))
+    }
+
+    /// Returns whether not even a single page can be allocated, e.g. to skip a full arena
+    /// without trying an allocation.
+    ///
+    /// Only the free page counter is read, so it takes constant time and is a snapshot like
+    /// [`Self::free_pages`]. Blocks queued by [`Self::free_lazy`] are not free until flushed.
+    pub fn is_full(&self) -> bool {
+        self.free_pages() == 0
+    }
+
+    #[inline]
+    fn free_shard(&self, cpu: usize) -> &AtomicUsize {
+        &self.free_shards[cpu % FREE_PAGE_SHARDS].0
+    }
+
+    /// Returns the address of the first page.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub(crate) fn backend(&self) -> &'a A {
+        self.tree.backend()
+    }
+
+    /// Returns the number of bytes managed from the start of the arena.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the order of the smallest block, every request is rounded up to it.
+    pub fn min_order(&self) -> usize {
+        self.tree.leaf_order()
+    }
+
+    pub fn metadata_bytes(&self) -> usize {
+        Tree::<PAGE_SIZE, A>::metadata_bytes(self.num_pages, self.min_order())
+    }
+
+    /// Returns the alignments of the node array and of the containers, the strictest ones
+    /// metadata allocations ask the backend for.
+    pub const fn metadata_alignment() -> (usize, usize) {
+        Tree::<PAGE_SIZE, A>::metadata_alignment()
+    }
+
+    /// Returns the raw state word of the container at `index`.
+    ///
+    /// The value is read with a single atomic load, so it can be called while other
+    /// threads allocate and free. It is only a snapshot: it may be stale by the time
+    /// the caller looks at it.
+    pub fn container_state(&self, index: usize) -> Option<usize> {
+        self.tree.containers().get(index).map(|c| c.get_state())
+    }
+
+    /// Returns the raw state word of the container holding node `node_pos`, the same
+    /// snapshot as [`Self::container_state`] of [`Self::container_of`] the node.
+    ///
+    /// For debugging only, the layout of the word may change between versions.
+    pub fn raw_container_value(&self, node_pos: u32) -> usize {
+        self.tree
+            .container_of(&self.tree.node(node_pos as usize))
+            .get_state()
+    }
+
+    /// Returns a view that can only query the allocator state.
+    pub fn view(&self) -> BuddyView<'_, 'a, PAGE_SIZE, A> {
+        BuddyView::new(&self.tree)
+    }
+
+    /// Returns the index of the container holding the state of node `node_pos`.
+    pub fn container_of(&self, node_pos: u32) -> usize {
+        self.tree
+            .container_index(&self.tree.node(node_pos as usize))
+    }
+
+    /// Lists positions of the nodes whose state lives in container `index`.
+    ///
+    /// A container covers up to 4 levels of the tree starting at its root node (3 levels when
+    /// the state has to fit into 32 bits, one more with `no-coalesce-state`), so at most 31
+    /// positions are returned.
+    pub fn nodes_in_container(&self, index: usize) -> impl Iterator<Item = u32> + '_ {
+        let root = self.tree.container_root_at(index);
+        let last = self.tree.node_count() as u32;
+
+        (0..CONTAINER_LEVELS)
+            .flat_map(move |l| (root << l)..(root << l) + (1 << l))
+            .filter(move |pos| *pos <= last)
+    }
+
+    /// Returns the indices of the arena pages covered by node `node_pos`.
+    pub fn leaf_range(&self, node_pos: u32) -> core::ops::Range<usize> {
+        self.pages_of(&self.tree.node(node_pos as usize))
+    }
+
+    /// Checks the whole state against the recorded allocations.
+    ///
+    /// Allocated blocks have to be aligned to their size and disjoint, every container
+    /// state has to be exactly what allocating those blocks in an empty tree gives, which
+    /// leaves no coalescing bits behind, and free and allocated pages have to cover the
+    /// arena. Meant to run between operations in fuzzers and stress tests, a concurrent
+    /// update can make it report an error.
+    pub fn validate(&self) -> Result<(), InvariantError> {
+        let mut used = 0;
+        let mut page = 0;
+
+        while page < self.num_pages {
+            let Some(order) = self.tree.order_at(page) else {
+                page += 1;
+                continue;
+            };
+            let pages = 1 << order;
+
+            if !page.is_multiple_of(pages) || page + pages > self.num_pages {
+                return Err(InvariantError::Misaligned { page });
+            }
+
+            if let Some(inner) = (page + 1..page + pages).find(|p| self.tree.order_at(*p).is_some())
+            {
+                return Err(InvariantError::Overlap { page: inner });
+            }
+
+            used += pages;
+            page += pages;
+        }
+
+        for (index, container) in self.tree.containers().iter().enumerate() {
+            let found = NodeState(container.get_state());
+            let expected = self.expected_state(index, found);
+
+            if expected != found {
+                return Err(InvariantError::ContainerState {
+                    container: index,
+                    expected: expected.0,
+                    found: found.0,
+                });
+            }
+        }
+
+        let free = self.view().free_pages();
+
+        if free + used != self.num_pages {
+            return Err(InvariantError::PageCount { free, used });
+        }
+
+        Ok(())
+    }
+
+    // Builds the state of container `index` from the page orders, the same way allocations
+    // of the recorded blocks would set it. Coalescing marks are taken from `found` where
+    // they are allowed.
+    #[cfg_attr(feature = "no-coalesce-state", allow(unused_variables))]
+    fn expected_state(&self, index: usize, found: NodeState) -> NodeState {
+        let mut val = NodeState(0);
+
+        for pos in self.nodes_in_container(index) {
+            let node = &self.tree.node(pos as usize);
+
+            if self.tree.order_at(node.start / PAGE_SIZE) == Some(self.order_of(node)) {
+                val = self.lock_node(node, val);
+                continue;
+            }
+
+            if !self.tree.is_leaf(node) || pos as usize * 2 > self.tree.node_count() {
+                continue;
+            }
+
+            let (left, right) = (&self.tree.left_of(node), &self.tree.right_of(node));
+            let leaf = node.container_pos;
+
+            if self.holds_allocation(left) {
+                val = val.occupy_left(leaf);
+            }
+
+            if self.holds_allocation(right) {
+                val = val.occupy_rigth(leaf);
+            }
+
+            // A free that didn't empty its container leaves the mark above it in place,
+            // which is harmless as long as the side stays in use
+            #[cfg(not(feature = "no-coalesce-state"))]
+            {
+                if val.is_occupied_left(leaf) && found.is_left_coalescing(leaf) {
+                    val = val.left_coalesce(leaf);
+                }
+
+                if val.is_occupied_rigth(leaf) && found.is_right_coalescing(leaf) {
+                    val = val.rigth_coalesce(leaf);
+                }
+            }
+
+            if val.is_occupied_left(leaf) || val.is_occupied_rigth(leaf) {
+                let mut above = *node;
+
+                while above.pos != self.tree.container_root_pos(node) {
+                    above = self.tree.parent_of(&above);
+                    val = val.lock_not_leaf(above.container_pos);
+                }
+            }
+        }
+
+        val
+    }
+
+    #[inline]
+    fn order_of(&self, node: &Node) -> usize {
+        (node.size / PAGE_SIZE).ilog2() as usize
+    }
+
+    // Whether an allocated block lies within `node`. Blocks are already known to be
+    // aligned, so one starting inside and no larger than `node` ends inside too.
+    fn holds_allocation(&self, node: &Node) -> bool {
+        let order = self.order_of(node);
+
+        self.pages_of(node)
+            .any(|p| self.tree.order_at(p).is_some_and(|o| o <= order))
+    }
+
+    #[inline]
+    fn pages_of(&self, node: &Node) -> core::ops::Range<usize> {
+        let first = node.start / PAGE_SIZE;
+
+        first..first + node.size / PAGE_SIZE
+    }
+
+    #[cfg(test)]
+    pub fn __set_container_state(&self, index: usize, state: usize) {
+        self.tree.containers()[index]
+            .nodes
+            .store(state, Ordering::Relaxed);
+    }
+
+    #[cfg(test)]
+    fn dump(&self) {
+        println!("Overall size {}", self.size);
+        println!("Num_pages {}", self.num_pages);
+        println!("Heigth {}", self.tree.height());
+        println!("Num nodes {}", self.tree.node_count());
+    }
+
+    #[inline]
+    fn node_addr(&self, node: &Node) -> usize {
+        self.start + node.start
+    }
+
+    #[inline]
+    fn node_at(&self, addr: usize, pages: usize) -> Node {
+        let pages = pages.max(1 << self.min_order());
+        let first = self.level_nodes(pages.ilog2() as usize).start as usize;
+
+        self.tree
+            .node(first + (addr - self.start) / (pages * PAGE_SIZE))
+    }
+
+    // Accounts a successful allocation of `node` on `cpu` that took `attempts` container
+    // updates and returns its address
+    #[inline]
+    #[cfg_attr(not(feature = "stats"), allow(unused_variables))]
+    fn allocated(&self, node: &Node, attempts: usize, cpu: usize) -> usize {
+        #[cfg(feature = "stats")]
+        {
+            self.stats.allocs.fetch_add(1, Ordering::Relaxed);
+            self.stats.record_attempts(attempts);
+        }
+
+        self.tree
+            .set_order(node.start / PAGE_SIZE, Some(self.order_of(node)));
+        self.free_shard(cpu)
+            .fetch_sub(node.size / PAGE_SIZE, Ordering::Relaxed);
+
+        self.node_addr(node)
+    }
+
+    // Every allocation that doesn't scan takes a block through here, so this is where
+    // they all refuse to touch a poisoned tree
+    #[inline]
+    fn alloc_node(&self, node: &Node) -> Option<usize> {
+        if self.is_poisoned() {
+            return None;
+        }
+
+        let mut attempts = 0;
+
+        match self.try_alloc_node(node, &mut attempts) {
+            None => Some(self.allocated(node, attempts, C::current_cpu())),
+            Some(_) => None,
         }
     }
 
+    /// Returns the range of tree positions of all blocks of `1 << order` pages.
+    ///
+    /// Blocks of one order are laid out left to right, so the first position covers the
+    /// start of the arena. Orders below [`Self::min_order`] give the leaves.
     #[inline]
-    fn is_occupied(val: usize, pos: u8) -> bool {
-        if pos < 8 {
-            (val & (0x1 << (pos - 1))) != 0
+    pub fn level_nodes(&self, order: usize) -> core::ops::Range<u32> {
+        let first = (self.num_pages >> order.max(self.min_order())) as u32;
+
+        first..first * 2
+    }
+
+    /// Returns the maximum number of blocks `alloc` visits for a block of `1 << order` pages.
+    ///
+    /// Only the scan is bounded: compare-and-swap retries under contention come on top.
+    pub fn max_scan_steps(&self, order: usize) -> usize {
+        self.level_nodes(order).len() * self.restart.max_wraps().max(1)
+    }
+
+    pub fn restart_policy(&self) -> RestartPolicy {
+        self.restart
+    }
+
+    /// Sets how often a scan that reached the end of the arena continues from its start.
+    pub fn set_restart_policy(&mut self, policy: RestartPolicy) {
+        self.restart = policy;
+    }
+
+    /// Makes an allocation give up once it tried `limit` container updates, `None`, the
+    /// default, never gives up.
+    ///
+    /// Updates are only retried after another thread's update went through, so a limit
+    /// only matters if the tree is stuck in a state that never resolves. [`Self::try_alloc`]
+    /// reports such an allocation with [`AllocError::NoProgress`].
+    pub fn set_attempt_limit(&mut self, limit: Option<usize>) {
+        self.attempt_limit = limit;
+    }
+
+    /// Makes allocations fail while fewer than `pages` pages are free, so that reclaim can
+    /// start before the arena runs out. 0, the default, turns the watermark off.
+    ///
+    /// Throttled allocations fail without calling the OOM handler and [`Self::try_alloc`]
+    /// reports them with [`AllocError::BelowWatermark`]. [`Self::try_alloc_forced`] ignores
+    /// the watermark, and so do allocations picking their block themselves, like
+    /// [`Self::alloc_near`].
+    pub fn set_low_watermark(&self, pages: usize) {
+        self.low_watermark.store(pages, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn below_watermark(&self) -> bool {
+        let watermark = self.low_watermark.load(Ordering::Relaxed);
+
+        watermark != 0 && self.free_pages() < watermark
+    }
+
+    #[inline]
+    fn out_of_attempts(&self, attempts: usize) -> bool {
+        self.attempt_limit.is_some_and(|limit| attempts >= limit)
+    }
+
+    /// Keeps up to `depth` freed blocks of each order below 8 out of the tree, so that an
+    /// `alloc` of the same size takes one back without updating any container.
+    ///
+    /// 0, the default, turns the cache off and the depth is capped at 8. Cached blocks
+    /// stay allocated in the tree, so [`Self::view`] doesn't count them as free. They are
+    /// handed back to the tree by [`Self::flush`] and when an allocation would fail
+    /// otherwise.
+    pub fn set_order_cache(&mut self, depth: usize) {
+        self.flush_order_cache();
+        self.cache_depth = depth.min(ORDER_CACHE_DEPTH);
+    }
+
+    #[inline]
+    fn cache_slots(&self, pages: usize) -> Option<core::ops::Range<usize>> {
+        let first = pages.ilog2() as usize * ORDER_CACHE_DEPTH;
+
+        (self.cache_depth != 0 && first < ORDER_CACHE_ORDERS * ORDER_CACHE_DEPTH)
+            .then_some(first..first + self.cache_depth)
+    }
+
+    // Frees every cached block into the tree and returns whether there was any
+    fn flush_order_cache(&self) -> bool {
+        let mut flushed = false;
+
+        for i in 0..ORDER_CACHE_ORDERS * ORDER_CACHE_DEPTH {
+            if let Some(entry) = self.cache.take(i) {
+                let pages = 1 << (i / ORDER_CACHE_DEPTH);
+
+                self.free_to_tree(&self.node_at(self.start + (entry - 1) * PAGE_SIZE, pages));
+                flushed = true;
+            }
+        }
+
+        flushed
+    }
+
+    pub fn oom_handler(&self) -> &H {
+        &self.oom
+    }
+
+    pub fn alloc(&self, pages: usize) -> Option<usize> {
+        self.alloc_on(pages, C::current_cpu())
+    }
+
+    /// Same as [`Self::alloc`], but tells why the allocation failed.
+    pub fn try_alloc(&self, pages: usize) -> Result<usize, AllocError> {
+        self.try_alloc_on(pages, C::current_cpu(), false)
+    }
+
+    /// Same as [`Self::try_alloc`], but ignores the watermark set by
+    /// [`Self::set_low_watermark`], e.g. for the reclaim path itself.
+    pub fn try_alloc_forced(&self, pages: usize) -> Result<usize, AllocError> {
+        self.try_alloc_on(pages, C::current_cpu(), true)
+    }
+
+    /// Same as [`Self::alloc`] with the result typed as an address.
+    pub fn alloc_addr(&self, pages: usize) -> Option<ByteAddr> {
+        self.alloc(pages).map(ByteAddr)
+    }
+
+    /// Same as [`Self::alloc`], but returns the whole block, which is `pages` rounded up to
+    /// a power of two long.
+    ///
+    /// ```
+    /// #![feature(allocator_api)]
+    /// use lock_free_buddy_allocator::{buddy_alloc::BuddyAlloc, cpuid::Cpu};
+    ///
+    /// struct Cpu0;
+    ///
+    /// impl Cpu for Cpu0 {
+    ///     fn current_cpu() -> usize {
+    ///         0
+    ///     }
+    /// }
+    ///
+    /// let buddy = BuddyAlloc::<4096, Cpu0, _>::new(0, 16, &std::alloc::Global).unwrap();
+    /// let block = buddy.alloc_range(3).unwrap();
+    ///
+    /// assert_eq!(block.len(), 4 * 4096);
+    /// buddy.free(block.start, 3);
+    /// ```
+    pub fn alloc_range(&self, pages: usize) -> Option<core::ops::Range<usize>> {
+        let start = self.alloc(pages)?;
+
+        Some(start..start + self.block_pages(pages) * PAGE_SIZE)
+    }
+
+    /// Captures the current CPU id for [`Self::alloc_with_token`].
+    pub fn thread_token(&self) -> BuddyToken {
+        BuddyToken::new(C::current_cpu())
+    }
+
+    /// Same as [`Self::alloc`], but starts the scan from the CPU cached in `token` instead of
+    /// asking `C` again.
+    ///
+    /// The token goes stale if the thread migrates to another CPU.
+    pub fn alloc_with_token(&self, pages: usize, token: &BuddyToken) -> Option<usize> {
+        self.alloc_on(pages, token.cpu)
+    }
+
+    fn alloc_on(&self, pages: usize, cpu: usize) -> Option<usize> {
+        self.try_alloc_on(pages, cpu, false).ok()
+    }
+
+    fn try_alloc_on(&self, pages: usize, cpu: usize, force: bool) -> Result<usize, AllocError> {
+        // The scan has no level to look at for such blocks
+        if pages > self.num_pages {
+            return Err(AllocError::OrderTooLarge);
+        }
+
+        if self.is_poisoned() {
+            return Err(AllocError::Poisoned);
+        }
+
+        if !force && self.below_watermark() {
+            return Err(AllocError::BelowWatermark);
+        }
+
+        let pages = self.block_pages(pages);
+
+        // Cache entries are page indices plus one, so that none of them is 0
+        if let Some(entry) = self.cache_slots(pages).and_then(|s| self.cache.pop(s)) {
+            let node = &self.node_at(self.start + (entry - 1) * PAGE_SIZE, pages);
+
+            return Ok(self.allocated(node, 0, cpu));
+        }
+
+        loop {
+            match self.scan(pages, cpu) {
+                Err(AllocError::OutOfMemory) => {}
+                res => return res,
+            }
+
+            if self.flush_order_cache() {
+                continue;
+            }
+
+            if self.oom.on_oom(pages.ilog2() as usize) == OomAction::Fail {
+                return Err(AllocError::OutOfMemory);
+            }
+        }
+    }
+
+    /// Returns the stripe of blocks of `pages` pages that `addr` falls into.
+    ///
+    /// A scan for such blocks starts at the stripe equal to the CPU id modulo the number
+    /// of stripes, so on a mostly free arena the allocations of a CPU match its stripe.
+    /// Every block is a stripe of its own, except that the last one shares stripe 0.
+    ///
+    /// Returns `None` for an address outside the arena or blocks larger than it.
+    pub fn partition_of(&self, addr: usize, pages: usize) -> Option<usize> {
+        let pages = self.block_pages(pages);
+
+        if pages > self.num_pages {
+            return None;
+        }
+
+        let blocks = self.num_pages / pages;
+        let offset = addr.checked_sub(self.start).filter(|o| *o < self.size)?;
+
+        Some(offset / (pages * PAGE_SIZE) % (blocks - 1).max(1))
+    }
+
+    /// Returns the address and size in pages of every allocated block in the stripe where
+    /// scans of `cpu` for blocks of its size start, see [`Self::partition_of`].
+    ///
+    /// Blocks are reported from the start of the arena, those held by the order cache count
+    /// as allocated. A block allocated or freed concurrently may or may not be seen.
+    pub fn blocks_in_partition(&self, cpu: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let mut page = 0;
+
+        core::iter::from_fn(move || {
+            while page < self.num_pages {
+                let Some(order) = self.tree.order_at(page) else {
+                    page += 1;
+                    continue;
+                };
+                let (addr, pages) = (self.start + page * PAGE_SIZE, 1 << order);
+
+                page += pages;
+
+                if self.partition_of(addr, pages) == Some(cpu % (self.num_pages / pages - 1).max(1))
+                {
+                    return Some((addr, pages));
+                }
+            }
+
+            None
+        })
+    }
+
+    // Visits every block of `pages` pages once, starting from the one picked by the CPU
+    fn scan(&self, pages: usize, cpu: usize) -> Result<usize, AllocError> {
+        let nodes = self.level_nodes(pages.ilog2() as usize);
+        let start_node = nodes.start as usize;
+        let last_node = nodes.end as usize - 1;
+
+        hot_assert!(self.tree.node(start_node).size == pages * PAGE_SIZE);
+
+        let max_wraps = self.restart.max_wraps();
+        let mut a = cpu;
+        let mut wraps = 0;
+        let mut attempts = 0;
+
+        if last_node - start_node != 0 {
+            a %= last_node - start_node;
         } else {
-            val & ((0x1 << 6) << (5 * (pos - 7))) != 0
+            a = 0;
+        }
+
+        a += start_node;
+
+        let started_at = a;
+
+        while {
+            match self.try_alloc_node(&self.tree.node(a), &mut attempts) {
+                None => {
+                    return Ok(self.allocated(&self.tree.node(a), attempts, cpu));
+                }
+                Some(_) if self.out_of_attempts(attempts) => {
+                    #[cfg(feature = "stats")]
+                    self.stats.no_progress.fetch_add(1, Ordering::Relaxed);
+
+                    return Err(AllocError::NoProgress);
+                }
+                Some(i) => {
+                    if i == 1 {
+                        return Err(AllocError::OutOfMemory);
+                    }
+
+                    a = (i + 1)
+                        * (1 << (self.level(&self.tree.node(a)) - self.level(&self.tree.node(i))));
+                }
+            }
+
+            if a > last_node {
+                // A busy ancestor may skip past `started_at`, so a wrap beyond the allowed
+                // ones ends the scan
+                if wraps == max_wraps {
+                    return Err(AllocError::OutOfMemory);
+                }
+
+                #[cfg(feature = "stats")]
+                self.stats.scan_restarts.fetch_add(1, Ordering::Relaxed);
+
+                a = start_node;
+                wraps += 1;
+            }
+
+            wraps < max_wraps.max(1) || a < started_at
+        } {}
+
+        Err(AllocError::OutOfMemory)
+    }
+
+    /// Returns the block [`Self::alloc`] would take for `pages` pages on the current CPU and
+    /// the state of its container before and after, without changing anything.
+    ///
+    /// Blocks are visited in scan order and the new state comes from the same locking an
+    /// allocation commits. Leaves of the containers above are marked on top of that. The
+    /// order cache isn't looked at, and concurrent updates may make `alloc` pick another
+    /// block.
+    pub fn simulate_alloc(&self, pages: usize) -> Option<(usize, usize, usize)> {
+        if self.is_poisoned() || pages > self.num_pages {
+            return None;
+        }
+
+        let pages = self.block_pages(pages);
+        let nodes = self.level_nodes(pages.ilog2() as usize);
+        let (first, count) = (nodes.start as usize, nodes.len());
+        let start = C::current_cpu() % (count - 1).max(1);
+        let visits = if self.restart.max_wraps() == 0 {
+            count - start
+        } else {
+            count
+        };
+
+        (0..visits)
+            .map(|i| self.tree.node(first + (start + i) % count))
+            .find(|node| self.tree.is_free(node))
+            .map(|node| {
+                let old = NodeState(self.tree.container_of(&node).get_state());
+
+                (self.node_addr(&node), old.0, self.lock_node(&node, old).0)
+            })
+    }
+
+    /// Allocates the whole arena if nothing in it is in use and returns its start.
+    ///
+    /// Only the root is tried, with no scan and no OOM handling, so it fails while any
+    /// block is allocated. The arena is released with `free(start, pages())`.
+    pub fn try_claim_all(&self) -> Option<usize> {
+        self.alloc_node(&self.tree.root())
+    }
+
+    // Takes `pages` pages from page `first` on as the largest aligned blocks that fit,
+    // rounded out to whole leaves. They are construction time reservations, which
+    // `reset` takes again
+    pub(crate) fn reserve_range(&self, first: usize, pages: usize) -> bool {
+        let leaf = 1 << self.min_order();
+        let (mut page, end) = (first / leaf * leaf, (first + pages).next_multiple_of(leaf));
+
+        while page < end {
+            let align = if page == 0 {
+                self.num_pages
+            } else {
+                1 << page.trailing_zeros()
+            };
+            let block = align.min(1 << (end - page).ilog2());
+
+            if !self.reserve(self.start + page * PAGE_SIZE, block) {
+                return false;
+            }
+
+            self.tree.mark_reserved(page);
+            page += block;
+        }
+
+        true
+    }
+
+    // Takes the block of `pages` pages at `addr`, failing if any part of it is in use
+    pub(crate) fn reserve(&self, addr: usize, pages: usize) -> bool {
+        self.alloc_node(&self.node_at(addr, pages)).is_some()
+    }
+
+    /// Takes the block of `pages` pages at `start` as a reservation, released with
+    /// [`Self::free`].
+    ///
+    /// Returns `None` if `start` is not aligned to the block size, the block is outside of
+    /// the arena or any part of it is in use.
+    pub fn occupy(&self, start: usize, pages: usize) -> Option<()> {
+        let pages = self.block_pages(pages);
+        let offset = start.checked_sub(self.start)?;
+
+        if !offset.is_multiple_of(pages * PAGE_SIZE) || offset + pages * PAGE_SIZE > self.size() {
+            return None;
         }
+
+        self.reserve(start, pages).then_some(())
     }
 
-    #[inline]
-    fn lock_not_leaf(val: usize, pos: u8) -> usize {
-        val | (0x1 << (pos as usize - 1))
+    /// Hands the free block of `pages` pages at `start` over to `other`.
+    ///
+    /// Arenas sharing address space keep the ranges they don't own reserved. The block is
+    /// occupied here and its reservation in `other` is freed, so afterwards only `other`
+    /// can allocate it. Returns `None` without changing anything if `other` doesn't cover
+    /// the block, doesn't hold it reserved as one block or the block isn't free here.
+    pub fn transfer<C2: Cpu, A2: Allocator, H2: OomHandler>(
+        &self,
+        other: &BuddyAlloc<'_, PAGE_SIZE, C2, A2, H2>,
+        start: usize,
+        pages: usize,
+    ) -> Option<()> {
+        let pages = self.block_pages(pages);
+        let offset = start.checked_sub(other.start)?;
+
+        if !offset.is_multiple_of(pages * PAGE_SIZE)
+            || offset + pages * PAGE_SIZE > other.size
+            || other.tree.order_at(offset / PAGE_SIZE) != Some(pages.ilog2() as usize)
+        {
+            return None;
+        }
+
+        self.occupy(start, pages)?;
+        other.free(start, pages)
+    }
+
+    /// Allocates `pages` pages that are freed when the returned guard is dropped.
+    pub fn alloc_owned(&self, pages: usize) -> Option<OwnedPages<'_, 'a, PAGE_SIZE, C, A, H>> {
+        let pages = self.block_pages(pages);
+
+        self.alloc(pages)
+            .map(|addr| OwnedPages::new(self, addr, pages))
+    }
+
+    /// Allocates a block of `pages` pages to be split up with [`RegionHandle::carve`]
+    /// without going through the tree, freed as a whole when the handle is dropped.
+    pub fn alloc_region(&self, pages: usize) -> Option<RegionHandle<'_, 'a, PAGE_SIZE, C, A, H>> {
+        let pages = self.block_pages(pages);
+
+        self.alloc(pages)
+            .map(|addr| RegionHandle::new(self, addr, pages))
+    }
+
+    /// Allocates enough pages for `requested_bytes` bytes.
+    ///
+    /// Returns the address and the number of bytes lost to rounding the request up to a
+    /// power of two pages.
+    pub fn alloc_tracked(&self, requested_bytes: usize) -> Option<(usize, usize)> {
+        let pages = self.block_pages(1 << order_for_bytes(requested_bytes, PAGE_SIZE));
+        let addr = self.alloc(pages)?;
+        let wasted = pages * PAGE_SIZE - requested_bytes;
+
+        #[cfg(feature = "stats")]
+        self.stats
+            .internal_fragmentation_bytes
+            .fetch_add(wasted, Ordering::Relaxed);
+
+        Some((addr, wasted))
+    }
+
+    /// Returns an [`Allocator`] handing out blocks of this arena as memory.
+    ///
+    /// # Safety
+    ///
+    /// The arena must be readable and writable memory that isn't used otherwise while
+    /// blocks are handed out.
+    pub unsafe fn as_allocator(&self) -> ArenaAllocator<'_, 'a, PAGE_SIZE, C, A, H> {
+        ArenaAllocator::new(self)
+    }
+
+    /// Allocates `pages` zeroed pages.
+    ///
+    /// Pages that were never freed since construction are assumed to be zeroed already, so
+    /// the block is only cleared if some of its pages were handed back with `free`.
+    ///
+    /// # Safety
+    ///
+    /// The arena must be writable memory that was zeroed before the allocator was created.
+    pub unsafe fn alloc_zeroed(&self, pages: usize) -> Option<usize> {
+        let pages = self.block_pages(pages);
+        let addr = self.alloc(pages)?;
+
+        if !self.tree.is_clean((addr - self.start) / PAGE_SIZE, pages) {
+            core::ptr::write_bytes(addr as *mut u8, 0, pages * PAGE_SIZE);
+        }
+
+        Some(addr)
+    }
+
+    /// Allocates `pages` pages preferring the block closest to address `hint`.
+    ///
+    /// The scan starts at the block containing `hint` and moves outward, alternating between
+    /// the blocks above and below it.
+    pub fn alloc_near(&self, pages: usize, hint: usize) -> Option<usize> {
+        let pages = self.block_pages(pages);
+
+        if pages > self.num_pages {
+            return None;
+        }
+
+        let first = self.level_nodes(pages.ilog2() as usize).start as usize;
+        let hint = (hint.saturating_sub(self.start) / (pages * PAGE_SIZE)).min(first - 1);
+
+        for d in 0..first {
+            if hint + d < first {
+                if let Some(addr) = self.alloc_node(&self.tree.node(first + hint + d)) {
+                    return Some(addr);
+                }
+            }
+
+            if d != 0 && d <= hint {
+                if let Some(addr) = self.alloc_node(&self.tree.node(first + hint - d)) {
+                    return Some(addr);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Allocates `pages` pages whose first page index `addr / PAGE_SIZE` is `color` modulo
+    /// `num_colors`.
+    ///
+    /// Blocks are visited from the start of the arena, skipping those of other colors.
+    pub fn alloc_colored(&self, pages: usize, color: usize, num_colors: usize) -> Option<usize> {
+        if color >= num_colors {
+            return None;
+        }
+
+        let pages = self.block_pages(pages);
+
+        self.level_nodes(pages.ilog2() as usize)
+            .map(|pos| self.tree.node(pos as usize))
+            .filter(|node| (self.node_addr(node) / PAGE_SIZE) % num_colors == color)
+            .find_map(|node| self.alloc_node(&node))
+    }
+
+    /// Allocates `pages` pages preferring a block whose address is aligned to `align_pages`
+    /// pages, e.g. so that the region can later be backed by a huge page.
+    ///
+    /// Aligned blocks are visited from the start of the arena, and if none of them is free
+    /// any block is taken like [`Self::alloc`] does. Every block is aligned to its own size.
+    pub fn alloc_prefer_aligned(&self, pages: usize, align_pages: usize) -> Option<usize> {
+        let pages = self.block_pages(pages);
+        let align = align_pages.next_power_of_two() * PAGE_SIZE;
+
+        if pages * PAGE_SIZE < align {
+            let aligned = self
+                .level_nodes(pages.ilog2() as usize)
+                .map(|pos| self.tree.node(pos as usize))
+                .filter(|node| self.node_addr(node).is_multiple_of(align))
+                .find_map(|node| self.alloc_node(&node));
+
+            if aligned.is_some() {
+                return aligned;
+            }
+        }
+
+        self.alloc(pages)
     }
 
-    #[inline]
-    fn lock_leaf(val: usize, pos: u8) -> usize {
-        val | (0x13 << (7 + (5 * (pos as usize - 8))))
+    /// Allocates `pages` pages all of which `M` maps to NUMA node `node`.
+    ///
+    /// Blocks are visited from the start of the arena, skipping those that cross into
+    /// another node.
+    pub fn alloc_on_node<M: NumaMap>(&self, pages: usize, node: u32) -> Option<usize> {
+        let pages = self.block_pages(pages);
+
+        self.level_nodes(pages.ilog2() as usize)
+            .map(|pos| self.tree.node(pos as usize))
+            .filter(|n| {
+                let first = self.node_addr(n) / PAGE_SIZE;
+
+                (first..first + pages).all(|page| M::node_of(page) == node)
+            })
+            .find_map(|n| self.alloc_node(&n))
     }
 
-    #[inline]
-    fn unlock_not_leaf(val: usize, pos: u8) -> usize {
-        val & !(0x1 << (pos as usize - 1))
-    }
+    /// Returns whether the block of `pages` pages at `start` spans more than one interleave
+    /// group, with consecutive groups of `stride` pages counted from address 0. `stride`
+    /// must not be 0.
+    pub fn is_interleaved(&self, start: usize, pages: usize, stride: usize) -> bool {
+        let first = start / PAGE_SIZE;
 
-    #[inline]
-    fn unlock_leaf(val: usize, pos: u8) -> usize {
-        val & !(0x13 << (7 + (5 * (pos as usize - 8))))
+        first / stride != (first + self.block_pages(pages) - 1) / stride
     }
 
-    #[inline]
-    fn clean_left_coalesce(val: usize, pos: u8) -> usize {
-        val & !(COALESCE_LEFT << (7 + (5 * (pos as usize - 8))))
+    /// Allocates `pages` pages within a single interleave group of `stride` pages, see
+    /// [`Self::is_interleaved`].
+    ///
+    /// Blocks are visited from the start of the arena, skipping those that span groups.
+    pub fn alloc_single_group(&self, pages: usize, stride: usize) -> Option<usize> {
+        let pages = self.block_pages(pages);
+
+        self.level_nodes(pages.ilog2() as usize)
+            .map(|pos| self.tree.node(pos as usize))
+            .filter(|n| !self.is_interleaved(self.node_addr(n), pages, stride))
+            .find_map(|n| self.alloc_node(&n))
     }
 
-    #[inline]
-    fn clean_rigth_coalesce(val: usize, pos: u8) -> usize {
-        val & !(COALESCE_RIGHT << (7 + (5 * (pos as usize - 8))))
+    /// Allocates `pages` pages ending at or below `max_addr`, e.g. for devices that can only
+    /// address low memory.
+    ///
+    /// Only the blocks below the bound are visited, from the start of the arena.
+    pub fn alloc_below(&self, pages: usize, max_addr: usize) -> Option<usize> {
+        let pages = self.block_pages(pages);
+        let blocks = max_addr.checked_sub(self.start)? / (pages * PAGE_SIZE);
+
+        self.level_nodes(pages.ilog2() as usize)
+            .take(blocks)
+            .find_map(|pos| self.alloc_node(&self.tree.node(pos as usize)))
     }
 
-    #[inline]
-    fn left_coalesce(val: usize, pos: u8) -> usize {
-        val | (COALESCE_LEFT << (7 + (5 * (pos as usize - 8))))
-    }
+    /// Reserves the smallest block that can hold `pages` pages and serves the request from
+    /// its start.
+    ///
+    /// Sizes are tried from `pages` upward, so a larger block is only taken when no smaller
+    /// one could be allocated. Returns the address and the size in pages of the reserved
+    /// block. The whole block belongs to the caller, who may hand out the remainder on its
+    /// own, and must be released with `free(addr, block_pages)`.
+    ///
+    /// The OOM handler is asked at most once, for `pages`, after every size failed.
+    pub fn alloc_from_larger(&self, pages: usize) -> Option<(usize, usize)> {
+        if pages > self.num_pages || self.is_poisoned() || self.below_watermark() {
+            return None;
+        }
 
-    #[inline]
-    fn rigth_coalesce(val: usize, pos: u8) -> usize {
-        val | (COALESCE_RIGHT << (7 + (5 * (pos as usize - 8))))
-    }
+        let cpu = C::current_cpu();
+        let pages = self.block_pages(pages);
+
+        // No free block of a size means none of the larger sizes is free either, unless a
+        // free raced in or a larger block is cached
+        let probe = || {
+            core::iter::successors(Some(pages), |b| Some(b * 2))
+                .take_while(|b| *b <= self.num_pages)
+                .find_map(|block| {
+                    let addr = match self.cache_slots(block).and_then(|s| self.cache.pop(s)) {
+                        Some(entry) => self.allocated(
+                            &self.node_at(self.start + (entry - 1) * PAGE_SIZE, block),
+                            0,
+                            cpu,
+                        ),
+                        None => self.scan(block, cpu).ok()?,
+                    };
+
+                    Some((addr, block))
+                })
+        };
+
+        if let Some(found) = probe() {
+            return Some(found);
+        }
 
-    #[inline]
-    fn occupy_left(val: usize, pos: u8) -> usize {
-        val | (0x2 << (7 + (5 * (pos as usize - 8))))
-    }
+        if self.flush_order_cache() {
+            if let Some(found) = probe() {
+                return Some(found);
+            }
+        }
 
-    #[inline]
-    fn occupy_rigth(val: usize, pos: u8) -> usize {
-        val | (0x1 << (7 + (5 * (pos as usize - 8))))
-    }
+        if self.oom.on_oom(pages.ilog2() as usize) == OomAction::Fail {
+            return None;
+        }
 
-    #[inline]
-    fn is_left_coalescing(val: usize, pos: u8) -> bool {
-        val == Self::left_coalesce(val, pos)
+        probe()
     }
 
-    #[inline]
-    fn is_right_coalescing(val: usize, pos: u8) -> bool {
-        val == Self::rigth_coalesce(val, pos)
+    /// Allocates `pages` pages followed by a guard of the same size, so an overrun of the
+    /// returned block lands in memory nobody else owns.
+    ///
+    /// The block and its guard are buddies reserved together, which doubles the cost of the
+    /// allocation. Both are released by [`Self::free_guarded`].
+    pub fn alloc_guarded(&self, pages: usize) -> Option<usize> {
+        self.try_alloc(pages.checked_next_power_of_two()?.checked_mul(2)?)
+            .ok()
     }
 
-    #[inline]
-    fn clean_left(val: usize, pos: u8) -> usize {
-        val & !(0x2 << (7 + (5 * (pos - 8))))
+    /// Frees a block allocated by [`Self::alloc_guarded`] together with its guard.
+    pub fn free_guarded(&self, start: usize, pages: usize) -> Option<()> {
+        self.free(start, pages.next_power_of_two() * 2)
     }
 
-    #[inline]
-    fn clean_rigth(val: usize, pos: u8) -> usize {
-        val & !(0x1 << (7 + (5 * (pos - 8))))
+    // True if the sibling of `node` is not allocable in `state`, so a free reaching `node`
+    // can't unlock their parent and the merge stops there. `node` must not be a container
+    // root, its sibling then lives in the same container.
+    fn sibling_blocks_coalesce(&self, node: &Node, state: NodeState) -> bool {
+        let parent = &self.tree.parent_of(node);
+        let sibling = if self.tree.left_of(parent) == *node {
+            &self.tree.right_of(parent)
+        } else {
+            &self.tree.left_of(parent)
+        };
+
+        !state.is_allocable(sibling.container_pos)
     }
 
-    #[inline]
-    fn is_occupied_rigth(val: usize, pos: u8) -> bool {
-        val == Self::occupy_rigth(val, pos)
+    #[cfg(test)]
+    pub fn __sibling_blocks_coalesce(&self, pos: usize, state: NodeState) -> bool {
+        self.sibling_blocks_coalesce(&self.tree.node(pos), state)
     }
 
-    #[inline]
-    fn is_occupied_left(val: usize, pos: u8) -> bool {
-        val == Self::occupy_left(val, pos)
+    fn unlock_descendants(&self, node: &Node, val: NodeState) -> NodeState {
+        self.descendants(node, val, UNLOCK)
     }
 
-    pub fn new(start: usize, pages: usize, backend: &'a A) -> Option<Self> {
-        let num_pages = pages.next_power_of_two();
+    #[inline]
+    fn descendants(&self, node: &Node, val: NodeState, set: SetBits) -> NodeState {
+        #[cfg(feature = "iterative-lock")]
+        return self.descendants_iter(node, val, set);
 
-        Some(Self {
-            tree: Tree::<PAGE_SIZE, A>::new(num_pages, backend)?,
-            num_pages: num_pages,
-            start: start,
-            size: num_pages * PAGE_SIZE,
-            _d: PhantomData,
-        })
+        #[cfg(not(feature = "iterative-lock"))]
+        self.descendants_rec(node, val, set)
     }
 
-    #[cfg(test)]
-    fn dump(&self) {
-        println!("Overall size {}", self.size);
-        println!("Num_pages {}", self.num_pages);
-        println!("Heigth {}", self.tree.height());
-        println!("Num nodes {}", self.tree.node_count());
-    }
+    // Applies `set` to every descendant of `node` inside its container
+    fn descendants_rec(&self, node: &Node, mut val: NodeState, set: SetBits) -> NodeState {
+        if node.pos as usize * 2 >= self.tree.node_count() {
+            return val;
+        }
 
-    pub fn alloc(&self, pages: usize) -> Option<usize> {
-        let pages = pages.next_power_of_two();
-        let start_node = self.num_pages / pages;
-        let last_node = (self.tree.node(start_node).pos * 2 - 1) as usize;
-        let mut a = C::current_cpu();
-        let mut restared = false;
+        let (left, right) = (&self.tree.left_of(node), &self.tree.right_of(node));
 
-        if last_node - start_node != 0 {
-            a = a % (last_node - start_node);
+        if !self.tree.is_leaf(left) {
+            val = (set.0)(val, left.container_pos);
+            val = (set.0)(val, right.container_pos);
+            val = self.descendants_rec(left, val, set);
+            val = self.descendants_rec(right, val, set);
         } else {
-            a = 0;
+            val = (set.1)(val, left.container_pos);
+            val = (set.1)(val, right.container_pos);
         }
 
-        a += start_node;
+        val
+    }
 
-        let started_at = a;
+    // Same as `descendants_rec`, but with a stack bounded by the number of nodes above
+    // container leaves
+    fn descendants_iter(&self, node: &Node, mut val: NodeState, set: SetBits) -> NodeState {
+        let mut stack = [0u32; FIRST_LEAF as usize];
+        let mut len = 1;
 
-        while {
-            match self.try_alloc_node(self.tree.node(a)) {
-                None => {
-                    return Some(self.start + self.tree.node(a).start);
-                }
-                Some(i) => {
-                    if i == 1 {
-                        return None;
-                    }
+        stack[0] = node.pos;
 
-                    a = (i + 1)
-                        * (1 << (self.level(self.tree.node(a)) - self.level(self.tree.node(i))));
-                }
-            }
+        while len != 0 {
+            len -= 1;
 
-            if a > last_node {
-                a = start_node;
-                restared = true;
-            }
+            let node = &self.tree.node(stack[len] as usize);
 
-            !restared || a < started_at
-        } {}
+            if node.pos as usize * 2 >= self.tree.node_count() {
+                continue;
+            }
 
-        None
-    }
+            let (left, right) = (&self.tree.left_of(node), &self.tree.right_of(node));
 
-    fn check_brother(&self, node: &Node, val: usize) -> bool {
-        let parent = self.tree.parent_of(node);
-        let l_parent = self.tree.left_of(parent);
-        let r_parent = self.tree.right_of(parent);
+            if !self.tree.is_leaf(left) {
+                val = (set.0)(val, left.container_pos);
+                val = (set.0)(val, right.container_pos);
 
-        if l_parent == node && !Self::is_allocable(val, r_parent.container_pos) {
-            true
-        } else if r_parent == node && !Self::is_allocable(val, l_parent.container_pos) {
-            true
-        } else {
-            false
+                stack[len] = right.pos;
+                stack[len + 1] = left.pos;
+                len += 2;
+            } else {
+                val = (set.1)(val, left.container_pos);
+                val = (set.1)(val, right.container_pos);
+            }
         }
+
+        val
     }
 
-    fn unlock_descendants(&self, node: &Node, mut val: usize) -> usize {
-        if node.pos as usize * 2 >= self.tree.node_count() {
-            return val;
+    pub fn unmark(&self, node: &Node, upper_bound: &Node) {
+        #[cfg(feature = "no-coalesce-state")]
+        if !self.take_container(node) {
+            return;
         }
 
-        if !self.tree.is_leaf(self.tree.left_of(node)) {
-            val = Self::lock_not_leaf(val, self.tree.left_of(node).container_pos);
-            val = Self::lock_not_leaf(val, self.tree.right_of(node).container_pos);
+        let next = self.unmark_parent(node);
 
-            val = self.unlock_descendants(self.tree.left_of(node), val);
-            val = self.unlock_descendants(self.tree.right_of(node), val);
-        } else {
-            val = Self::lock_leaf(val, self.tree.left_of(node).container_pos);
-            val = Self::lock_leaf(val, self.tree.right_of(node).container_pos);
+        #[cfg(feature = "no-coalesce-state")]
+        self.release_container(node);
+
+        if let Some(cur) = next {
+            if cur.pos != upper_bound.pos {
+                self.unmark(&cur, upper_bound)
+            }
         }
+    }
 
-        val
+    // Without coalescing marks nothing tells a pending free of a container from a stale
+    // one, so the free locks the emptied container like an allocation of its root would.
+    // Only one free gets it while it stays empty, allocations below fail on the locked
+    // leaves and the parent leaf is updated only as long as it's occupied from below.
+    #[cfg(feature = "no-coalesce-state")]
+    fn take_container(&self, root: &Node) -> bool {
+        while {
+            if self.tree.container_of(root).get_state() != 0 {
+                return false;
+            }
+
+            !self.free_update(root, NodeState(0), self.lock_node(root, NodeState(0)))
+        } {}
+
+        true
     }
 
-    pub fn unmark(&self, node: &Node, upper_bound: &Node) {
+    #[cfg(feature = "no-coalesce-state")]
+    fn release_container(&self, root: &Node) {
+        let locked = self.lock_node(root, NodeState(0));
+
+        while !self.free_update(root, locked, NodeState(0)) {}
+    }
+
+    // When both buddies are freed at once, each free clears only its own side of the
+    // parent leaf. The updates of the leaf's container are serialized, so exactly one of
+    // them sees the other side already clear and carries the merge further up.
+    //
+    // Returns the highest node the update made free if the merge goes on from there.
+    fn unmark_parent(&self, node: &Node) -> Option<Node> {
         let mut exit;
         let mut cur;
 
         'foo: while {
-            let parent = self.tree.parent_of(node);
-            let mut new_val = parent.container.nodes.load(Ordering::Relaxed);
+            let parent = &self.tree.parent_of(node);
+            let mut new_val =
+                NodeState(self.tree.container_of(parent).nodes.load(Ordering::Relaxed));
             let old_val = new_val;
 
-            cur = node;
             exit = false;
 
-            if self.tree.left_of(parent) == node {
-                if !Self::is_left_coalescing(new_val, parent.container_pos) {
-                    return;
+            if self.tree.left_of(parent) == *node {
+                if !new_val.is_left_merging(parent.container_pos) {
+                    return None;
                 }
 
-                new_val = Self::clean_left_coalesce(new_val, parent.container_pos);
-                new_val = Self::clean_left(new_val, parent.container_pos);
-
-                if Self::is_occupied_rigth(new_val, parent.container_pos) {
-                    if parent
-                        .container
-                        .nodes
-                        .compare_exchange(old_val, new_val, Ordering::Relaxed, Ordering::Relaxed)
-                        .is_err()
-                    {
-                        break 'foo;
-                    } else {
-                        continue 'foo;
+                new_val = new_val.merge_left(parent.container_pos);
+
+                // The brother is in use, so the merge ends here. Returning right after the
+                // update keeps this free from acting on a mark a later free of `node` set
+                if new_val.is_occupied_rigth(parent.container_pos) {
+                    if self.free_update(parent, old_val, new_val) {
+                        return None;
                     }
+
+                    continue 'foo;
                 }
             }
 
-            if self.tree.right_of(parent) == node {
-                if !Self::is_right_coalescing(new_val, parent.container_pos) {
-                    return;
+            if self.tree.right_of(parent) == *node {
+                if !new_val.is_right_merging(parent.container_pos) {
+                    return None;
                 }
 
-                new_val = Self::clean_rigth_coalesce(new_val, parent.container_pos);
-                new_val = Self::clean_rigth(new_val, parent.container_pos);
-
-                if Self::is_occupied_left(new_val, parent.container_pos) {
-                    if parent
-                        .container
-                        .nodes
-                        .compare_exchange(old_val, new_val, Ordering::Relaxed, Ordering::Relaxed)
-                        .is_err()
-                    {
-                        continue 'foo;
-                    } else {
-                        break 'foo;
+                new_val = new_val.merge_right(parent.container_pos);
+
+                if new_val.is_occupied_left(parent.container_pos) {
+                    if self.free_update(parent, old_val, new_val) {
+                        return None;
                     }
+
+                    continue 'foo;
                 }
             }
 
             cur = self.tree.parent_of(node);
 
-            'bar: while cur.pos != cur.container.node.pos {
-                exit = self.check_brother(cur, new_val);
+            'bar: while cur.pos != self.tree.container_root_pos(&cur) {
+                exit = self.sibling_blocks_coalesce(&cur, new_val);
                 if exit {
                     break 'bar;
                 }
 
-                new_val = Self::unlock_not_leaf(new_val, self.tree.parent_of(cur).container_pos);
-                cur = self.tree.parent_of(cur);
+                new_val = new_val.unlock_not_leaf(self.tree.parent_of(&cur).container_pos);
+                cur = self.tree.parent_of(&cur);
             }
 
-            parent
-                .container
-                .nodes
-                .compare_exchange(old_val, new_val, Ordering::Relaxed, Ordering::Relaxed)
-                .is_err()
+            !self.free_update(parent, old_val, new_val)
         } {}
 
-        if cur.pos != upper_bound.pos && !exit {
-            self.unmark(cur, upper_bound)
-        }
+        // `cur` is the highest node the committed state made free
+        #[cfg(feature = "stats")]
+        self.stats
+            .coalesces
+            .fetch_add(self.level(node) - self.level(&cur), Ordering::Relaxed);
+
+        (!exit).then_some(cur)
     }
 
+    // Coalescing bits on container leaves make the leaf unallocable, so an allocation never
+    // takes a block a free is still merging into. An allocation below such a leaf doesn't
+    // wait either: `check_parent` clears the bit while occupying the child, and the pending
+    // `unmark` stops at the first leaf that is no longer marked. Every step is a single
+    // update of one container, and a failing one is retried only after another thread's
+    // update succeeded there, so neither side can livelock the other.
+    #[cfg(not(feature = "no-coalesce-state"))]
     pub fn mark(&self, node: &Node, upper_bound: &Node) {
-        let parent = self.tree.parent_of(node);
+        let parent = &self.tree.parent_of(node);
 
         while {
-            let mut new_val = parent.container.nodes.load(Ordering::Relaxed);
+            let mut new_val =
+                NodeState(self.tree.container_of(parent).nodes.load(Ordering::Relaxed));
             let old_val = new_val;
 
-            if self.tree.left_of(parent) == node {
-                new_val = Self::left_coalesce(new_val, parent.container_pos);
+            if self.tree.left_of(parent) == *node {
+                new_val = new_val.left_coalesce(parent.container_pos);
             } else {
-                new_val = Self::rigth_coalesce(new_val, parent.container_pos);
+                new_val = new_val.rigth_coalesce(parent.container_pos);
             }
 
-            parent
-                .container
-                .nodes
-                .compare_exchange(old_val, new_val, Ordering::Relaxed, Ordering::Relaxed)
-                .is_err()
+            !self.free_update(parent, old_val, new_val)
         } {}
 
-        if parent.container.node.pos != upper_bound.pos {
-            self.mark(parent.container.node, upper_bound);
+        if self.tree.container_root_pos(parent) != upper_bound.pos {
+            self.mark(&self.tree.container_root(parent), upper_bound);
         }
     }
 
     pub fn free_node(&self, node: &Node, upper_bound: &Node) {
         let mut exit;
+        let mut cur;
 
-        if node.container.node.pos != upper_bound.pos {
-            self.mark(node.container.node, upper_bound);
+        #[cfg(not(feature = "no-coalesce-state"))]
+        if self.tree.container_root_pos(node) != upper_bound.pos {
+            self.mark(&self.tree.container_root(node), upper_bound);
         }
 
         while {
-            let mut new_val = node.container.nodes.load(Ordering::Relaxed);
+            let mut new_val = NodeState(self.tree.container_of(node).nodes.load(Ordering::Relaxed));
             let old_val = new_val;
-            let mut cur = node;
 
+            cur = *node;
             exit = false;
 
-            'inner: while cur.pos != node.container.node.pos {
-                exit = self.check_brother(cur, new_val);
+            'inner: while cur.pos != self.tree.container_root_pos(node) {
+                exit = self.sibling_blocks_coalesce(&cur, new_val);
                 if exit {
                     break 'inner;
                 }
 
-                new_val = Self::unlock_not_leaf(new_val, self.tree.parent_of(cur).container_pos);
-                cur = self.tree.parent_of(cur);
+                new_val = new_val.unlock_not_leaf(self.tree.parent_of(&cur).container_pos);
+                cur = self.tree.parent_of(&cur);
             }
 
             if !self.tree.is_leaf(node) && node.pos as usize * 2 <= self.tree.node_count() {
@@ -350,156 +1832,585 @@ impl<'a, const PAGE_SIZE: usize, C: Cpu, A: Allocator + 'a> BuddyAlloc<'a, PAGE_
             }
 
             if self.tree.is_leaf(node) {
-                new_val = Self::unlock_leaf(new_val, node.container_pos);
+                new_val = new_val.unlock_leaf(node.container_pos);
             } else {
-                new_val = Self::unlock_not_leaf(new_val, node.container_pos);
+                new_val = new_val.unlock_not_leaf(node.container_pos);
             }
 
-            node.container
-                .nodes
-                .compare_exchange(old_val, new_val, Ordering::Relaxed, Ordering::Relaxed)
-                .is_err()
+            !self.free_update(node, old_val, new_val)
         } {}
 
-        if node.container.node.pos != upper_bound.pos && !exit {
-            self.unmark(node.container.node, upper_bound);
+        #[cfg(feature = "stats")]
+        self.stats
+            .coalesces
+            .fetch_add(self.level(node) - self.level(&cur), Ordering::Relaxed);
+
+        if self.tree.container_root_pos(node) != upper_bound.pos && !exit {
+            self.unmark(&self.tree.container_root(node), upper_bound);
         }
     }
 
-    pub fn free(&self, start: usize, pages: usize) {
-        let level = self.tree.height() - pages.ilog2() as usize;
-        let level_offset = (self.num_pages / (1 << (level - 1))) * PAGE_SIZE;
+    /// Same as [`Self::free`] with the block typed as an address.
+    pub fn free_addr(&self, start: ByteAddr, pages: usize) -> Option<()> {
+        self.free(start.0, pages)
+    }
 
-        self.free_node(
-            self.tree
-                .node((1 << (level - 1)) + (start - self.start) / level_offset),
-            self.tree.root(),
-        );
+    /// Frees `pages` pages at `start`.
+    ///
+    /// In debug builds the block is checked to be allocated with exactly this size first,
+    /// and `None` is returned without freeing anything otherwise. A free with too large a
+    /// size would release the neighbouring buddy as well. Small blocks may go to the cache
+    /// set up by [`Self::set_order_cache`] instead of the tree.
+    pub fn free(&self, start: usize, pages: usize) -> Option<()> {
+        self.release(start, pages, true).map(|_| ())
     }
 
-    fn lock_descendants(&self, node: &Node, mut val: usize) -> usize {
-        if node.pos as usize * 2 >= self.tree.node_count() {
-            return val;
+    /// Same as [`Self::free`], but always hands the block back to the tree and returns the
+    /// order of the largest free block it ended up in.
+    ///
+    /// Frees merge free buddies up to the root anyway, across containers too. Skipping the
+    /// order cache lets that happen right away, so afterwards the returned order is
+    /// available unless another thread allocated in the meantime.
+    pub fn free_coalesce_full(&self, start: usize, pages: usize) -> Option<usize> {
+        let mut top = self.release(start, pages, false)?;
+
+        while top.pos != 1 && self.tree.is_free(&self.tree.parent_of(&top)) {
+            top = self.tree.parent_of(&top);
+        }
+
+        Some(self.order_of(&top))
+    }
+
+    fn release(&self, start: usize, pages: usize, cache: bool) -> Option<Node> {
+        let pages = self.block_pages(pages);
+        let node = &self.node_at(start, pages);
+
+        let page = (start - self.start) / PAGE_SIZE;
+        let slots = self.cache_slots(pages);
+
+        if cfg!(debug_assertions) && !self.is_held(node, pages) {
+            return None;
+        }
+
+        #[cfg(feature = "stats")]
+        self.stats.frees.fetch_add(1, Ordering::Relaxed);
+
+        self.tree.mark_dirty(page, pages);
+        self.free_shard(C::current_cpu())
+            .fetch_add(pages, Ordering::Relaxed);
+
+        if !(cache && slots.is_some_and(|s| self.cache.push(s, page + 1))) {
+            self.free_to_tree(node);
+        }
+
+        Some(*node)
+    }
+
+    /// Frees the block allocated at `start`, whatever its size, and returns its order.
+    ///
+    /// The size comes from the per-page record of allocated blocks, which also tells a
+    /// double free or a stray address from one in the middle of a block. Two frees of the
+    /// same block racing each other aren't caught.
+    pub fn free_checked(&self, start: usize) -> Result<usize, FreeError> {
+        let offset = start
+            .checked_sub(self.start)
+            .filter(|o| *o < self.size)
+            .ok_or(FreeError::NotAllocated)?;
+        let page = offset / PAGE_SIZE;
+
+        let Some(order) = self.tree.order_at(page) else {
+            // Blocks are aligned and disjoint, so only the closest one starting below can
+            // cover the page
+            let covering = (1..=self.num_pages.ilog2() as usize)
+                .find_map(|o| Some((o, self.tree.order_at(page & !((1 << o) - 1))?)));
+
+            return Err(match covering {
+                Some((o, order)) if order >= o => FreeError::NotBlockStart,
+                _ => FreeError::NotAllocated,
+            });
+        };
+
+        if !offset.is_multiple_of(PAGE_SIZE) {
+            return Err(FreeError::NotBlockStart);
+        }
+
+        // Cached and queued blocks are still recorded as allocated
+        if self
+            .cache_slots(1 << order)
+            .is_some_and(|s| self.cache.contains(s, page + 1))
+            || self
+                .lazy
+                .contains(0..LAZY_FREE_SLOTS, Self::lazy_entry(page, 1 << order))
+        {
+            return Err(FreeError::NotAllocated);
+        }
+
+        self.free(start, 1 << order)
+            .ok_or(FreeError::NotAllocated)?;
+        Ok(order)
+    }
+
+    fn free_to_tree(&self, node: &Node) {
+        self.tree.set_order(node.start / PAGE_SIZE, None);
+        self.free_node(node, &self.tree.root());
+    }
+
+    // True if `node` is allocated as a block of `pages` pages that isn't in the order cache
+    // or queued by `free_lazy`. Such blocks are still allocated in the tree, so the cache and
+    // the queues are looked at too.
+    fn is_held(&self, node: &Node, pages: usize) -> bool {
+        let page = node.start / PAGE_SIZE;
+
+        self.is_allocated(node)
+            && !self
+                .cache_slots(pages)
+                .is_some_and(|s| self.cache.contains(s, page + 1))
+            && !self
+                .lazy
+                .contains(0..LAZY_FREE_SLOTS, Self::lazy_entry(page, pages))
+    }
+
+    // An allocated node has every bit an allocation of it would set. A node covering a
+    // smaller allocation has some of its descendants or one of its leaf children free.
+    fn is_allocated(&self, node: &Node) -> bool {
+        let state = NodeState(self.tree.container_of(node).get_state());
+
+        self.lock_node(node, state) == state
+    }
+
+    /// Frees the block of `pages` pages at `start` and allocates a block of the same size.
+    ///
+    /// The block stays locked in the tree and is handed out again, so no state changes.
+    /// A poisoned allocator takes the plain free and alloc path instead.
+    pub fn recycle(&self, start: usize, pages: usize) -> Option<usize> {
+        let pages = self.block_pages(pages);
+        let node = &self.node_at(start, pages);
+
+        // Anything but a block the caller holds goes through `free`, which rejects it in
+        // debug builds
+        if self.is_poisoned() || !self.is_held(node, pages) {
+            self.free(start, pages)?;
+            return self.alloc(pages);
+        }
+
+        #[cfg(feature = "stats")]
+        self.stats.frees.fetch_add(1, Ordering::Relaxed);
+
+        let cpu = C::current_cpu();
+
+        self.free_shard(cpu).fetch_add(pages, Ordering::Relaxed);
+
+        Some(self.allocated(node, 0, cpu))
+    }
+
+    /// Queues a free of `pages` pages at `start` into the current CPU's buffer.
+    ///
+    /// The block stays allocated until [`Self::flush`] hands it back to the tree, so it
+    /// can't be returned by `alloc` in the meantime. When the CPU's buffer is full the block
+    /// is freed right away.
+    ///
+    /// Returns `None` for a block outside the arena or not aligned to its size, which isn't
+    /// queued. Debug builds also reject a block that isn't allocated or is queued already,
+    /// like [`Self::free`] does.
+    pub fn free_lazy(&self, start: usize, pages: usize) -> Option<()> {
+        let pages = self.block_pages(pages);
+        let offset = start.checked_sub(self.start)?;
+
+        if pages > self.num_pages
+            || !offset.is_multiple_of(pages * PAGE_SIZE)
+            || offset + pages * PAGE_SIZE > self.size
+        {
+            return None;
+        }
+
+        if cfg!(debug_assertions) && !self.is_held(&self.node_at(start, pages), pages) {
+            return None;
         }
 
-        if !self.tree.is_leaf(self.tree.left_of(node)) {
-            val = Self::lock_not_leaf(val, self.tree.left_of(node).container_pos);
-            val = Self::lock_not_leaf(val, self.tree.right_of(node).container_pos);
-            val = self.lock_descendants(self.tree.left_of(node), val);
-            val = self.lock_descendants(self.tree.right_of(node), val);
+        let entry = Self::lazy_entry(offset / PAGE_SIZE, pages);
+
+        if self.lazy.push(Self::lazy_slots(C::current_cpu()), entry) {
+            Some(())
         } else {
-            val = Self::lock_leaf(val, self.tree.left_of(node).container_pos);
-            val = Self::lock_leaf(val, self.tree.right_of(node).container_pos);
+            self.free(start, pages)
         }
+    }
 
-        val
+    // Entry of a queued block of `pages` pages starting at `page`
+    #[inline]
+    fn lazy_entry(page: usize, pages: usize) -> usize {
+        (page << 6) | (pages.ilog2() as usize + 1)
+    }
+
+    #[inline]
+    fn lazy_slots(cpu: usize) -> core::ops::Range<usize> {
+        let cpus = LAZY_FREE_SLOTS / LAZY_FREE_PER_CPU;
+        let first = (cpu % cpus) * LAZY_FREE_PER_CPU;
+
+        first..first + LAZY_FREE_PER_CPU
+    }
+
+    /// Frees every block queued by [`Self::free_lazy`] and every cached block.
+    pub fn flush(&self) {
+        self.flush_slots(0..LAZY_FREE_SLOTS);
+        self.flush_order_cache();
+    }
+
+    /// Frees the blocks queued by [`Self::free_lazy`] on `cpu`, e.g. from a thread exit hook
+    /// so that they don't stay allocated until the next [`Self::flush`].
+    ///
+    /// CPUs share buffers when there are more of them than buffers, so blocks queued on
+    /// other CPUs may be freed as well.
+    pub fn flush_cpu_cache(&self, cpu: usize) {
+        self.flush_slots(Self::lazy_slots(cpu));
+    }
+
+    fn flush_slots(&self, slots: core::ops::Range<usize>) {
+        for i in slots {
+            if let Some(entry) = self.lazy.take(i) {
+                #[cfg_attr(feature = "unchecked", allow(unused_variables))]
+                let freed = self.free(
+                    self.start + (entry >> 6) * PAGE_SIZE,
+                    1 << ((entry & 0x3F) - 1),
+                );
+
+                // Queued blocks were checked by `free_lazy`
+                hot_assert!(
+                    freed.is_some(),
+                    "queued block freed behind the queue's back"
+                );
+            }
+        }
+    }
+
+    /// Frees every block at once, returning the allocator to the state its constructor left
+    /// it in.
+    ///
+    /// Blocks queued by [`Self::free_lazy`] or cached are dropped with the rest. Reservations
+    /// taken on construction, such as the tail of [`Self::new_sized`], the used pages of
+    /// [`Self::new_from_bitmap`] and those of [`crate::builder::BuddyAllocBuilder::reserved`],
+    /// are taken again unless they were freed. Settings and counters are kept. A poisoned
+    /// allocator becomes usable again, as nothing of the old tree is left.
+    pub fn reset(&mut self) {
+        // `&mut self` rules out any other operation
+        unsafe { self.bench_reset() };
+    }
+
+    /// Same as [`Self::reset`], but through a shared reference so that a benchmark can
+    /// reuse the allocator it measures.
+    ///
+    /// # Safety
+    ///
+    /// No other operation may run on the allocator meanwhile. Addresses handed out before
+    /// are no longer allocated, freeing them afterwards corrupts the tree.
+    pub unsafe fn bench_reset(&self) {
+        // Queued and cached blocks are freed already, so they are no reservations anymore
+        for i in 0..LAZY_FREE_SLOTS {
+            if let Some(entry) = self.lazy.take(i) {
+                self.tree.unmark_reserved(entry >> 6);
+            }
+        }
+
+        for i in 0..ORDER_CACHE_ORDERS * ORDER_CACHE_DEPTH {
+            if let Some(entry) = self.cache.take(i) {
+                self.tree.unmark_reserved(entry - 1);
+            }
+        }
+
+        self.tree.reset();
+
+        for (i, shard) in self.free_shards.iter().enumerate() {
+            shard
+                .0
+                .store(if i == 0 { self.num_pages } else { 0 }, Ordering::Relaxed);
+        }
+
+        self.poisoned.store(false, Ordering::Relaxed);
+
+        let mut page = 0;
+
+        while page < self.num_pages {
+            let Some(order) = self.tree.reserved_at(page) else {
+                page += 1 << self.min_order();
+                continue;
+            };
+
+            // Nothing else is allocated, so the block is free
+            self.reserve(self.start + page * PAGE_SIZE, 1 << order);
+            self.tree.mark_reserved(page);
+            page += 1 << order;
+        }
+    }
+
+    /// Frees a block that the caller expects to be aligned to `align_pages` pages.
+    ///
+    /// In debug builds a misaligned `start` panics instead of freeing, which catches
+    /// mismatched allocation and free pairs.
+    pub fn free_aligned(&self, start: usize, pages: usize, align_pages: usize) {
+        debug_assert!(
+            start.is_multiple_of(align_pages * PAGE_SIZE),
+            "{start:#x} is not aligned to {align_pages} pages"
+        );
+
+        self.free(start, pages);
+    }
+
+    fn lock_descendants(&self, node: &Node, val: NodeState) -> NodeState {
+        hot_assert!(!self.tree.is_leaf(node));
+
+        self.descendants(node, val, LOCK)
     }
 
-    fn check_parent(&self, node: &Node) -> Option<(usize, usize)> {
-        let mut parent = self.tree.parent_of(node);
-        let root = parent.container.node;
+    fn check_parent(&self, node: &Node, attempts: &mut usize) -> Option<(usize, usize)> {
+        let parent = &self.tree.parent_of(node);
+        let root = &self.tree.container_root(parent);
 
         while {
             let mut new_val;
 
-            new_val = parent.container.nodes.load(Ordering::Relaxed);
+            new_val = NodeState(self.tree.container_of(parent).nodes.load(Ordering::Relaxed));
 
             let old_val = new_val;
 
-            if Self::is_occupied(new_val, parent.container_pos) {
+            // Giving up is rolled back like running into an occupied parent
+            if new_val.is_occupied(parent.container_pos) || self.out_of_attempts(*attempts) {
                 return Some((parent.pos as usize, node.pos as usize));
             }
 
-            if self.tree.left_of(parent) == node {
-                new_val = Self::clean_left_coalesce(new_val, parent.container_pos);
-                new_val = Self::occupy_left(new_val, parent.container_pos);
+            if self.tree.left_of(parent) == *node {
+                #[cfg(not(feature = "no-coalesce-state"))]
+                {
+                    new_val = new_val.clean_left_coalesce(parent.container_pos);
+                }
+                new_val = new_val.occupy_left(parent.container_pos);
             } else {
-                new_val = Self::clean_rigth_coalesce(new_val, parent.container_pos);
-                new_val = Self::occupy_rigth(new_val, parent.container_pos);
+                #[cfg(not(feature = "no-coalesce-state"))]
+                {
+                    new_val = new_val.clean_rigth_coalesce(parent.container_pos);
+                }
+                new_val = new_val.occupy_rigth(parent.container_pos);
             }
 
-            new_val = Self::lock_not_leaf(new_val, self.tree.parent_of(parent).container_pos);
-            parent = self.tree.parent_of(parent);
-            new_val = Self::lock_not_leaf(new_val, self.tree.parent_of(parent).container_pos);
-            new_val = Self::lock_not_leaf(new_val, root.container_pos);
+            // A failed update retries from the same leaf, so climb with a separate binding
+            let mut above = *parent;
+
+            while above != *root {
+                above = self.tree.parent_of(&above);
+                new_val = new_val.lock_not_leaf(above.container_pos);
+            }
+            *attempts += 1;
 
-            self.tree
-                .parent_of(node)
-                .container
-                .nodes
-                .compare_exchange(old_val, new_val, Ordering::Relaxed, Ordering::Relaxed)
-                .is_err()
+            !self.update(parent, old_val, new_val)
         } {}
 
-        if root == self.tree.root() {
+        if *root == self.tree.root() {
             None
         } else {
-            self.check_parent(root)
+            self.check_parent(root, attempts)
         }
     }
 
+    #[cfg(test)]
+    pub fn inject_commit_panic(&self, on: bool) {
+        self.panic_in_commit.store(on, Ordering::Relaxed);
+    }
+
+    #[cfg(test)]
+    pub fn fail_updates(&self, count: usize) {
+        self.failing_updates.store(count, Ordering::Relaxed);
+    }
+
+    #[cfg(test)]
+    pub fn disable_fast_path(&self, on: bool) {
+        self.no_fast_path.store(on, Ordering::Relaxed);
+    }
+
+    // Locks and unlocks the descendants of `pos` starting from `val` with both walks
+    #[cfg(test)]
+    pub fn __descendants(&self, pos: usize, val: usize) -> [(NodeState, NodeState); 2] {
+        let node = &self.tree.node(pos);
+
+        [LOCK, UNLOCK].map(|set| {
+            (
+                self.descendants_rec(node, NodeState(val), set),
+                self.descendants_iter(node, NodeState(val), set),
+            )
+        })
+    }
+
+    // Moves the metadata to another address and frees the old copy
+    #[cfg(test)]
+    pub fn __relocate_metadata(&mut self) {
+        self.tree = self.tree.__relocated().unwrap();
+    }
+
     #[cfg(test)]
     pub fn __try_alloc_node(&self, pos: usize) -> Option<usize> {
-        self.try_alloc_node(self.tree.node(pos))
+        self.try_alloc_node(&self.tree.node(pos), &mut 0)
     }
 
-    fn try_alloc_node(&self, node: &Node) -> Option<usize> {
-        while {
-            let mut new_val;
-            let old_val;
+    // Locks `node` with its ancestors and descendants inside the container
+    fn lock_node(&self, node: &Node, mut val: NodeState) -> NodeState {
+        let root_pos = self.tree.container_root_pos(node);
+        let mut cur = *node;
+
+        while cur.pos != root_pos {
+            val = val.lock_not_leaf(self.tree.parent_of(&cur).container_pos);
 
-            new_val = node.container.nodes.load(Ordering::Relaxed);
+            cur = self.tree.parent_of(&cur);
+        }
+
+        if self.tree.is_leaf(node) {
+            val = val.lock_leaf(node.container_pos);
+        } else {
+            val = val.lock_not_leaf(node.container_pos);
 
-            if !Self::is_allocable(new_val, node.container_pos) {
-                return Some(node.pos as usize);
+            if node.pos as usize * 2 < self.tree.node_count() {
+                val = self.lock_descendants(node, val);
             }
+        }
 
-            old_val = new_val;
+        val
+    }
+
+    // Spins after every failed update and lets the runtime run something else after every
+    // YIELD_AFTER of them
+    #[cfg(feature = "yield")]
+    #[inline]
+    fn backoff(failures: usize) {
+        core::hint::spin_loop();
 
-            let root_pos = node.container.node.pos;
-            let mut cur = node;
+        if failures.is_multiple_of(YIELD_AFTER) {
+            C::yield_now();
+        }
+    }
 
-            while cur.pos != root_pos {
-                new_val = Self::lock_not_leaf(new_val, self.tree.parent_of(cur).container_pos);
+    #[inline]
+    fn fast_path(&self, node: &Node, attempts: &mut usize) -> bool {
+        #[cfg(test)]
+        if self.no_fast_path.load(Ordering::Relaxed) {
+            return false;
+        }
 
-                cur = self.tree.parent_of(cur);
-            }
+        if node.container_pos != 1 {
+            return false;
+        }
 
-            if self.tree.is_leaf(node) {
-                new_val = Self::lock_leaf(new_val, node.container_pos);
-            } else {
-                new_val = Self::lock_not_leaf(new_val, node.container_pos);
+        *attempts += 1;
 
-                if node.pos as usize * 2 < self.tree.node_count() {
-                    new_val = self.lock_descendants(node, new_val);
+        // A container root is allocable only if the whole container is free, so
+        // try to lock it without loading the state first
+        self.update(node, NodeState(0), self.lock_node(node, NodeState(0)))
+    }
+
+    // Counts the container updates it tries in `attempts`
+    fn try_alloc_node(&self, node: &Node, attempts: &mut usize) -> Option<usize> {
+        hot_assert!(node.container_pos != 0);
+
+        if !self.fast_path(node, attempts) {
+            #[cfg(feature = "yield")]
+            let mut failures = 0;
+
+            while {
+                let new_val = NodeState(self.tree.container_of(node).nodes.load(Ordering::Relaxed));
+
+                if !new_val.is_allocable(node.container_pos) || self.out_of_attempts(*attempts) {
+                    return Some(node.pos as usize);
                 }
-            }
 
-            node.container
-                .nodes
-                .compare_exchange(old_val, new_val, Ordering::Relaxed, Ordering::Relaxed)
-                .is_err()
-        } {}
+                let done = self.update(node, new_val, self.lock_node(node, new_val));
+                *attempts += 1;
+
+                #[cfg(feature = "yield")]
+                if !done {
+                    failures += 1;
+                    Self::backoff(failures);
+                }
+
+                !done
+            } {}
+        }
 
-        if node.container.node == self.tree.root() {
+        if self.tree.container_root(node) == self.tree.root() {
             return None;
         }
 
-        match self.check_parent(node.container.node) {
+        let guard = CommitGuard(&self.poisoned);
+
+        #[cfg(test)]
+        if self.panic_in_commit.load(Ordering::Relaxed) {
+            panic!("injected panic in alloc commit");
+        }
+
+        let res = match self.check_parent(&self.tree.container_root(node), attempts) {
             None => None,
             Some((i, n)) => {
-                self.free_node(node, self.tree.node(n));
+                self.free_node(node, &self.tree.node(n));
                 Some(i)
             }
-        }
+        };
+
+        core::mem::forget(guard);
+        res
+    }
+}
+
+struct ContainerStates<'t, 'a, const PAGE_SIZE: usize, A: Allocator>(&'t Tree<'a, PAGE_SIZE, A>);
+
+struct Hex(usize);
+
+impl fmt::Debug for Hex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+impl<const PAGE_SIZE: usize, A: Allocator> fmt::Debug for ContainerStates<'_, '_, PAGE_SIZE, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.containers().iter().map(|c| Hex(c.get_state())))
+            .finish()
+    }
+}
+
+/// Dumps the allocator together with every container state.
+///
+/// Container states are loaded one by one with atomic loads, so the dump is safe to take
+/// while other threads allocate, but it is not a consistent snapshot of the whole tree.
+impl<const PAGE_SIZE: usize, C: Cpu, A: Allocator, H: OomHandler> fmt::Debug
+    for BuddyAlloc<'_, PAGE_SIZE, C, A, H>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BuddyAlloc")
+            .field("start", &Hex(self.start))
+            .field("size", &self.size)
+            .field("num_pages", &self.num_pages)
+            .field("height", &self.tree.height())
+            .field("containers", &ContainerStates(&self.tree))
+            .finish()
+    }
+}
+
+impl<const PAGE_SIZE: usize, C: Cpu, A: Allocator, H: OomHandler> fmt::Display
+    for BuddyAlloc<'_, PAGE_SIZE, C, A, H>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "buddy allocator at {:#x}: {} pages of {} bytes, {} levels",
+            self.start,
+            self.num_pages,
+            PAGE_SIZE,
+            self.tree.height()
+        )
     }
 }
 
-unsafe impl<'a, const PAGE_SIZE: usize, C: Cpu, A: Allocator> Send
-    for BuddyAlloc<'a, PAGE_SIZE, C, A>
+unsafe impl<'a, const PAGE_SIZE: usize, C: Cpu, A: Allocator, H: OomHandler + Send> Send
+    for BuddyAlloc<'a, PAGE_SIZE, C, A, H>
 {
 }
-unsafe impl<'a, const PAGE_SIZE: usize, C: Cpu, A: Allocator> Sync
-    for BuddyAlloc<'a, PAGE_SIZE, C, A>
+unsafe impl<'a, const PAGE_SIZE: usize, C: Cpu, A: Allocator, H: OomHandler + Sync> Sync
+    for BuddyAlloc<'a, PAGE_SIZE, C, A, H>
 {
 }
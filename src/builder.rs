@@ -0,0 +1,123 @@
+use core::alloc::Allocator;
+
+use crate::buddy_alloc::BuddyAlloc;
+use crate::cpuid::Cpu;
+use crate::error::BuddyInitError;
+use crate::tree::Tree;
+
+/// Effective configuration of an arena, see [`BuddyAllocBuilder::describe`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ArenaInfo {
+    /// Pages managed by the allocator
+    pub total_pages: usize,
+    /// Pages taken by reservations
+    pub reserved_pages: usize,
+    /// Bytes of metadata allocated from the backend
+    pub metadata_bytes: usize,
+    /// Order of the largest block
+    pub max_order: usize,
+}
+
+pub struct BuddyAllocBuilder<'a, const PAGE_SIZE: usize, C: Cpu, A: Allocator> {
+    backend: &'a A,
+    start: usize,
+    order: Option<usize>,
+    min_order: usize,
+    reserved: &'a [(usize, usize)],
+    _d: core::marker::PhantomData<C>,
+}
+
+impl<'a, const PAGE_SIZE: usize, C: Cpu, A: Allocator> BuddyAllocBuilder<'a, PAGE_SIZE, C, A> {
+    pub fn new(backend: &'a A) -> Self {
+        Self {
+            backend,
+            start: 0,
+            order: None,
+            min_order: 0,
+            reserved: &[],
+            _d: core::marker::PhantomData,
+        }
+    }
+
+    /// Sets the address of the first page, 0 by default.
+    pub fn start(mut self, start: usize) -> Self {
+        self.start = start;
+        self
+    }
+
+    /// Sets the arena size to `1 << order` pages.
+    pub fn order(mut self, order: usize) -> Self {
+        self.order = Some(order);
+        self
+    }
+
+    /// Sets the order of the smallest block, see [`BuddyAlloc::with_min_order`]. 0 by default.
+    pub fn min_order(mut self, min_order: usize) -> Self {
+        self.min_order = min_order;
+        self
+    }
+
+    /// Marks `(addr, pages)` ranges as allocated once the allocator is built.
+    ///
+    /// Ranges are page granular but don't have to be aligned, they are split into
+    /// blocks the tree can hold. With a minimum order every range grows to whole blocks of
+    /// that order, which two ranges must not share.
+    pub fn reserved(mut self, reserved: &'a [(usize, usize)]) -> Self {
+        self.reserved = reserved;
+        self
+    }
+
+    /// Validates the configuration and reports the arena it would build.
+    pub fn describe(&self) -> Result<ArenaInfo, BuddyInitError> {
+        let order = self
+            .order
+            .filter(|o| *o >= self.min_order)
+            .ok_or(BuddyInitError::InvalidOrder)?;
+        let total_pages = 1usize
+            .checked_shl(order as u32)
+            .filter(|p| p.checked_mul(PAGE_SIZE * 2).is_some())
+            .ok_or(BuddyInitError::InvalidOrder)?;
+        let end = (total_pages * PAGE_SIZE)
+            .checked_add(self.start)
+            .ok_or(BuddyInitError::InvalidOrder)?;
+
+        let mut reserved_pages = 0;
+
+        for &(addr, pages) in self.reserved {
+            let fits = addr >= self.start
+                && (addr - self.start).is_multiple_of(PAGE_SIZE)
+                && pages
+                    .checked_mul(PAGE_SIZE)
+                    .and_then(|size| size.checked_add(addr))
+                    .is_some_and(|last| last <= end);
+
+            if !fits {
+                return Err(BuddyInitError::BadReservation);
+            }
+
+            reserved_pages += pages;
+        }
+
+        Ok(ArenaInfo {
+            total_pages,
+            reserved_pages,
+            metadata_bytes: Tree::<PAGE_SIZE, A>::metadata_bytes(total_pages, self.min_order),
+            max_order: order,
+        })
+    }
+
+    pub fn build(self) -> Result<BuddyAlloc<'a, PAGE_SIZE, C, A>, BuddyInitError> {
+        let info = self.describe()?;
+        let buddy =
+            BuddyAlloc::with_min_order(self.start, info.total_pages, self.min_order, self.backend)
+                .ok_or(BuddyInitError::OutOfMemory)?;
+
+        for &(addr, pages) in self.reserved {
+            if !buddy.reserve_range((addr - self.start) / PAGE_SIZE, pages) {
+                return Err(BuddyInitError::BadReservation);
+            }
+        }
+
+        Ok(buddy)
+    }
+}
@@ -1,3 +1,77 @@
+use core::marker::PhantomData;
+
 pub trait Cpu {
+    /// Returns the id of the executing CPU.
+    ///
+    /// Ids only pick where scans and per-CPU caches start and are always reduced modulo
+    /// their count, so any value is accepted. They don't have to be dense or below a
+    /// limit, and a bogus value like `usize::MAX` only costs locality.
     fn current_cpu() -> usize;
+
+    /// Called when an allocation keeps losing the race for a container, so that a
+    /// cooperative runtime can run other tasks meanwhile.
+    #[cfg(feature = "yield")]
+    #[inline]
+    fn yield_now() {}
+}
+
+/// [`Cpu`] reading the id of the executing CPU from a register, for bare metal targets.
+///
+/// On x86_64 it's the `IA32_TSC_AUX` value `RDTSCP` returns, which the OS has to set up
+/// per CPU. On aarch64 it's the affinity fields of `MPIDR_EL1` packed into the low 32 bits,
+/// which needs EL1.
+#[cfg(all(
+    feature = "hw-cpu",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub struct HwCpu;
+
+#[cfg(all(feature = "hw-cpu", target_arch = "x86_64"))]
+impl Cpu for HwCpu {
+    #[inline]
+    fn current_cpu() -> usize {
+        let mut aux = 0;
+
+        unsafe { core::arch::x86_64::__rdtscp(&mut aux) };
+        aux as usize
+    }
+}
+
+#[cfg(all(feature = "hw-cpu", target_arch = "aarch64"))]
+impl Cpu for HwCpu {
+    #[inline]
+    fn current_cpu() -> usize {
+        let mpidr: u64;
+
+        unsafe {
+            core::arch::asm!("mrs {}, mpidr_el1", out(reg) mpidr, options(nomem, nostack));
+        }
+
+        // Aff0..Aff2 sit in bits 0..24, Aff3 in bits 32..40
+        ((mpidr & 0xff_ffff) | ((mpidr >> 8) & 0xff00_0000)) as usize
+    }
+}
+
+/// CPU id captured once by [`crate::buddy_alloc::BuddyAlloc::thread_token`].
+///
+/// The token only describes the thread that took it while it stays on the same CPU, so it
+/// is neither `Send` nor `Sync`. After a migration it still works but scans from the old
+/// CPU's blocks.
+#[derive(Clone, Debug)]
+pub struct BuddyToken {
+    pub(crate) cpu: usize,
+    _thread: PhantomData<*const ()>,
+}
+
+impl BuddyToken {
+    pub(crate) fn new(cpu: usize) -> Self {
+        Self {
+            cpu,
+            _thread: PhantomData,
+        }
+    }
+
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
 }
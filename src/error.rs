@@ -0,0 +1,109 @@
+use core::fmt;
+
+/// Errors reported by the allocator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocError {
+    /// The request needs a block larger than the whole arena
+    OrderTooLarge,
+    /// No free block of the requested size was found
+    OutOfMemory,
+    /// A panic during an earlier allocation left the tree inconsistent
+    Poisoned,
+    /// The allocation hit the attempt limit before its updates went through
+    NoProgress,
+    /// Fewer pages than the low watermark are free
+    BelowWatermark,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllocError::OrderTooLarge => write!(f, "requested order exceeds the arena size"),
+            AllocError::OutOfMemory => write!(f, "no free block of the requested size"),
+            AllocError::Poisoned => write!(f, "allocator is poisoned"),
+            AllocError::NoProgress => write!(f, "allocation made no progress"),
+            AllocError::BelowWatermark => write!(f, "free pages are below the low watermark"),
+        }
+    }
+}
+
+/// Errors reported while building an allocator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuddyInitError {
+    /// No order was given, or the arena doesn't fit into the address space
+    InvalidOrder,
+    /// A reserved range lies outside of the arena or overlaps another one
+    BadReservation,
+    /// The backend failed to allocate metadata
+    OutOfMemory,
+    /// The metadata would exceed the given budget
+    MetadataTooLarge,
+}
+
+impl fmt::Display for BuddyInitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuddyInitError::InvalidOrder => write!(f, "invalid arena order"),
+            BuddyInitError::BadReservation => write!(f, "invalid reserved range"),
+            BuddyInitError::OutOfMemory => write!(f, "failed to allocate metadata"),
+            BuddyInitError::MetadataTooLarge => write!(f, "metadata exceeds the budget"),
+        }
+    }
+}
+
+/// Misuse caught by [`crate::buddy_alloc::BuddyAlloc::free_checked`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FreeError {
+    /// No allocated block covers the address, e.g. it was freed already
+    NotAllocated,
+    /// The address is inside an allocated block, but not at its start
+    NotBlockStart,
+}
+
+impl fmt::Display for FreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FreeError::NotAllocated => write!(f, "address is not allocated"),
+            FreeError::NotBlockStart => write!(f, "address is not the start of a block"),
+        }
+    }
+}
+
+/// Inconsistencies found by [`crate::buddy_alloc::BuddyAlloc::validate`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvariantError {
+    /// The block allocated at `page` isn't aligned to its size or ends past the arena
+    Misaligned { page: usize },
+    /// Another allocated block starts at `page` inside an allocated block
+    Overlap { page: usize },
+    /// The state of a container doesn't match the allocated blocks
+    ContainerState {
+        container: usize,
+        expected: usize,
+        found: usize,
+    },
+    /// Free and allocated pages don't add up to the arena size
+    PageCount { free: usize, used: usize },
+}
+
+impl fmt::Display for InvariantError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvariantError::Misaligned { page } => {
+                write!(f, "misaligned block at page {page}")
+            }
+            InvariantError::Overlap { page } => write!(f, "overlapping block at page {page}"),
+            InvariantError::ContainerState {
+                container,
+                expected,
+                found,
+            } => write!(
+                f,
+                "container {container} is {found:#x}, expected {expected:#x}"
+            ),
+            InvariantError::PageCount { free, used } => {
+                write!(f, "{free} free and {used} used pages don't cover the arena")
+            }
+        }
+    }
+}
@@ -1,17 +1,43 @@
 #![no_std]
 #![feature(allocator_api)]
-#![feature(slice_ptr_get)]
 #![allow(dead_code)]
 
 #[cfg(test)]
 #[macro_use]
 extern crate std;
 
+// Debug assertions on the allocation hot path, compiled out by the `unchecked` feature
+macro_rules! hot_assert {
+    ($($arg:tt)*) => {
+        #[cfg(not(feature = "unchecked"))]
+        debug_assert!($($arg)*);
+    };
+}
+
+pub mod allocator;
+mod atomic;
 pub mod buddy_alloc;
+pub mod builder;
 pub mod cpuid;
+pub mod error;
+pub mod numa;
+pub mod oom;
+#[cfg(feature = "oplog")]
+pub mod oplog;
+pub mod owned;
+pub mod region;
+pub mod slab;
+mod slots;
+mod state;
+#[cfg(feature = "stats")]
+pub mod stats;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 mod tree;
+pub mod units;
+pub mod view;
 
-#[cfg(test)]
+#[cfg(all(test, not(loom)))]
 mod test {
     use super::*;
     use buddy_alloc::BuddyAlloc;
@@ -22,6 +48,7 @@ mod test {
         thread::{self, ThreadId},
         vec::Vec,
     };
+    use testing::{intersection, MemRegion};
 
     const PAGE_SIZE: usize = 1 << 12;
 
@@ -35,145 +62,2998 @@ mod test {
                     .get() as usize
             }
         }
-    }
+    }
+
+    #[test]
+    fn test_helpers() {
+        {
+            let vec = vec![MemRegion::new(1, 10), MemRegion::new(2, 5)];
+
+            assert!(intersection(&vec));
+        }
+
+        {
+            let vec = vec![
+                MemRegion::new(1, 10),
+                MemRegion::new(11, 10),
+                MemRegion::new(21, 10),
+            ];
+
+            assert!(!intersection(&vec));
+        }
+
+        {
+            let vec = vec![
+                MemRegion::new(1, 10),
+                MemRegion::new(10, 10),
+                MemRegion::new(21, 10),
+            ];
+
+            assert!(intersection(&vec));
+        }
+
+        // Overlap doesn't depend on the order of the regions
+        let (empty, full) = (MemRegion::new(1, 0), MemRegion::new(1, 10));
+
+        assert_eq!(empty == full, full == empty);
+        assert!(!intersection(&[empty, full]));
+        assert!(!intersection(&[full, empty]));
+    }
+
+    #[test]
+    #[cfg(feature = "portable-atomic")]
+    fn portable_atomics() {
+        assert!(core::any::type_name::<atomic::AtomicUsize>().starts_with("portable_atomic"));
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let addrs: Vec<_> = (0..16).map(|_| buddy.alloc(1).unwrap()).collect();
+
+        for addr in addrs {
+            buddy.free(addr, 1).unwrap();
+        }
+
+        assert_eq!(buddy.alloc(16), Some(0));
+        assert_eq!(buddy.validate(), Ok(()));
+    }
+
+    #[test]
+    fn basic_create() {
+        let _buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 10, &Global).unwrap();
+        let _buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(1000, 1000, &Global).unwrap();
+    }
+
+    #[test]
+    fn metadata_size() {
+        use core::mem::size_of;
+        use tree::{Node, NodeContainer};
+
+        // 1024 pages give 11 levels, so containers are rooted at levels 1, 5 and 9
+        // (1, 4, 7 and 10 with the narrow layout, 1, 6 and 11 without coalescing bits)
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1024, &Global).unwrap();
+        let containers = match state::CONTAINER_LEVELS {
+            3 => 1 + 8 + 64 + 512,
+            4 => 1 + 16 + 256,
+            _ => 1 + 32 + 1024,
+        };
+        let old = 2048 * size_of::<Node>() + 2046 * size_of::<NodeContainer>();
+
+        // Nodes aren't stored with `lazy-init`
+        let node_bytes = |pages: usize| {
+            if cfg!(feature = "lazy-init") {
+                0
+            } else {
+                pages * 2 * size_of::<Node>()
+            }
+        };
+
+        // Dirty bitmap and one order byte per page
+        let per_page = 1024 / usize::BITS as usize * size_of::<usize>() + 1024;
+
+        assert_eq!(
+            buddy.metadata_bytes(),
+            node_bytes(1024) + containers * size_of::<NodeContainer>() + per_page
+        );
+        assert!(buddy.metadata_bytes() < old);
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1, &Global).unwrap();
+        assert_eq!(
+            buddy.metadata_bytes(),
+            node_bytes(1) + size_of::<NodeContainer>() + size_of::<usize>() + 1
+        );
+    }
+
+    #[test]
+    fn metadata_alignment() {
+        use core::alloc::{AllocError, Allocator, Layout};
+        use core::mem::{align_of, size_of};
+        use core::ptr::NonNull;
+        use std::sync::atomic::AtomicUsize;
+
+        // Records every block handed out for metadata
+        struct Recording(Mutex<Vec<(usize, Layout)>>);
+
+        unsafe impl Allocator for Recording {
+            fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                let ptr = Global.allocate(layout)?;
+
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((ptr.as_ptr() as *mut u8 as usize, layout));
+                Ok(ptr)
+            }
+
+            unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+                Global.deallocate(ptr, layout)
+            }
+        }
+
+        type Buddy<'a> = BuddyAlloc<'a, PAGE_SIZE, Cpu, Recording>;
+
+        let (node_align, container_align) = Buddy::metadata_alignment();
+        assert!(container_align >= align_of::<AtomicUsize>());
+        assert!(node_align >= align_of::<u32>());
+
+        let backend = Recording(Mutex::new(Vec::new()));
+        let buddy = Buddy::new(0, 1024, &backend).unwrap();
+        let blocks = backend.0.lock().unwrap().clone();
+        let count = (0..)
+            .take_while(|i| buddy.container_state(*i).is_some())
+            .count();
+        let containers = blocks
+            .iter()
+            .find(|(_, layout)| layout.size() == count * size_of::<tree::NodeContainer>())
+            .unwrap();
+
+        assert!(containers.0.is_multiple_of(align_of::<AtomicUsize>()));
+        assert!(blocks
+            .iter()
+            .all(|(addr, layout)| addr.is_multiple_of(layout.align())
+                && layout.align() <= node_align.max(container_align)));
+        drop(buddy);
+    }
+
+    #[test]
+    fn lazy_nodes() {
+        for pages in [1, 2, 64, 1024, 4096] {
+            let tree = tree::Tree::<PAGE_SIZE, Global>::new(pages, 0, &Global).unwrap();
+            let fields = |n: tree::Node| (n.start, n.size, n.pos, n.container_pos, n.container);
+
+            // Without `lazy-init` the nodes come from the array filled in by `Tree::new`
+            for pos in 0..=tree.node_count() {
+                assert_eq!(fields(tree.node(pos)), fields(tree.compute_node(pos)));
+            }
+
+            for pos in 1..=tree.node_count() {
+                let node = tree.node(pos);
+                let root = tree.container_root_pos(&node);
+
+                assert_eq!(tree.container_root_at(tree.container_index(&node)), root);
+                assert_eq!(tree.node(root as usize).container, node.container);
+                assert_eq!(tree.node(root as usize).container_pos, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn leaf_encoding() {
+        use state::{NodeState, FIRST_LEAF};
+
+        let empty = NodeState(0);
+
+        for pos in FIRST_LEAF..FIRST_LEAF * 2 {
+            let locked = empty.lock_leaf(pos);
+            assert!(locked.is_occupied(pos));
+            assert!(!locked.is_allocable(pos));
+            assert_eq!(locked.unlock_leaf(pos), empty);
+
+            // A child being taken doesn't make the leaf itself occupied
+            let right = empty.occupy_rigth(pos);
+            assert!(right.is_occupied_rigth(pos) && !right.is_occupied_left(pos));
+            assert!(!right.is_occupied(pos) && !right.is_allocable(pos));
+            assert_eq!(right.clean_rigth(pos), empty);
+
+            let left = empty.occupy_left(pos);
+            assert!(left.is_occupied_left(pos) && !left.is_occupied_rigth(pos));
+            assert!(!left.is_occupied(pos) && !left.is_allocable(pos));
+            assert_eq!(left.clean_left(pos), empty);
+
+            for other in (1..FIRST_LEAF * 2).filter(|p| *p != pos) {
+                assert!(locked.is_allocable(other));
+                assert!(right.is_allocable(other) && left.is_allocable(other));
+            }
+        }
+
+        for pos in 1..FIRST_LEAF {
+            let locked = empty.lock_not_leaf(pos);
+            assert!(locked.is_occupied(pos) && !locked.is_allocable(pos));
+            assert_eq!(locked.unlock_not_leaf(pos), empty);
+        }
+    }
+
+    #[test]
+    fn sibling_blocks_coalesce() {
+        use state::{NodeState, FIRST_LEAF};
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1024, &Global).unwrap();
+        let lock = |state: NodeState, pos: u8| {
+            if pos < FIRST_LEAF {
+                state.lock_not_leaf(pos)
+            } else {
+                state.lock_leaf(pos)
+            }
+        };
+
+        // Positions in the first container match the tree positions
+        for pos in 2..FIRST_LEAF * 2 {
+            let sibling = pos ^ 1;
+            let free = NodeState(0);
+
+            assert!(!buddy.__sibling_blocks_coalesce(pos as usize, free));
+            assert!(!buddy.__sibling_blocks_coalesce(pos as usize, lock(free, pos)));
+            assert!(buddy.__sibling_blocks_coalesce(pos as usize, lock(free, sibling)));
+
+            if sibling < FIRST_LEAF {
+                continue;
+            }
+
+            // A sibling taken only from below or merging still blocks
+            for used in [free.occupy_left(sibling), free.occupy_rigth(sibling)] {
+                assert!(buddy.__sibling_blocks_coalesce(pos as usize, used));
+            }
+
+            #[cfg(not(feature = "no-coalesce-state"))]
+            for merging in [free.left_coalesce(sibling), free.rigth_coalesce(sibling)] {
+                assert!(buddy.__sibling_blocks_coalesce(pos as usize, merging));
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "no-coalesce-state"))]
+    fn coalescing_state() {
+        use state::{NodeState, FIRST_LEAF};
+
+        let free = NodeState(0);
+
+        for pos in 1..FIRST_LEAF * 2 {
+            let occupied = if pos < FIRST_LEAF {
+                free.lock_not_leaf(pos)
+            } else {
+                free.lock_leaf(pos)
+            };
+
+            assert!(free.is_allocable(pos) && !free.is_occupied(pos) && !free.is_coalescing(pos));
+            assert!(!occupied.is_allocable(pos) && occupied.is_occupied(pos));
+            assert!(!occupied.is_coalescing(pos));
+
+            if pos < FIRST_LEAF {
+                continue;
+            }
+
+            // Merging in either side keeps the leaf away from `alloc` without occupying it
+            for coalescing in [free.left_coalesce(pos), free.rigth_coalesce(pos)] {
+                assert!(coalescing.is_coalescing(pos));
+                assert!(!coalescing.is_allocable(pos) && !coalescing.is_occupied(pos));
+
+                for other in (1..FIRST_LEAF * 2).filter(|p| *p != pos) {
+                    assert!(!coalescing.is_coalescing(other) && coalescing.is_allocable(other));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn descendant_walks() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1024, &Global).unwrap();
+
+        // Nodes above container leaves come first in every container
+        let inner = (0..)
+            .take_while(|i| buddy.container_state(*i).is_some())
+            .flat_map(|i| {
+                buddy
+                    .nodes_in_container(i)
+                    .take(state::FIRST_LEAF as usize - 1)
+            });
+
+        for pos in inner {
+            let [lock, unlock] = buddy.__descendants(pos as usize, 0);
+            assert_eq!(lock.0, lock.1);
+            assert_eq!(unlock.0, unlock.1);
+
+            // Unlocking undoes locking
+            let [_, unlock] = buddy.__descendants(pos as usize, lock.0 .0);
+            assert_eq!(unlock.0 .0, 0);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn free_too_large() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+
+        // 4 pages within a container, 16 pages across a container leaf
+        for pages in [4, 16] {
+            let addr = buddy.alloc(pages).unwrap();
+
+            assert_eq!(buddy.free(addr, pages * 2), None);
+            assert_eq!(buddy.free(addr, pages), Some(()));
+        }
+
+        assert_eq!(buddy.alloc(64), Some(0));
+    }
+
+    #[test]
+    fn realloc_after_free() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+
+        for pages in [16, 4, 1] {
+            let addr = buddy.alloc(pages).unwrap();
+            buddy.free(addr, pages);
+        }
+
+        assert_eq!((0..16).filter_map(|_| buddy.alloc(1)).count(), 16);
+    }
+
+    #[test]
+    fn short_backend() {
+        use core::{alloc::Layout, ptr::NonNull};
+        use std::alloc::{AllocError, Allocator};
+
+        // Hands out one byte less than asked for after the given number of allocations
+        struct Short(std::sync::atomic::AtomicUsize);
+
+        unsafe impl Allocator for Short {
+            fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                let ptr = Global.allocate(layout)?;
+
+                if self.0.fetch_sub(1, std::sync::atomic::Ordering::Relaxed) != 0 {
+                    return Ok(ptr);
+                }
+
+                Ok(NonNull::slice_from_raw_parts(ptr.cast(), layout.size() - 1))
+            }
+
+            unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+                Global.deallocate(ptr, layout)
+            }
+        }
+
+        // Every metadata region is allocated separately, there is no node array with
+        // `lazy-init`
+        let regions = if cfg!(feature = "lazy-init") { 3 } else { 4 };
+
+        for ok in 0..regions {
+            let backend = Short(std::sync::atomic::AtomicUsize::new(ok));
+            assert!(BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &backend).is_none());
+        }
+    }
+
+    #[test]
+    fn new_within_budget() {
+        use error::BuddyInitError;
+
+        type Buddy<'a> = BuddyAlloc<'a, PAGE_SIZE, Cpu, testing::StaticBackend<{ 1 << 16 }>>;
+
+        let backend = testing::StaticBackend::<{ 1 << 16 }>::new();
+
+        // Rejected up front, nothing is taken from the backend
+        assert_eq!(
+            Buddy::new_within_budget(0, 1 << 30, 1 << 16, &backend).err(),
+            Some(BuddyInitError::MetadataTooLarge)
+        );
+        assert_eq!(
+            Buddy::new_within_budget(0, usize::MAX, 1 << 16, &backend).err(),
+            Some(BuddyInitError::InvalidOrder)
+        );
+        assert_eq!(backend.used(), 0);
+
+        let bytes = Buddy::new(0, 256, &backend).unwrap().metadata_bytes();
+
+        unsafe { backend.reset() };
+        assert_eq!(
+            Buddy::new_within_budget(0, 256, bytes - 1, &backend).err(),
+            Some(BuddyInitError::MetadataTooLarge)
+        );
+        assert!(Buddy::new_within_budget(0, 256, bytes, &backend).is_ok());
+
+        // The backend also counts padding between the regions
+        assert!(backend.used() <= bytes + 64 * 4);
+    }
+
+    #[test]
+    fn container_mapping() {
+        use state::CONTAINER_LEVELS;
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1024, &Global).unwrap();
+        let mut owners = vec![None; 2048];
+        let mut i = 0;
+
+        while buddy.container_state(i).is_some() {
+            let nodes: Vec<_> = buddy.nodes_in_container(i).collect();
+            let root = nodes[0];
+            let levels = CONTAINER_LEVELS.min(11 - root.ilog2() as usize);
+
+            assert_eq!(root.ilog2() as usize % CONTAINER_LEVELS, 0);
+            assert_eq!(nodes.len(), (1 << levels) - 1);
+
+            for pos in nodes {
+                assert_eq!(buddy.container_of(pos), i);
+                assert!(owners[pos as usize].replace(i).is_none());
+
+                if pos != root {
+                    assert_eq!(buddy.container_of(pos / 2), i);
+                }
+            }
+
+            i += 1;
+        }
+
+        assert!(owners[1..].iter().all(|o| o.is_some()));
+    }
+
+    #[test]
+    fn raw_container_value() {
+        use state::{NodeState, FIRST_LEAF};
+
+        // The tree is a single container, container positions are node positions
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, FIRST_LEAF as usize, &Global).unwrap();
+
+        assert_eq!(buddy.raw_container_value(1), 0);
+
+        let pos = FIRST_LEAF + (buddy.alloc(1).unwrap() / PAGE_SIZE) as u8;
+        let mut expected = NodeState(0).lock_leaf(pos);
+
+        for above in (1..FIRST_LEAF.ilog2() + 1).map(|shift| pos >> shift) {
+            expected = expected.lock_not_leaf(above);
+        }
+
+        for node in 1..FIRST_LEAF as u32 * 2 {
+            assert_eq!(buddy.raw_container_value(node), expected.0);
+        }
+
+        assert_eq!(
+            expected.0.count_ones(),
+            NodeState(0).lock_leaf(pos).0.count_ones() + FIRST_LEAF.ilog2()
+        );
+        assert_eq!(Some(expected.0), buddy.container_state(0));
+    }
+
+    #[test]
+    fn leaf_range() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1024, &Global).unwrap();
+
+        assert_eq!(buddy.leaf_range(1), 0..1024);
+        assert_eq!(buddy.leaf_range(3), 512..1024);
+        assert_eq!(buddy.leaf_range(1024), 0..1);
+        assert_eq!(buddy.leaf_range(2047), 1023..1024);
+
+        // Children split their parent
+        for pos in 1..1024 {
+            let (node, left, right) = (
+                buddy.leaf_range(pos),
+                buddy.leaf_range(pos * 2),
+                buddy.leaf_range(pos * 2 + 1),
+            );
+
+            assert_eq!((node.start, node.end), (left.start, right.end));
+            assert_eq!(left.end, right.start);
+        }
+    }
+
+    #[test]
+    #[cfg(miri)]
+    fn miri_alloc_free() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1 << 6, &Global).unwrap();
+        let addrs: Vec<_> = (0..32).map(|_| buddy.alloc(2).unwrap()).collect();
+
+        assert!(buddy.alloc(1).is_none());
+
+        for i in addrs {
+            buddy.free(i, 2);
+        }
+
+        let addrs: Vec<_> = (0..32).map(|_| buddy.alloc(2).unwrap()).collect();
+
+        for i in addrs {
+            buddy.free(i, 2);
+        }
+    }
+
+    #[test]
+    fn state_width() {
+        use state::{NodeState, CONTAINER_LEVELS, FIRST_LEAF, STATE_BITS};
+
+        #[cfg(feature = "narrow-state")]
+        const {
+            assert!(STATE_BITS <= 32)
+        };
+
+        // Locking every position of a container must stay within STATE_BITS
+        let mut state = NodeState(0);
+
+        for pos in 1..(1 << CONTAINER_LEVELS) as u8 {
+            state = if pos < FIRST_LEAF {
+                state.lock_not_leaf(pos)
+            } else {
+                state.lock_leaf(pos)
+            };
+        }
+
+        assert!(state.0 < 1 << STATE_BITS);
+    }
+
+    #[test]
+    #[cfg(feature = "no-coalesce-state")]
+    fn no_coalesce_state() {
+        use state::{NodeState, CONTAINER_LEVELS, FIRST_LEAF, STATE_BITS};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Three bits per leaf buy one more level in the same word
+        const {
+            assert!(
+                CONTAINER_LEVELS == if usize::BITS < 64 { 4 } else { 5 }
+                    || cfg!(feature = "narrow-state")
+            );
+            assert!(STATE_BITS == FIRST_LEAF as usize - 1 + FIRST_LEAF as usize * 3);
+        };
+
+        // A leaf is merged into only while it's in use from below and not allocated itself
+        let leaf = FIRST_LEAF + 1;
+        let left = NodeState(0).occupy_left(leaf);
+
+        assert!(left.is_left_merging(leaf) && !left.is_right_merging(leaf));
+        assert_eq!(left.merge_left(leaf), NodeState(0));
+        assert!(!NodeState(0).lock_leaf(leaf).is_left_merging(leaf));
+        assert!(!NodeState(0).is_left_merging(leaf));
+
+        // Pages and pairs sit in the lowest containers, so frees merge across containers
+        // while the other threads allocate around them
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+        let owned = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for pages in [1, 1, 2, 4] {
+                let (buddy, owned) = (&buddy, &owned);
+
+                s.spawn(move || {
+                    for _ in 0..5000 {
+                        let Some(addr) = buddy.alloc(pages) else {
+                            continue;
+                        };
+                        let mask = ((1 << pages) - 1) << (addr / PAGE_SIZE);
+
+                        assert_eq!(owned.fetch_or(mask, Ordering::Relaxed) & mask, 0);
+                        owned.fetch_and(!mask, Ordering::Relaxed);
+                        buddy.free(addr, pages).unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(buddy.validate(), Ok(()));
+        assert_eq!(buddy.alloc(64), Some(0));
+    }
+
+    #[test]
+    fn alloc_child() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1024, &Global).unwrap();
+
+        assert!(buddy.__try_alloc_node(513).is_none());
+        assert!(buddy.__try_alloc_node(513 * 2 + 1).is_some());
+    }
+
+    #[test]
+    fn basic_alloc() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let mut vec = Vec::with_capacity(8);
+
+        for _ in 0..8 {
+            vec.push(MemRegion::new(buddy.alloc(2).unwrap(), 2 * PAGE_SIZE));
+        }
+
+        assert!(!intersection(&vec));
+        assert!(buddy.alloc(1).is_none());
+    }
+
+    #[test]
+    fn basic_alloc_1() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1024, &Global).unwrap();
+        let mut vec = Vec::with_capacity(8);
+
+        for _ in 0..512 {
+            vec.push(MemRegion::new(buddy.alloc(2).unwrap(), 2 * PAGE_SIZE));
+        }
+
+        assert!(!intersection(&vec));
+        assert!(buddy.alloc(1).is_none());
+    }
+
+    #[test]
+    fn alloc_alignment() {
+        type Buddy<'a> = BuddyAlloc<'a, PAGE_SIZE, Cpu, Global>;
+
+        let start = 3 * 64 * PAGE_SIZE;
+        let mut pages = 1;
+
+        while pages <= 64 {
+            for fragments in [0, 3] {
+                let buddy = Buddy::new(start, 64, &Global).unwrap();
+                let align = Buddy::align_of_alloc(pages);
+
+                for _ in 0..fragments {
+                    assert_eq!(buddy.alloc(1).unwrap() % PAGE_SIZE, 0);
+                }
+
+                while let Some(addr) = buddy.alloc(pages) {
+                    assert_eq!(addr % align, 0);
+                }
+            }
+
+            pages *= 2;
+        }
+
+        assert_eq!(Buddy::align_of_alloc(3), 4 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn basic_free() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let mut addrs = Vec::with_capacity(16);
+
+        for _ in 0..5 {
+            addrs.push(buddy.alloc(2).unwrap());
+        }
+
+        for i in addrs {
+            buddy.free(i, 2);
+        }
+
+        for _ in 0..8 {
+            assert!(buddy.alloc(2).is_some())
+        }
+    }
+
+    #[test]
+    fn alloc_colored() {
+        let alloc = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+        let mut count = 0;
+
+        while let Some(addr) = alloc.alloc_colored(1, 2, 4) {
+            assert_eq!((addr / PAGE_SIZE) % 4, 2);
+            count += 1;
+        }
+
+        assert_eq!(count, 16);
+        assert!(alloc.alloc_colored(1, 4, 4).is_none());
+
+        let addr = alloc.alloc_colored(2, 0, 4).unwrap();
+        assert_eq!((addr / PAGE_SIZE) % 4, 0);
+        assert!(alloc.alloc_colored(4, 0, 4).is_none());
+    }
+
+    #[test]
+    fn alloc_on_node() {
+        struct Halves;
+
+        // Pages 0..40 are on node 0, the rest of the 64 pages on node 1
+        impl numa::NumaMap for Halves {
+            fn node_of(page_index: usize) -> u32 {
+                (page_index >= 40) as u32
+            }
+        }
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+        let on_node = |pages, node| buddy.alloc_on_node::<Halves>(pages, node);
+
+        assert_eq!(on_node(32, 1), None);
+        assert_eq!(on_node(16, 1), Some(48 * PAGE_SIZE));
+        assert_eq!(on_node(16, 1), None);
+        assert_eq!(on_node(8, 0), Some(0));
+        assert_eq!(on_node(16, 0), Some(16 * PAGE_SIZE));
+
+        // Only 32..40 is left on node 0 and 40..48 on node 1
+        assert_eq!(on_node(16, 0), None);
+        assert_eq!(on_node(8, 1), Some(40 * PAGE_SIZE));
+        assert_eq!(on_node(8, 0), Some(8 * PAGE_SIZE));
+        assert_eq!(on_node(8, 0), Some(32 * PAGE_SIZE));
+    }
+
+    #[test]
+    fn alloc_single_group() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+
+        // Groups of 6 pages split every third 4 page block
+        let spanning: Vec<_> = (0..16)
+            .filter(|i| buddy.is_interleaved(i * 4 * PAGE_SIZE, 4, 6))
+            .collect();
+
+        assert_eq!(spanning, [1, 4, 7, 10, 13]);
+        assert!(!buddy.is_interleaved(0, 64, 64));
+        assert!(buddy.is_interleaved(0, 2, 1));
+
+        let addrs: Vec<_> = (0..)
+            .map_while(|_| buddy.alloc_single_group(4, 6))
+            .collect();
+
+        assert_eq!(addrs.len(), 11);
+        assert!(addrs
+            .iter()
+            .all(|a| !buddy.is_interleaved(*a, 4, 6) && !spanning.contains(&(a / PAGE_SIZE / 4))));
+        assert_eq!(buddy.free_pages(), 20);
+        assert_eq!(buddy.alloc_single_group(8, 6), None);
+    }
+
+    #[test]
+    fn bench_reset() {
+        let mut buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new_sized(0, 200, &Global).unwrap();
+        buddy.set_order_cache(4);
+
+        // The tail reserved by `new_sized` is taken again
+        assert_eq!(buddy.free_pages(), 200);
+        unsafe { buddy.bench_reset() };
+        assert_eq!(buddy.free_pages(), 200);
+
+        for round in 0..64 {
+            let pages = 1 << (round % 4);
+            let addrs: Vec<_> = (0..).map_while(|_| buddy.alloc(pages)).collect();
+
+            assert_eq!(addrs.len() * pages, 200);
+            assert!(buddy.is_full());
+
+            // Leave blocks in the lazy buffers and the order cache for the reset to drop
+            for addr in &addrs[..4] {
+                buddy.free_lazy(*addr, pages);
+            }
+
+            for addr in &addrs[4..8] {
+                buddy.free(*addr, pages);
+            }
+
+            unsafe { buddy.bench_reset() };
+            assert_eq!(buddy.free_pages(), 200);
+            assert_eq!(buddy.validate(), Ok(()));
+        }
+
+        buddy.inject_commit_panic(true);
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| buddy.alloc(1))).is_err());
+        buddy.inject_commit_panic(false);
+
+        buddy.reset();
+        assert!(!buddy.is_poisoned());
+        assert_eq!(buddy.alloc(256), None);
+        assert_eq!(buddy.alloc(128), Some(0));
+        assert_eq!(buddy.alloc(64), Some(128 * PAGE_SIZE));
+        assert_eq!(buddy.alloc(8), Some(192 * PAGE_SIZE));
+        assert!(buddy.is_full());
+
+        // A reservation freed before the reset stays free
+        buddy.free(200 * PAGE_SIZE, 8).unwrap();
+        buddy.reset();
+        assert_eq!(buddy.free_pages(), 208);
+
+        // Pages of the bitmap and those reserved by the builder are taken again
+        let mut buddy =
+            BuddyAlloc::<PAGE_SIZE, Cpu, _>::new_from_bitmap(0, 64, &[0b1110 << 8], &Global)
+                .unwrap();
+        buddy.alloc(16).unwrap();
+        buddy.reset();
+        assert_eq!(buddy.free_pages(), 61);
+        assert_eq!(buddy.occupy(9 * PAGE_SIZE, 1), None);
+        assert_eq!(buddy.occupy(8 * PAGE_SIZE, 1), Some(()));
+
+        let mut buddy = builder::BuddyAllocBuilder::<PAGE_SIZE, Cpu, _>::new(&Global)
+            .order(6)
+            .min_order(2)
+            .reserved(&[(17 * PAGE_SIZE, 3)])
+            .build()
+            .unwrap();
+        buddy.alloc(32).unwrap();
+        buddy.reset();
+        assert_eq!(buddy.free_pages(), 60);
+        assert_eq!(buddy.occupy(16 * PAGE_SIZE, 4), None);
+        assert_eq!(buddy.validate(), Ok(()));
+
+        // Merging keeps the reservations of both halves
+        let lower = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new_sized(0, 60, &Global).unwrap();
+        let upper = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(64 * PAGE_SIZE, 64, &Global).unwrap();
+        let mut merged = BuddyAlloc::merge(lower, upper).unwrap();
+
+        merged.alloc(64).unwrap();
+        merged.reset();
+        assert_eq!(merged.free_pages(), 124);
+        assert_eq!(merged.validate(), Ok(()));
+    }
+
+    #[test]
+    fn alloc_tracked() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+
+        let (addr, wasted) = buddy.alloc_tracked(5 * PAGE_SIZE).unwrap();
+        assert_eq!(addr % (8 * PAGE_SIZE), 0);
+        assert_eq!(wasted, 3 * PAGE_SIZE);
+
+        assert_eq!(buddy.alloc_tracked(PAGE_SIZE - 10).unwrap().1, 10);
+        assert_eq!(buddy.alloc_tracked(64 * PAGE_SIZE), None);
+        assert_eq!(buddy.alloc_tracked(1 << 30), None);
+        assert_eq!(buddy.alloc(usize::MAX), None);
+
+        #[cfg(feature = "stats")]
+        assert_eq!(
+            buddy.stats().internal_fragmentation_bytes,
+            3 * PAGE_SIZE + 10
+        );
+    }
+
+    #[test]
+    fn alloc_from_larger() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+
+        // A fresh arena serves the request itself instead of giving up the whole arena
+        assert_eq!(buddy.alloc_from_larger(1).map(|(_, pages)| pages), Some(1));
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let first = buddy.alloc(4).unwrap();
+
+        let (addr, pages) = buddy.alloc_from_larger(3).unwrap();
+        assert_eq!(pages, 4);
+
+        // The remainder of the block is owned by the caller, so it is never handed out
+        let other = buddy.alloc(8).unwrap();
+        let mut vec = vec![
+            MemRegion::new(first, 4 * PAGE_SIZE),
+            MemRegion::new(addr, pages * PAGE_SIZE),
+            MemRegion::new(other, 8 * PAGE_SIZE),
+        ];
+
+        assert!(!intersection(&vec));
+        assert!(buddy.alloc(1).is_none());
+
+        buddy.free(addr, pages);
+        vec[1] = MemRegion::new(buddy.alloc(4).unwrap(), 4 * PAGE_SIZE);
+        assert!(!intersection(&vec));
+
+        assert!(buddy.alloc_from_larger(1).is_none());
+        assert!(buddy.alloc_from_larger(32).is_none());
+    }
+
+    #[test]
+    fn alloc_from_larger_asks_oom_handler_once() {
+        use oom::{OomAction, OomHandler};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Default)]
+        struct Count(AtomicUsize);
+
+        impl OomHandler for Count {
+            fn on_oom(&self, order: usize) -> OomAction {
+                assert_eq!(order, 0);
+                self.0.fetch_add(1, Ordering::Relaxed);
+                OomAction::Fail
+            }
+        }
+
+        let buddy =
+            BuddyAlloc::<PAGE_SIZE, Cpu, _, _>::with_oom_handler(0, 64, &Global, Count::default())
+                .unwrap();
+
+        while buddy.alloc(1).is_some() {}
+
+        let before = buddy.oom_handler().0.load(Ordering::Relaxed);
+
+        assert_eq!(buddy.alloc_from_larger(1), None);
+        assert_eq!(buddy.oom_handler().0.load(Ordering::Relaxed), before + 1);
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn free_stats() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let addrs: Vec<_> = (0..8).map(|_| buddy.alloc(2).unwrap()).collect();
+        let allocated = buddy.stats();
+
+        for i in addrs {
+            buddy.free(i, 2);
+        }
+
+        let stats = buddy.stats();
+
+        assert_eq!(stats.allocs, 8);
+        assert_eq!(stats.frees, 8);
+        assert!(stats.cas - allocated.cas >= 8);
+        assert_eq!(stats.free_retries, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn stats_delta() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+
+        for _ in 0..4 {
+            buddy.alloc(1).unwrap();
+        }
+        let prev = buddy.stats();
+
+        let addrs: Vec<_> = (0..10).map(|_| buddy.alloc(2).unwrap()).collect();
+        for a in &addrs[..3] {
+            buddy.free(*a, 2).unwrap();
+        }
+
+        let delta = buddy.stats_delta(&prev);
+        assert_eq!((delta.allocs, delta.frees), (10, 3));
+
+        buddy.reset_stats();
+        assert_eq!(buddy.stats(), stats::BuddyStats::default());
+
+        // A sample taken before the reset looks like a wrapped counter
+        buddy.alloc(1).unwrap();
+        assert_eq!(
+            buddy.stats_delta(&prev).allocs,
+            1usize.wrapping_sub(prev.allocs)
+        );
+    }
+
+    #[test]
+    fn alloc_owned() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(PAGE_SIZE, 16, &Global).unwrap();
+
+        {
+            let a = buddy.alloc_owned(3).unwrap();
+            let b = buddy.alloc_owned(1).unwrap();
+
+            assert_eq!(a.pages(), 4);
+            assert_eq!(a.as_non_null().unwrap().len(), 4 * PAGE_SIZE);
+            assert_eq!(buddy.view().free_pages(), 11);
+
+            let raw = b.into_raw();
+            buddy.free(raw, 1).unwrap();
+        }
+
+        assert_eq!(buddy.view().free_pages(), 16);
+
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _pages = buddy.alloc_owned(8).unwrap();
+            panic!("dropped during unwinding");
+        }));
+
+        assert!(res.is_err());
+        assert_eq!(buddy.view().free_pages(), 16);
+        assert!(buddy.alloc(16).is_some());
+    }
+
+    #[test]
+    fn alloc_region() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+
+        {
+            let mut region = buddy.alloc_region(16).unwrap();
+            let base = region.addr();
+
+            #[cfg(feature = "stats")]
+            let before = buddy.stats();
+
+            // Pieces come from the region in order, with no container updates
+            let pieces: Vec<_> = (0..).map_while(|_| region.carve(2)).collect();
+
+            #[cfg(feature = "stats")]
+            assert_eq!(buddy.stats(), before);
+
+            assert_eq!(
+                pieces,
+                (0..8).map(|i| base + i * 2 * PAGE_SIZE).collect::<Vec<_>>()
+            );
+            assert_eq!((region.remaining(), buddy.free_pages()), (0, 48));
+        }
+
+        assert_eq!(buddy.free_pages(), 64);
+
+        // A piece is aligned to its size, the gap before it is lost
+        let mut region = buddy.alloc_region(16).unwrap();
+        let base = region.addr();
+
+        assert_eq!(region.carve(1), Some(base));
+        assert_eq!(region.carve(4), Some(base + 4 * PAGE_SIZE));
+        assert_eq!(region.carve(16), None);
+        assert_eq!(region.remaining(), 8);
+    }
+
+    #[test]
+    fn occupy() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let region = 4 * PAGE_SIZE;
+
+        assert_eq!(buddy.occupy(region, 4), Some(()));
+        assert_eq!(buddy.occupy(region, 4), None);
+        assert_eq!(buddy.occupy(PAGE_SIZE, 4), None);
+        assert_eq!(buddy.occupy(16 * PAGE_SIZE, 1), None);
+
+        let addrs: Vec<_> = (0..12).map_while(|_| buddy.alloc(1)).collect();
+        assert_eq!(addrs.len(), 12);
+        assert!(addrs
+            .iter()
+            .all(|a| !(region..region + 4 * PAGE_SIZE).contains(a)));
+        assert_eq!(buddy.alloc(1), None);
+
+        buddy.free(region, 4).unwrap();
+        assert_eq!(buddy.alloc(4), Some(region));
+        buddy.free(region, 4).unwrap();
+        addrs.iter().for_each(|&a| buddy.free(a, 1).unwrap());
+        assert_eq!(buddy.alloc(16), Some(0));
+    }
+
+    #[test]
+    fn transfer() {
+        type Buddy<'a> = BuddyAlloc<'a, PAGE_SIZE, Cpu, Global>;
+
+        // Pages 16..32 lie in both arenas and belong to `lower`, `upper` keeps them reserved
+        let lower = Buddy::new(0, 32, &Global).unwrap();
+        let upper = Buddy::new(16 * PAGE_SIZE, 32, &Global).unwrap();
+        let shared = 16 * PAGE_SIZE..32 * PAGE_SIZE;
+
+        for addr in shared.clone().step_by(4 * PAGE_SIZE) {
+            upper.occupy(addr, 4).unwrap();
+        }
+
+        assert_eq!(lower.transfer(&upper, shared.start, 4), Some(()));
+        assert_eq!(lower.transfer(&upper, shared.start, 4), None);
+        assert_eq!(lower.transfer(&upper, 0, 4), None);
+        assert_eq!(lower.transfer(&upper, shared.start, 16), None);
+
+        let moved = shared.start..shared.start + 4 * PAGE_SIZE;
+        let from_lower: Vec<_> = (0..).map_while(|_| lower.alloc(1)).collect();
+        let from_upper: Vec<_> = (0..).map_while(|_| upper.alloc(1)).collect();
+
+        assert_eq!(from_lower.len(), 28);
+        assert!(from_lower.iter().all(|a| !moved.contains(a)));
+        assert_eq!(from_upper.len(), 20);
+        assert_eq!(from_upper.iter().filter(|a| moved.contains(a)).count(), 4);
+    }
+
+    #[test]
+    fn order_cache() {
+        let mut buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        buddy.set_order_cache(4);
+
+        let pages: Vec<_> = (0..8).map(|_| buddy.alloc(1).unwrap()).collect();
+        pages.iter().for_each(|&p| buddy.free(p, 1).unwrap());
+
+        // Half of the frees stayed in the cache and are handed out again first
+        assert_eq!(buddy.view().free_pages(), 12);
+        assert_eq!(buddy.validate(), Ok(()));
+        #[cfg(debug_assertions)]
+        assert_eq!(buddy.free(pages[0], 1), None);
+
+        let again = buddy.alloc(1).unwrap();
+        assert!(pages[..4].contains(&again));
+        assert_eq!(buddy.view().free_pages(), 12);
+        buddy.free(again, 1).unwrap();
+
+        // An allocation that doesn't fit in the tree drains the cache
+        assert_eq!(buddy.alloc(16), Some(0));
+        buddy.free(0, 16).unwrap();
+        assert_eq!(buddy.view().free_pages(), 0);
+
+        buddy.flush();
+        assert_eq!(buddy.view().free_pages(), 16);
+    }
+
+    #[test]
+    fn concurrent_order_cache() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let mut buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 32, &Global).unwrap();
+        let owned = AtomicUsize::new(0);
+
+        buddy.set_order_cache(8);
+
+        thread::scope(|s| {
+            for pages in [1, 1, 2, 4] {
+                let (buddy, owned) = (&buddy, &owned);
+
+                s.spawn(move || {
+                    for _ in 0..5000 {
+                        let Some(addr) = buddy.alloc(pages) else {
+                            continue;
+                        };
+                        let mask = ((1 << pages) - 1) << (addr / PAGE_SIZE);
+
+                        assert_eq!(owned.fetch_or(mask, Ordering::Relaxed) & mask, 0);
+                        owned.fetch_and(!mask, Ordering::Relaxed);
+                        buddy.free(addr, pages).unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(buddy.validate(), Ok(()));
+        buddy.flush();
+        assert_eq!(buddy.view().free_pages(), 32);
+        assert_eq!(buddy.alloc(32), Some(0));
+    }
+
+    #[test]
+    fn free_pages_counter() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1024, &Global).unwrap();
+
+        let held: Vec<Vec<(usize, usize)>> = thread::scope(|s| {
+            let threads: Vec<_> = (0..8)
+                .map(|t| {
+                    let buddy = &buddy;
+
+                    s.spawn(move || {
+                        let mut held = Vec::new();
+
+                        for i in 0..2000 {
+                            let pages = 1 << ((t + i) % 4);
+
+                            if let Some(addr) = buddy.alloc(pages) {
+                                held.push((addr, pages));
+                            }
+
+                            if held.len() > 4 {
+                                let (addr, pages) = held.swap_remove(i % held.len());
+
+                                if i % 3 == 0 {
+                                    held.push((buddy.recycle(addr, pages).unwrap(), pages));
+                                } else {
+                                    buddy.free(addr, pages).unwrap();
+                                }
+                            }
+                        }
+
+                        held
+                    })
+                })
+                .collect();
+
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+
+        let used: usize = held.iter().flatten().map(|(_, pages)| pages).sum();
+
+        assert_eq!(buddy.free_pages(), 1024 - used);
+        assert_eq!(buddy.free_pages(), buddy.view().free_pages());
+
+        for (addr, pages) in held.into_iter().flatten() {
+            buddy.free(addr, pages).unwrap();
+        }
+
+        assert_eq!(buddy.free_pages(), 1024);
+
+        let dup = buddy.duplicate_in(&Global).unwrap();
+        let addr = buddy.alloc(16).unwrap();
+
+        assert_eq!(buddy.free_pages(), 1008);
+        assert_eq!(dup.free_pages(), 1024);
+        buddy.free(addr, 16).unwrap();
+    }
+
+    #[test]
+    fn alloc_below() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(PAGE_SIZE, 64, &Global).unwrap();
+        let max_addr = PAGE_SIZE + 32 * PAGE_SIZE;
+        let mut taken = 0;
+
+        while let Some(addr) = buddy.alloc_below(2, max_addr) {
+            assert!(addr + 2 * PAGE_SIZE <= max_addr);
+            taken += 2;
+        }
+
+        // The upper half is still free, but out of reach
+        assert_eq!(taken, 32);
+        assert_eq!(buddy.view().free_pages(), 32);
+        assert_eq!(buddy.alloc_below(1, max_addr - 1), None);
+        assert_eq!(buddy.alloc_below(1, 0), None);
+        assert_eq!(buddy.alloc_below(64, usize::MAX), None);
+        assert_eq!(buddy.alloc_below(32, usize::MAX), Some(max_addr));
+    }
+
+    #[test]
+    fn try_alloc() {
+        use error::AllocError;
+
+        let mut buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1024, &Global).unwrap();
+
+        assert_eq!(buddy.try_alloc(2048), Err(AllocError::OrderTooLarge));
+        assert_eq!(buddy.try_alloc(1024), Ok(0));
+        assert_eq!(buddy.try_alloc(1), Err(AllocError::OutOfMemory));
+        buddy.free(0, 1024).unwrap();
+
+        // Every update fails, without a limit this would spin forever
+        buddy.set_attempt_limit(Some(64));
+        buddy.fail_updates(usize::MAX);
+        assert_eq!(buddy.try_alloc(1), Err(AllocError::NoProgress));
+        assert_eq!(buddy.alloc(4), None);
+        buddy.fail_updates(0);
+
+        #[cfg(feature = "stats")]
+        assert_eq!(buddy.stats().no_progress, 2);
+
+        // Giving up after the block was locked rolls the partial allocation back
+        buddy.set_attempt_limit(Some(1));
+        buddy.disable_fast_path(true);
+        assert_eq!(buddy.try_alloc(1), Err(AllocError::NoProgress));
+        assert_eq!(buddy.validate(), Ok(()));
+        assert_eq!(buddy.view().free_pages(), 1024);
+
+        buddy.set_attempt_limit(None);
+        assert!(buddy.try_alloc(1).is_ok());
+    }
+
+    #[test]
+    fn low_watermark() {
+        use error::AllocError;
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+
+        buddy.set_low_watermark(16);
+
+        // Allocations go through while at least 16 pages are free
+        for _ in 0..3 {
+            assert!(buddy.try_alloc(16).is_ok());
+        }
+
+        // Exactly at the watermark is still fine, one page below isn't
+        assert!(buddy.try_alloc(1).is_ok());
+        assert_eq!(buddy.free_pages(), 15);
+        assert_eq!(buddy.try_alloc(1), Err(AllocError::BelowWatermark));
+        assert_eq!(buddy.alloc(1), None);
+
+        assert!(buddy.try_alloc_forced(8).is_ok());
+        assert!(buddy.try_alloc_forced(4).is_ok());
+        assert_eq!(buddy.free_pages(), 3);
+
+        buddy.set_low_watermark(0);
+        assert!(buddy.alloc(2).is_some());
+    }
+
+    #[test]
+    fn relocatable_metadata() {
+        let mut buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 256, &Global).unwrap();
+        let blocks: Vec<_> = [1, 4, 16, 64]
+            .map(|pages| (buddy.alloc(pages).unwrap(), pages))
+            .into();
+
+        buddy.__relocate_metadata();
+        assert_eq!(buddy.validate(), Ok(()));
+        assert_eq!(buddy.view().free_pages(), 256 - 85);
+
+        for (addr, pages) in blocks {
+            buddy.free(addr, pages).unwrap();
+        }
+
+        assert_eq!(buddy.validate(), Ok(()));
+        assert_eq!(buddy.alloc(256), Some(0));
+    }
+
+    #[test]
+    fn simulate_alloc() {
+        struct Cpu5;
+
+        impl cpuid::Cpu for Cpu5 {
+            fn current_cpu() -> usize {
+                5
+            }
+        }
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu5, _>::new(0, 1024, &Global).unwrap();
+        let container = |addr: usize, pages: usize| {
+            buddy.container_of((1024 / pages + addr / (pages * PAGE_SIZE)) as u32)
+        };
+
+        // Within a container, at a container root and a block taking a part of the previous
+        for pages in [4, 16, 1, 64] {
+            let (addr, old, new) = buddy.simulate_alloc(pages).unwrap();
+            let index = container(addr, pages);
+
+            assert_eq!(buddy.container_state(index), Some(old));
+            assert_eq!(buddy.simulate_alloc(pages), Some((addr, old, new)));
+            assert_eq!(buddy.alloc(pages), Some(addr));
+            assert_eq!(buddy.container_state(index), Some(new));
+        }
+
+        while buddy.alloc(1).is_some() {}
+
+        assert_eq!(buddy.simulate_alloc(1), None);
+        assert_eq!(buddy.simulate_alloc(2048), None);
+    }
+
+    #[test]
+    fn huge_cpu_id() {
+        struct CpuMax;
+
+        impl cpuid::Cpu for CpuMax {
+            fn current_cpu() -> usize {
+                usize::MAX
+            }
+        }
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, CpuMax, _>::new(0, 64, &Global).unwrap();
+
+        for order in 0..=6 {
+            let pages = 1 << order;
+
+            assert!(buddy.simulate_alloc(pages).is_some());
+
+            let addrs: Vec<_> = (0..64 / pages)
+                .map(|_| buddy.alloc(pages).unwrap())
+                .collect();
+
+            assert!(buddy.alloc(pages).is_none());
+
+            for addr in addrs {
+                buddy.free_lazy(addr, pages);
+            }
+
+            buddy.flush();
+            assert_eq!(buddy.validate(), Ok(()));
+        }
+
+        assert_eq!(buddy.thread_token().cpu(), usize::MAX);
+        assert_eq!(buddy.free_pages(), 64);
+    }
+
+    #[test]
+    fn static_backend() {
+        static BACKEND: testing::StaticBackend<8192> = testing::StaticBackend::new();
+
+        // Single pages live in the containers below the root one
+        let pages = 1 << state::CONTAINER_LEVELS;
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, pages, &BACKEND).unwrap();
+        let addrs: Vec<_> = (0..pages).map(|_| buddy.alloc(1).unwrap()).collect();
+
+        assert!(BACKEND.used() >= buddy.metadata_bytes());
+        assert!(buddy.alloc(1).is_none());
+
+        for addr in addrs {
+            buddy.free(addr, 1).unwrap();
+        }
+
+        assert_eq!(buddy.alloc(pages), Some(0));
+        assert_eq!(buddy.validate(), Ok(()));
+        drop(buddy);
+
+        unsafe { BACKEND.reset() };
+        assert!(BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1 << 20, &BACKEND).is_none());
+    }
+
+    #[test]
+    fn free_checked() {
+        use error::FreeError;
+
+        let mut buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+        let block = buddy.alloc(4).unwrap();
+        let page = buddy.alloc(1).unwrap();
+
+        assert_eq!(
+            buddy.free_checked(block + PAGE_SIZE),
+            Err(FreeError::NotBlockStart)
+        );
+        assert_eq!(
+            buddy.free_checked(block + 3 * PAGE_SIZE),
+            Err(FreeError::NotBlockStart)
+        );
+        assert_eq!(buddy.free_checked(block + 1), Err(FreeError::NotBlockStart));
+        assert_eq!(
+            buddy.free_checked(page ^ PAGE_SIZE),
+            Err(FreeError::NotAllocated)
+        );
+        assert_eq!(
+            buddy.free_checked(64 * PAGE_SIZE),
+            Err(FreeError::NotAllocated)
+        );
+
+        assert_eq!(buddy.free_checked(block), Ok(2));
+        assert_eq!(buddy.free_checked(block), Err(FreeError::NotAllocated));
+        assert_eq!(buddy.free_checked(page), Ok(0));
+        assert_eq!(buddy.free_checked(page), Err(FreeError::NotAllocated));
+        assert_eq!(buddy.validate(), Ok(()));
+
+        // Cached blocks stay recorded in the tree
+        buddy.set_order_cache(4);
+
+        let page = buddy.alloc(1).unwrap();
+
+        assert_eq!(buddy.free_checked(page), Ok(0));
+        assert_eq!(buddy.free_checked(page), Err(FreeError::NotAllocated));
+
+        // and so do queued ones
+        let block = buddy.alloc(4).unwrap();
+
+        buddy.free_lazy(block, 4);
+        assert_eq!(buddy.free_checked(block), Err(FreeError::NotAllocated));
+
+        buddy.flush();
+        assert_eq!(buddy.validate(), Ok(()));
+        assert_eq!(buddy.alloc(64), Some(0));
+    }
+
+    #[test]
+    fn alloc_prefer_aligned() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+
+        // Every 16 page boundary gets a page until none is left
+        for page in [0, 16, 32, 48] {
+            assert_eq!(buddy.alloc_prefer_aligned(1, 16), Some(page * PAGE_SIZE));
+        }
+
+        let other = buddy.alloc_prefer_aligned(1, 16).unwrap();
+
+        assert!(!(other / PAGE_SIZE).is_multiple_of(16));
+        assert_eq!(
+            buddy
+                .alloc_prefer_aligned(8, 4)
+                .map(|a| a % (8 * PAGE_SIZE)),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn is_full() {
+        let mut buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let mut addrs: Vec<_> = (0..15).map(|_| buddy.alloc(1).unwrap()).collect();
+
+        assert!(!buddy.is_full());
+        addrs.push(buddy.alloc(1).unwrap());
+        assert!(buddy.is_full());
+
+        buddy.free(addrs.pop().unwrap(), 1).unwrap();
+        assert!(!buddy.is_full());
+        addrs.push(buddy.alloc(1).unwrap());
+
+        // A cached block can still be handed out, a lazily freed one can't
+        buddy.set_order_cache(1);
+        buddy.free(addrs.pop().unwrap(), 1).unwrap();
+        assert!(!buddy.is_full());
+        addrs.push(buddy.alloc(1).unwrap());
+        assert!(buddy.is_full());
+
+        buddy.free_lazy(addrs.pop().unwrap(), 1);
+        assert!(buddy.is_full() && buddy.alloc(1).is_none());
+        buddy.flush();
+        assert!(!buddy.is_full());
+    }
+
+    #[test]
+    fn alloc_range() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+        let blocks: Vec<_> = [1, 3, 8, 5]
+            .into_iter()
+            .map(|pages| buddy.alloc_range(pages).unwrap())
+            .collect();
+
+        for (block, pages) in blocks.iter().zip([1, 4, 8, 8]) {
+            assert_eq!(block.len(), pages * PAGE_SIZE);
+            assert!(block.start.is_multiple_of(pages * PAGE_SIZE));
+        }
+
+        let regions: Vec<_> = blocks
+            .iter()
+            .map(|b| MemRegion::new(b.start, b.len()))
+            .collect();
+
+        assert!(!intersection(&regions));
+        assert_eq!(buddy.free_pages(), 64 - 21);
+        assert_eq!(buddy.alloc_range(64), None);
+    }
+
+    #[test]
+    fn alloc_guarded() {
+        let buddy = Arc::new(BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 256, &Global).unwrap());
+        let guarded: Vec<_> = (0..16).map(|_| buddy.alloc_guarded(4).unwrap()).collect();
+        let guards: Vec<_> = guarded
+            .iter()
+            .map(|addr| MemRegion::new(addr + 4 * PAGE_SIZE, 4 * PAGE_SIZE))
+            .collect();
+
+        // Everything else is taken concurrently, none of it from a guard
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let buddy = buddy.clone();
+                thread::spawn(move || {
+                    let mut taken = Vec::new();
+
+                    while let Some(addr) = buddy.alloc(1) {
+                        taken.push(MemRegion::new(addr, PAGE_SIZE));
+                    }
+                    taken
+                })
+            })
+            .collect();
+
+        let mut taken: Vec<_> = threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect();
+
+        assert_eq!(taken.len(), 128);
+        taken.extend(&guards);
+        assert!(!intersection(&taken));
+
+        buddy.free_guarded(guarded[0], 4).unwrap();
+        assert_eq!(buddy.alloc(8), Some(guarded[0]));
+        assert_eq!(buddy.alloc_guarded(1024), None);
+    }
+
+    #[test]
+    fn recycle() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let addr = buddy.alloc(1).unwrap();
+
+        assert_eq!(buddy.recycle(addr, 1), Some(addr));
+        assert_eq!((0..15).filter_map(|_| buddy.alloc(1)).count(), 15);
+        assert_eq!(buddy.alloc(1), None);
+
+        // A block that isn't allocated is rejected like by `free`
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+
+        if cfg!(debug_assertions) {
+            assert_eq!(buddy.recycle(0, 4), None);
+        }
+
+        assert_eq!(buddy.validate(), Ok(()));
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn recycle_stats() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let addr = buddy.alloc(1).unwrap();
+
+        let before = buddy.stats();
+        let addr = buddy.recycle(addr, 1).unwrap();
+        let recycled = buddy.stats();
+
+        buddy.free(addr, 1);
+        buddy.alloc(1).unwrap();
+        let after = buddy.stats();
+
+        assert_eq!((recycled.allocs, recycled.frees), (2, 1));
+        assert_eq!(recycled.cas, before.cas);
+        assert!(after.cas > recycled.cas);
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn retry_histogram() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1024, &Global).unwrap();
+
+        // The first allocation locks the empty root container in one update
+        assert_eq!(buddy.alloc(1024), Some(0));
+        assert_eq!(buddy.retry_histogram(), [1, 0, 0, 0, 0, 0, 0, 0]);
+        buddy.free(0, 1024).unwrap();
+        buddy.reset_stats();
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..500 {
+                        let addr = buddy.alloc(2).unwrap();
+                        buddy.free(addr, 2).unwrap();
+                    }
+                });
+            }
+        });
+
+        let histogram = buddy.retry_histogram();
+        assert_eq!(histogram.iter().sum::<usize>(), buddy.stats().allocs);
+        assert_eq!(buddy.stats().allocs, 2000);
+    }
+
+    #[test]
+    fn free_coalesce_full() {
+        let mut buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+        buddy.set_order_cache(4);
+
+        // One half is held as a whole, the other one is split into pages living in the
+        // containers at the bottom of the tree
+        let half = buddy.alloc(32).unwrap();
+        let mut pages: Vec<_> = (0..32).map(|_| buddy.alloc(1).unwrap()).collect();
+        pages.sort();
+
+        assert_eq!(buddy.free_coalesce_full(pages[0], 1), Some(0));
+
+        for addr in &pages[2..] {
+            buddy.free(*addr, 1).unwrap();
+        }
+
+        buddy.flush();
+        assert_eq!(buddy.free_coalesce_full(pages[1], 1), Some(5));
+        assert_eq!(buddy.view().largest_available_order(), Some(5));
+
+        // A plain free would have kept the page in the order cache
+        let addr = buddy.alloc(1).unwrap();
+        assert_eq!(buddy.free_coalesce_full(addr, 1), Some(5));
+
+        assert_eq!(buddy.free_coalesce_full(half, 32), Some(6));
+        assert_eq!(buddy.validate(), Ok(()));
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn coalesce_count() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+        let free = |pages: &[usize]| {
+            let before = buddy.coalesce_count();
+
+            for page in pages {
+                buddy.free(page * PAGE_SIZE, 1).unwrap();
+            }
+
+            buddy.coalesce_count() - before
+        };
+
+        while buddy.alloc(1).is_some() {}
+
+        assert_eq!(free(&[4, 6]), 0);
+        assert_eq!(free(&[0, 1]), 1);
+        assert_eq!(free(&[2, 3]), 2);
+        assert_eq!(free(&[5, 7]), 4);
+        assert_eq!(buddy.stats().coalesces, 7);
+    }
+
+    #[test]
+    fn alloc_near() {
+        let start = 64 * PAGE_SIZE;
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(start, 64, &Global).unwrap();
+        let hint = start + 37 * PAGE_SIZE + 100;
+
+        assert_eq!(buddy.alloc_near(1, hint), Some(start + 37 * PAGE_SIZE));
+        assert_eq!(buddy.alloc_near(1, hint), Some(start + 38 * PAGE_SIZE));
+        assert_eq!(buddy.alloc_near(1, hint), Some(start + 36 * PAGE_SIZE));
+        assert_eq!(buddy.alloc_near(8, hint), Some(start + 40 * PAGE_SIZE));
+
+        // Hints outside of the arena are clamped to its ends
+        assert_eq!(buddy.alloc_near(1, 0), Some(start));
+        assert_eq!(
+            buddy.alloc_near(1, usize::MAX),
+            Some(start + 63 * PAGE_SIZE)
+        );
+
+        assert_eq!(buddy.alloc_near(128, hint), None);
+    }
+
+    #[test]
+    fn level_nodes() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+        let max_order = 6;
+
+        for order in 0..=max_order {
+            let nodes = buddy.level_nodes(order);
+
+            assert_eq!(nodes.len(), 1 << (max_order - order));
+            assert_eq!(nodes.start, 1 << (max_order - order));
+        }
+    }
+
+    #[test]
+    fn leaf_scan_range() {
+        // The last leaf is the last node of the tree, nothing past it may be visited
+        for pages in [1, 2, 8, 64] {
+            let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, pages, &Global).unwrap();
+            let mut addrs: Vec<_> = core::iter::from_fn(|| buddy.alloc(1)).collect();
+
+            addrs.sort();
+            assert_eq!(buddy.level_nodes(0), pages as u32..2 * pages as u32);
+            assert_eq!(addrs, (0..pages).map(|p| p * PAGE_SIZE).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn max_scan_steps() {
+        // Every block of an order fits into the scan range, so an empty arena serves exactly
+        // as many blocks as there are steps
+        for order in [0, 1, 3, 6] {
+            let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+            let served = core::iter::from_fn(|| buddy.alloc(1 << order)).count();
+
+            assert_eq!(buddy.max_scan_steps(order), served);
+            assert_eq!(buddy.max_scan_steps(order), 1 << (6 - order));
+        }
+    }
+
+    #[test]
+    fn poisoned_after_panic() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+        let addr = buddy.alloc(1).unwrap();
+
+        buddy.inject_commit_panic(true);
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| buddy.alloc(1))).is_err());
+        buddy.inject_commit_panic(false);
+
+        assert!(buddy.is_poisoned());
+        assert_eq!(buddy.alloc(1), None);
+
+        buddy.free(addr, 1);
+        assert_eq!(buddy.alloc(1), None);
+
+        // Allocations that pick their block without a scan are refused as well
+        assert_eq!(buddy.alloc_near(1, 0), None);
+        assert_eq!(buddy.alloc_below(1, 64 * PAGE_SIZE), None);
+        assert_eq!(buddy.occupy(0, 1), None);
+        assert_eq!(buddy.try_claim_all(), None);
+    }
+
+    #[test]
+    fn order_for_layout() {
+        use core::alloc::Layout;
+        use error::AllocError;
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+        let order =
+            |size, align| buddy.order_for_layout(Layout::from_size_align(size, align).unwrap());
+
+        assert_eq!(order(0, 1), Ok(0));
+        assert_eq!(order(PAGE_SIZE + 1, 8), Ok(1));
+        assert_eq!(order(3 * PAGE_SIZE, PAGE_SIZE), Ok(2));
+
+        // Alignment dominates the size
+        assert_eq!(order(8, 4 * PAGE_SIZE), Ok(2));
+        assert_eq!(order(PAGE_SIZE, 64 * PAGE_SIZE), Ok(6));
+        assert_eq!(order(8, 128 * PAGE_SIZE), Err(AllocError::OrderTooLarge));
+
+        assert_eq!(order(65 * PAGE_SIZE, 8), Err(AllocError::OrderTooLarge));
+    }
+
+    #[test]
+    fn alloc_zeroed() {
+        let mut arena = vec![0xAAu8; 8 * PAGE_SIZE];
+        let start = arena.as_mut_ptr() as usize;
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(start, 8, &Global).unwrap();
+        let page =
+            |addr: usize| unsafe { core::slice::from_raw_parts(addr as *const u8, PAGE_SIZE) };
+
+        // A fresh arena is taken as zeroed, so the junk stays in place
+        let addr = unsafe { buddy.alloc_zeroed(1) }.unwrap();
+        assert!(page(addr).iter().all(|b| *b == 0xAA));
+
+        let freed = addr;
+        buddy.free(freed, 1);
+
+        let mut addrs = Vec::new();
+        while let Some(addr) = unsafe { buddy.alloc_zeroed(1) } {
+            addrs.push(addr);
+        }
+
+        // Only the freed page is dirty and gets cleared
+        assert_eq!(addrs.len(), 8);
+        for addr in addrs {
+            let expected = if addr == freed { 0 } else { 0xAA };
+            assert!(page(addr).iter().all(|b| *b == expected));
+        }
+    }
+
+    #[test]
+    fn fast_path_state() {
+        let fast = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1024, &Global).unwrap();
+        let slow = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1024, &Global).unwrap();
+
+        slow.disable_fast_path(true);
+
+        // Container roots of every level, the whole arena is taken by then
+        for pos in [300, 256, 17, 1] {
+            assert_eq!(fast.__try_alloc_node(pos), slow.__try_alloc_node(pos));
+
+            let states = |b: &BuddyAlloc<PAGE_SIZE, Cpu, _>| {
+                (0..)
+                    .map_while(|i| b.container_state(i))
+                    .collect::<Vec<_>>()
+            };
+
+            assert_eq!(states(&fast), states(&slow));
+        }
+    }
+
+    #[test]
+    fn oom_retry() {
+        use oom::{OomAction, OomHandler};
+        use std::sync::OnceLock;
+
+        type Buddy = BuddyAlloc<'static, PAGE_SIZE, Cpu, Global, Reclaim>;
+
+        // Hands back a reserved block on the first OOM
+        struct Reclaim {
+            buddy: OnceLock<&'static Buddy>,
+            reserved: Mutex<Option<usize>>,
+        }
+
+        impl OomHandler for Reclaim {
+            fn on_oom(&self, _order: usize) -> OomAction {
+                match self.reserved.lock().unwrap().take() {
+                    Some(addr) => {
+                        self.buddy.get().unwrap().free(addr, 1);
+                        OomAction::Retry
+                    }
+                    None => OomAction::Fail,
+                }
+            }
+        }
+
+        let reclaim = Reclaim {
+            buddy: OnceLock::new(),
+            reserved: Mutex::new(None),
+        };
+        let buddy: &'static Buddy = std::boxed::Box::leak(std::boxed::Box::new(
+            Buddy::with_oom_handler(0, 16, &Global, reclaim).unwrap(),
+        ));
+
+        buddy.oom_handler().buddy.set(buddy).ok().unwrap();
+
+        let reserved = buddy.alloc(1).unwrap();
+        *buddy.oom_handler().reserved.lock().unwrap() = Some(reserved);
+
+        let addrs: Vec<_> = (0..15).map(|_| buddy.alloc(1).unwrap()).collect();
+        assert!(!addrs.contains(&reserved));
+
+        assert_eq!(buddy.alloc(1), Some(reserved));
+        assert_eq!(buddy.alloc(1), None);
+    }
+
+    #[test]
+    fn scan_wraps_once() {
+        struct LastCpu;
+
+        impl cpuid::Cpu for LastCpu {
+            fn current_cpu() -> usize {
+                30
+            }
+        }
+
+        // The scan starts in the last 4 page block, and every ancestor it hits on the way
+        // makes it jump over the rest of the level
+        let buddy = BuddyAlloc::<PAGE_SIZE, LastCpu, _>::new(0, 32, &Global).unwrap();
+
+        while buddy.alloc(4).is_some() {}
+        assert_eq!(buddy.alloc(1), None);
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn scan_restarts() {
+        struct Cpu10;
+
+        impl cpuid::Cpu for Cpu10 {
+            fn current_cpu() -> usize {
+                10
+            }
+        }
+
+        // Pages 10 to 15 are found without going back to the start. Every other page takes
+        // a restart, and so does the failing allocation at the end
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu10, _>::new(0, 16, &Global).unwrap();
+
+        for page in 10..16 {
+            assert_eq!(buddy.alloc(1), Some(page * PAGE_SIZE));
+        }
+
+        assert_eq!(buddy.scan_restarts(), 0);
+
+        while buddy.alloc(1).is_some() {}
+        assert_eq!(buddy.scan_restarts(), 11);
+        assert_eq!(buddy.stats().scan_restarts, 11);
+    }
+
+    #[test]
+    fn restart_policy() {
+        use buddy_alloc::RestartPolicy;
+
+        struct Cpu10;
+
+        impl cpuid::Cpu for Cpu10 {
+            fn current_cpu() -> usize {
+                10
+            }
+        }
+
+        // Single pages are scanned from page 10
+        let mut buddy = BuddyAlloc::<PAGE_SIZE, Cpu10, _>::new(0, 16, &Global).unwrap();
+        assert_eq!(buddy.restart_policy(), RestartPolicy::Once);
+
+        while buddy.alloc(1).is_some() {}
+        buddy.free(0, 1).unwrap();
+
+        buddy.set_restart_policy(RestartPolicy::Never);
+        assert_eq!(buddy.alloc(1), None);
+
+        buddy.free(12 * PAGE_SIZE, 1).unwrap();
+        assert_eq!(buddy.alloc(1), Some(12 * PAGE_SIZE));
+
+        buddy.set_restart_policy(RestartPolicy::Once);
+        assert_eq!(buddy.alloc(1), Some(0));
+
+        buddy.free(0, 1).unwrap();
+        buddy.set_restart_policy(RestartPolicy::Times(3));
+        assert_eq!(buddy.max_scan_steps(0), 48);
+        assert_eq!(buddy.alloc(1), Some(0));
+        assert_eq!(buddy.alloc(1), None);
+    }
+
+    #[test]
+    fn new_sized() {
+        for pages in [15, 1000, 1023] {
+            let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new_sized(0, pages, &Global).unwrap();
+            let top = buddy.pages().ilog2() as usize;
+
+            assert_eq!(buddy.free_pages(), pages);
+            assert_eq!(buddy.view().largest_available_order(), Some(top - 1));
+            assert_eq!(buddy.alloc(1 << top), None);
+
+            // Every page handed out lies below `pages`
+            let addrs: Vec<_> = (0..).map_while(|_| buddy.alloc(1)).collect();
+
+            assert_eq!(addrs.len(), pages);
+            assert!(addrs.iter().all(|a| *a < pages * PAGE_SIZE));
+            assert_eq!(buddy.validate(), Ok(()));
+        }
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new_sized(0, 16, &Global).unwrap();
+        assert_eq!(buddy.alloc(16), Some(0));
+    }
+
+    #[test]
+    fn min_order() {
+        use builder::BuddyAllocBuilder;
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::with_min_order(0, 64, 2, &Global).unwrap();
+        let plain = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+
+        assert_eq!(buddy.min_order(), 2);
+        assert!(buddy.metadata_bytes() < plain.metadata_bytes());
+
+        // Pages and page orders are counted as usual, only the block sizes are floored
+        let addrs: Vec<_> = (0..16).map(|i| buddy.alloc(1 + i % 2).unwrap()).collect();
+
+        assert!(addrs.iter().all(|a| a % (4 * PAGE_SIZE) == 0));
+        assert!(buddy.is_full());
+        assert_eq!(buddy.validate(), Ok(()));
+
+        assert_eq!(buddy.free_checked(addrs[0]), Ok(2));
+        buddy.free(addrs[1], 1).unwrap();
+        assert_eq!(buddy.free_pages(), 8);
+        assert_eq!(buddy.alloc_range(1).unwrap().len(), 4 * PAGE_SIZE);
+        assert_eq!(buddy.view().level_states(0).count(), 16);
+
+        // Reservations are rounded out to whole blocks as well
+        let sized = BuddyAllocBuilder::<PAGE_SIZE, Cpu, _>::new(&Global)
+            .order(6)
+            .min_order(2)
+            .reserved(&[(61 * PAGE_SIZE, 3)])
+            .build()
+            .unwrap();
+
+        assert_eq!(sized.free_pages(), 60);
+        assert_eq!(sized.validate(), Ok(()));
+
+        assert!(BuddyAlloc::<PAGE_SIZE, Cpu, _>::with_min_order(0, 2, 2, &Global).is_none());
+    }
+
+    #[test]
+    fn builder() {
+        use builder::{ArenaInfo, BuddyAllocBuilder};
+        use error::BuddyInitError;
+
+        let start = 0x100000;
+        let reserved = [(start, 3), (start + 8 * PAGE_SIZE, 1)];
+        let builder = BuddyAllocBuilder::<PAGE_SIZE, Cpu, _>::new(&Global)
+            .start(start)
+            .order(4)
+            .reserved(&reserved);
+
+        assert_eq!(
+            builder.describe(),
+            Ok(ArenaInfo {
+                total_pages: 16,
+                reserved_pages: 4,
+                metadata_bytes: tree::Tree::<PAGE_SIZE, Global>::metadata_bytes(16, 0),
+                max_order: 4,
+            })
+        );
+
+        let buddy = builder.build().unwrap();
+        let addrs: Vec<_> = core::iter::from_fn(|| buddy.alloc(1)).collect();
+
+        assert_eq!(addrs.len(), 12);
+        assert!(addrs
+            .iter()
+            .all(|a| *a >= start + 3 * PAGE_SIZE && *a != start + 8 * PAGE_SIZE));
+
+        let builder = BuddyAllocBuilder::<PAGE_SIZE, Cpu, _>::new(&Global);
+        assert_eq!(builder.describe(), Err(BuddyInitError::InvalidOrder));
+
+        let overlap = [(0, 2), (PAGE_SIZE, 1)];
+        let builder = builder.order(2).reserved(&overlap);
+        assert_eq!(builder.build().err(), Some(BuddyInitError::BadReservation));
+
+        let outside = [(3 * PAGE_SIZE, 2)];
+        let builder = BuddyAllocBuilder::<PAGE_SIZE, Cpu, _>::new(&Global)
+            .order(2)
+            .reserved(&outside);
+        assert_eq!(builder.describe(), Err(BuddyInitError::BadReservation));
+    }
+
+    #[test]
+    fn view() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1024, &Global).unwrap();
+        let view = buddy.view();
+
+        assert_send_sync(&view);
+        assert_eq!(view.free_pages(), 1024);
+        assert_eq!(view.largest_available_order(), Some(10));
+        assert_eq!(view.free_blocks(10), 1);
+
+        let addr = buddy.alloc(1).unwrap();
+        buddy.alloc(16).unwrap();
+
+        // Both blocks may land in the same half of the arena, depending on the CPU
+        assert_eq!(view.free_pages(), 1024 - 17);
+        assert!(matches!(view.largest_available_order(), Some(8 | 9)));
+        assert_eq!(view.free_blocks(0), 1);
+
+        buddy.free(addr, 1);
+        assert_eq!(view.free_pages(), 1024 - 16);
+
+        let done = std::sync::atomic::AtomicBool::new(false);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    assert!(view.free_pages() <= 1024 - 16);
+                    assert!(view.container_state(0).is_some());
+                }
+            });
+
+            while buddy.alloc(1).is_some() {}
+            done.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+
+        assert_eq!(view.free_pages(), 0);
+        assert_eq!(view.largest_available_order(), None);
+    }
+
+    #[test]
+    fn rounded_pages() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 3000, &Global).unwrap();
+
+        assert_eq!(buddy.pages(), 4096);
+        assert_eq!(buddy.size(), 4096 * PAGE_SIZE);
+        assert_eq!(buddy.alloc(4096), Some(0));
+    }
+
+    #[test]
+    fn high_arena() {
+        let start = 0usize.wrapping_sub(16 * PAGE_SIZE);
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(start, 16, &Global).unwrap();
+        let mut addrs: Vec<_> = (0..16).map(|_| buddy.alloc(1).unwrap()).collect();
+
+        addrs.sort();
+        assert_eq!(
+            addrs,
+            (0..16).map(|i| start + i * PAGE_SIZE).collect::<Vec<_>>()
+        );
+        assert_eq!(buddy.alloc(1), None);
+
+        buddy.free(usize::MAX - PAGE_SIZE + 1, 1).unwrap();
+        assert_eq!(buddy.alloc(1), Some(usize::MAX - PAGE_SIZE + 1));
+
+        assert!(BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(start + PAGE_SIZE, 16, &Global).is_none());
+    }
+
+    #[test]
+    fn units() {
+        use units::{ByteAddr, PageIndex};
+
+        assert_eq!(
+            ByteAddr(3 * PAGE_SIZE).to_page::<PAGE_SIZE>(),
+            Some(PageIndex(3))
+        );
+        assert_eq!(ByteAddr(3 * PAGE_SIZE + 1).to_page::<PAGE_SIZE>(), None);
+        assert_eq!(
+            PageIndex(3).to_addr::<PAGE_SIZE>(),
+            Some(ByteAddr(3 * PAGE_SIZE))
+        );
+        assert_eq!(PageIndex(usize::MAX).to_addr::<PAGE_SIZE>(), None);
+        assert_eq!(usize::from(PageIndex(7)), 7);
+
+        let start = PageIndex(16).to_addr::<PAGE_SIZE>().unwrap();
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new_at(start, 16, &Global).unwrap();
+        let addr = buddy.alloc_addr(4).unwrap();
+
+        assert!((16..32).contains(&addr.to_page::<PAGE_SIZE>().unwrap().0));
+        assert_eq!(buddy.free_addr(addr, 4), Some(()));
+        assert_eq!(buddy.alloc(16), Some(usize::from(start)));
+    }
+
+    #[test]
+    fn for_region() {
+        type Buddy<'a> = BuddyAlloc<'a, PAGE_SIZE, Cpu, Global>;
+
+        let buddy = Buddy::for_region(0x10_0000, 16 << 20, &Global).unwrap();
+        assert_eq!(buddy.alloc(4096), Some(0x10_0000));
+        assert_eq!(buddy.alloc(1), None);
+
+        assert!(Buddy::for_region(0, PAGE_SIZE, &Global).is_some());
+        assert!(Buddy::for_region(0, 0, &Global).is_none());
+        assert!(Buddy::for_region(0, 3 * PAGE_SIZE, &Global).is_none());
+        assert!(Buddy::for_region(0, 4 * PAGE_SIZE + 1, &Global).is_none());
+        assert!(Buddy::for_region(0, PAGE_SIZE / 2, &Global).is_none());
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "hw-cpu",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    fn hw_cpu() {
+        use cpuid::{Cpu, HwCpu};
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, HwCpu, _>::new(0, 16, &Global).unwrap();
+
+        // The id stays put unless the thread migrates, so only check it's usable
+        let _ = HwCpu::current_cpu();
+        assert!(buddy.alloc(1).is_some());
+    }
+
+    #[test]
+    fn new_from_bitmap() {
+        type Buddy<'a> = BuddyAlloc<'a, PAGE_SIZE, Cpu, Global>;
+
+        // Pages 3..bits + 6 are taken, which crosses a word boundary
+        let bits = usize::BITS as usize;
+        let taken = 3 * PAGE_SIZE..(bits + 6) * PAGE_SIZE;
+        let buddy = Buddy::new_from_bitmap(0, 128, &[!0b111, (1 << 6) - 1], &Global).unwrap();
+
+        assert_eq!(buddy.view().free_pages(), 128 - (bits + 3));
+        assert_eq!(buddy.validate(), Ok(()));
+
+        let pages: Vec<_> = (0..).map_while(|_| buddy.alloc(1)).collect();
+        assert_eq!(pages.len(), 128 - (bits + 3));
+        assert!(pages.iter().all(|a| !taken.contains(a)));
+
+        // A run is split into few aligned blocks instead of single pages
+        let buddy = Buddy::new_from_bitmap(0, 128, &[0, !0], &Global).unwrap();
+        assert_eq!(buddy.free(bits * PAGE_SIZE, bits), Some(()));
+        assert_eq!(buddy.alloc(128), Some(0));
+
+        assert!(Buddy::new_from_bitmap(0, bits, &[0, 1], &Global).is_none());
+    }
+
+    #[test]
+    fn try_claim_all() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0x8000, 64, &Global).unwrap();
+
+        assert_eq!(buddy.try_claim_all(), Some(0x8000));
+        assert_eq!(buddy.try_claim_all(), None);
+        assert_eq!(buddy.alloc(1), None);
+        buddy.free(0x8000, 64).unwrap();
+
+        let addr = buddy.alloc(1).unwrap();
+        assert_eq!(buddy.try_claim_all(), None);
+
+        buddy.free(addr, 1).unwrap();
+        assert_eq!(buddy.try_claim_all(), Some(0x8000));
+    }
+
+    #[test]
+    fn managed_range() {
+        for (pages, managed) in [(1, 1), (3, 4), (10, 16), (64, 64), (1000, 1024)] {
+            let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0x4000, pages, &Global).unwrap();
+
+            assert_eq!(buddy.managed_pages(), managed);
+            assert_eq!(buddy.managed_range(), 0x4000..0x4000 + managed * PAGE_SIZE);
+            assert_eq!(buddy.alloc(managed), Some(0x4000));
+        }
+    }
+
+    #[test]
+    fn duplicate_in() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+        let blocks: Vec<_> = [1, 4, 2, 8, 1]
+            .into_iter()
+            .map(|pages| (buddy.alloc(pages).unwrap(), pages))
+            .collect();
+        let (lazy, _) = blocks[0];
+
+        buddy.free_lazy(lazy, 1);
+
+        let dup = buddy.duplicate_in(&Global).unwrap();
+        assert_eq!(dup.view().free_pages(), 64 - 15);
+
+        for &(addr, pages) in &blocks[1..3] {
+            buddy.free(addr, pages).unwrap();
+        }
+
+        assert_eq!(buddy.view().free_pages(), 64 - 9);
+        assert_eq!(dup.view().free_pages(), 64 - 15);
+
+        for &(addr, pages) in &blocks[1..] {
+            dup.free(addr, pages).unwrap();
+        }
+
+        assert_eq!(dup.alloc(64), Some(0));
+        assert_eq!(buddy.alloc(64), None);
+    }
+
+    #[test]
+    fn free_bytes() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+        let view = buddy.view();
+
+        for pages in [1, 8, 2, 16, 4] {
+            buddy.alloc(pages).unwrap();
+
+            let order = view.largest_available_order().unwrap();
+            assert_eq!(view.largest_free_bytes(), (1 << order) * PAGE_SIZE);
+            assert_eq!(view.free_bytes(), view.free_pages() * PAGE_SIZE);
+        }
+
+        while buddy.alloc(1).is_some() {}
+        assert_eq!((view.free_bytes(), view.largest_free_bytes()), (0, 0));
+    }
+
+    #[test]
+    fn level_states() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let mut used = [false; 16];
+
+        for pages in [1, 4, 2, 1] {
+            let first = buddy.alloc(pages).unwrap() / PAGE_SIZE;
+            used[first..first + pages].fill(true);
+        }
+
+        let view = buddy.view();
+        let pages: Vec<_> = view.level_states(0).collect();
+        let pairs: Vec<_> = view.level_states(1).map(|(_, free)| free).collect();
+
+        assert_eq!(pages.len(), 16);
+        for (i, &(pos, free)) in pages.iter().enumerate() {
+            assert_eq!((pos, free), (16 + i as u32, !used[i]));
+        }
+
+        let expected: Vec<_> = used.chunks(2).map(|p| !p[0] && !p[1]).collect();
+        assert_eq!(pairs, expected);
+        assert_eq!(view.level_states(5).count(), 0);
+    }
+
+    #[test]
+    fn slab() {
+        use slab::SlabOnBuddy;
+
+        type Slab<'b, 'a> = SlabOnBuddy<'b, 'a, 128, PAGE_SIZE, Cpu, Global, oom::FailFast>;
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let slab = Slab::new(&buddy).unwrap();
+        let per_page = Slab::PER_PAGE;
+
+        assert_eq!(per_page, PAGE_SIZE / 128);
+
+        let mut objs: Vec<_> = (0..3 * per_page).map(|_| slab.alloc().unwrap()).collect();
+        objs.sort();
+        objs.dedup();
+
+        assert_eq!(objs.len(), 3 * per_page);
+        assert!(objs.iter().all(|o| o % 128 == 0));
+        assert_eq!(slab.pages(), 3);
+        assert_eq!(buddy.view().free_pages(), 13);
+
+        // A freed object of a full page is the next one handed out
+        slab.free(objs[per_page + 2]);
+        assert_eq!(slab.alloc(), Some(objs[per_page + 2]));
+        assert_eq!(slab.pages(), 3);
+
+        // An emptied page stays listed until trimmed
+        for obj in &objs[..per_page] {
+            slab.free(*obj);
+        }
+
+        assert_eq!(slab.pages(), 3);
+        slab.trim();
+        assert_eq!(slab.pages(), 2);
+        assert_eq!(buddy.view().free_pages(), 14);
+        assert_eq!(buddy.validate(), Ok(()));
+
+        // or until an allocation takes it off the list
+        for obj in &objs[per_page..2 * per_page] {
+            slab.free(*obj);
+        }
+
+        let obj = slab.alloc().unwrap();
+
+        assert!(!objs[per_page..].contains(&obj));
+        assert_eq!(slab.pages(), 2);
+        assert_eq!(buddy.view().free_pages(), 14);
+
+        // Pages still holding objects are freed along with the slab
+        drop(slab);
+        assert_eq!(buddy.alloc(16), Some(0));
+    }
+
+    #[test]
+    fn slab_object_sizes() {
+        use slab::SlabOnBuddy;
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+
+        // A page of a single object goes back as soon as it's freed
+        let whole = SlabOnBuddy::<'_, '_, PAGE_SIZE, PAGE_SIZE, _, _, _>::new(&buddy).unwrap();
+        let objs = [whole.alloc().unwrap(), whole.alloc().unwrap()];
+
+        assert_eq!(whole.pages(), 2);
+        whole.free(objs[0]);
+        assert_eq!(whole.pages(), 1);
+        whole.free(objs[1]);
+        assert_eq!(whole.pages(), 0);
+        drop(whole);
+
+        let half =
+            SlabOnBuddy::<'_, '_, { PAGE_SIZE / 2 }, PAGE_SIZE, _, _, _>::new(&buddy).unwrap();
+
+        let obj = half.alloc().unwrap();
+
+        assert_eq!(half.alloc(), Some(obj + PAGE_SIZE / 2));
+        assert_eq!(half.pages(), 1);
+        drop(half);
+
+        let tiny =
+            SlabOnBuddy::<'_, '_, { PAGE_SIZE / 64 }, PAGE_SIZE, _, _, _>::new(&buddy).unwrap();
+        let objs: Vec<_> = (0..64).map(|_| tiny.alloc().unwrap()).collect();
+
+        assert_eq!(tiny.pages(), 1);
+        assert!((0..64).all(|i| objs[i] == objs[0] + i * PAGE_SIZE / 64));
+        assert_ne!(tiny.alloc().unwrap() / PAGE_SIZE, objs[0] / PAGE_SIZE);
+        assert_eq!(tiny.pages(), 2);
+        drop(tiny);
+
+        assert_eq!(buddy.alloc(16), Some(0));
+    }
+
+    #[test]
+    fn slab_spills_listed_pages() {
+        use slab::SlabOnBuddy;
+
+        // More pages with room left than the list has slots
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 128, &Global).unwrap();
+        let slab =
+            SlabOnBuddy::<'_, '_, { PAGE_SIZE / 2 }, PAGE_SIZE, _, _, _>::new(&buddy).unwrap();
+        let objs: Vec<_> = (0..256).map(|_| slab.alloc().unwrap()).collect();
+
+        for obj in objs.iter().step_by(2) {
+            slab.free(*obj);
+        }
+
+        let again: Vec<_> = (0..128).map(|_| slab.alloc().unwrap()).collect();
+
+        assert_eq!(slab.pages(), 128);
+        assert!(again.iter().all(|o| o % PAGE_SIZE == 0));
+
+        for obj in objs.iter().skip(1).step_by(2).chain(&again) {
+            slab.free(*obj);
+        }
+
+        slab.trim();
+        assert_eq!(slab.pages(), 0);
+        assert_eq!(buddy.alloc(128), Some(0));
+    }
+
+    #[test]
+    fn concurrent_slab() {
+        use slab::SlabOnBuddy;
+        use std::collections::HashSet;
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+        let slab = SlabOnBuddy::<'_, '_, 256, PAGE_SIZE, _, _, _>::new(&buddy).unwrap();
+        let live = Mutex::new(HashSet::new());
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..50 {
+                        let objs: Vec<_> = (0..40).map(|_| slab.alloc().unwrap()).collect();
+
+                        for obj in &objs {
+                            assert!(live.lock().unwrap().insert(*obj));
+                        }
+
+                        for obj in objs {
+                            live.lock().unwrap().remove(&obj);
+                            slab.free(obj);
+                        }
+                    }
+                });
+            }
+        });
+
+        slab.trim();
+        assert_eq!(slab.pages(), 0);
+        assert_eq!(buddy.alloc(64), Some(0));
+    }
+
+    #[test]
+    fn as_allocator() {
+        use core::alloc::Layout;
+        use std::alloc::Allocator;
+
+        let arena = Layout::from_size_align(64 * PAGE_SIZE, 64 * PAGE_SIZE).unwrap();
+        let mem = Global.allocate(arena).unwrap().cast::<u8>();
+        let buddy =
+            BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(mem.as_ptr() as usize, 64, &Global).unwrap();
+        let alloc = unsafe { buddy.as_allocator() };
+
+        let layout = Layout::from_size_align(PAGE_SIZE, 16 * PAGE_SIZE).unwrap();
+        let block = alloc.allocate(layout).unwrap();
+        assert_eq!(block.cast::<u8>().as_ptr() as usize % (16 * PAGE_SIZE), 0);
+        assert_eq!(block.len(), 16 * PAGE_SIZE);
+
+        let mut v = Vec::new_in(&alloc);
+        v.extend(0..4 * PAGE_SIZE as u64);
+        v.truncate(10);
+        v.shrink_to_fit();
+        assert!(v.iter().copied().eq(0..10));
+        assert_eq!(buddy.view().free_pages(), 64 - 16 - 1);
+
+        drop(v);
+        unsafe { alloc.deallocate(block.cast(), layout) };
+        assert_eq!(buddy.view().free_pages(), 64);
+
+        assert!(alloc
+            .allocate(Layout::new::<[u8; 128 * PAGE_SIZE]>())
+            .is_err());
+
+        drop(buddy);
+        unsafe { Global.deallocate(mem, arena) };
+    }
+
+    #[test]
+    fn new_phys() {
+        let phys = 0x8000_0000;
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new_phys(phys, 16, &Global).unwrap();
+
+        for _ in 0..16 {
+            let addr = buddy.alloc(1).unwrap();
+            assert!((phys..phys + 16 * PAGE_SIZE).contains(&addr));
+        }
+
+        assert_eq!(buddy.alloc(1), None);
+    }
+
+    #[test]
+    fn separate_metadata_backend() {
+        use core::cell::UnsafeCell;
+
+        #[repr(align(4096))]
+        struct Region(UnsafeCell<[u8; 16 * PAGE_SIZE]>);
+
+        unsafe impl Sync for Region {}
+
+        static REGION: Region = Region(UnsafeCell::new([0; 16 * PAGE_SIZE]));
+
+        let start = REGION.0.get() as usize;
+        let region = start..start + 16 * PAGE_SIZE;
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(start, 16, &Global).unwrap();
+
+        // Scribbling over every block can't reach the metadata
+        while let Some(addr) = buddy.alloc(2) {
+            assert!(region.contains(&addr));
+            unsafe { core::ptr::write_bytes(addr as *mut u8, 0xAA, 2 * PAGE_SIZE) };
+        }
+
+        assert_eq!(buddy.validate(), Ok(()));
+    }
+
+    #[test]
+    fn merge() {
+        let arena = |start| BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(start, 256, &Global).unwrap();
+
+        assert!(BuddyAlloc::merge(arena(0), arena(512 * PAGE_SIZE)).is_none());
+
+        let lower = arena(0);
+        let upper = arena(256 * PAGE_SIZE);
+
+        // Half of each arena, in blocks spanning one or more containers
+        let mut blocks: Vec<_> = (0..8).map(|_| (lower.alloc(16).unwrap(), 16)).collect();
+        blocks.extend((0..128).map(|_| (upper.alloc(1).unwrap(), 1)));
+
+        let merged = BuddyAlloc::merge(lower, upper).unwrap();
+
+        assert_eq!(merged.view().free_pages(), 256);
+        assert_eq!(merged.alloc(512), None);
+        assert_eq!(merged.alloc(256), None);
+
+        for (addr, pages) in blocks {
+            merged.free(addr, pages).unwrap();
+        }
+
+        assert_eq!(merged.view().free_pages(), 512);
+        assert_eq!(merged.alloc(512), Some(0));
+    }
+
+    #[test]
+    fn fixed_cpu_sequence() {
+        struct FixedCpu;
+
+        impl cpuid::Cpu for FixedCpu {
+            fn current_cpu() -> usize {
+                5
+            }
+        }
+
+        // The same sequence is expected with and without the `unchecked` feature
+        let buddy = BuddyAlloc::<PAGE_SIZE, FixedCpu, _>::new(0, 64, &Global).unwrap();
+        let addrs: Vec<_> = [1, 2, 1, 4, 8, 1, 16, 2]
+            .into_iter()
+            .map(|p| buddy.alloc(p).unwrap() / PAGE_SIZE)
+            .collect();
+
+        assert_eq!(addrs, vec![5, 10, 6, 20, 40, 7, 48, 12]);
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, FixedCpu, _>::new(0, 64, &Global).unwrap();
+        let token = buddy.thread_token();
+        let with_token: Vec<_> = [1, 2, 1, 4, 8, 1, 16, 2]
+            .into_iter()
+            .map(|p| buddy.alloc_with_token(p, &token).unwrap() / PAGE_SIZE)
+            .collect();
+
+        assert_eq!(token.cpu(), 5);
+        assert_eq!(with_token, addrs);
+    }
+
+    #[test]
+    fn token_skips_cpu_lookup() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static LOOKUPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct CountingCpu;
+
+        impl cpuid::Cpu for CountingCpu {
+            fn current_cpu() -> usize {
+                LOOKUPS.fetch_add(1, Ordering::Relaxed);
+                3
+            }
+        }
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, CountingCpu, _>::new(0, 64, &Global).unwrap();
+        let token = buddy.thread_token();
+        let lookups = LOOKUPS.load(Ordering::Relaxed);
+
+        for pages in [1, 4, 2, 8] {
+            buddy.alloc_with_token(pages, &token).unwrap();
+        }
+
+        assert_eq!(LOOKUPS.load(Ordering::Relaxed), lookups);
+        assert_eq!(buddy.free_pages(), 64 - 15);
+    }
+
+    #[test]
+    fn partition_of() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+
+        // 16 blocks of 4 pages make 15 stripes, so CPU 20 starts its scans in stripe 5
+        for cpu in [0, 3, 7, 14, 20] {
+            let token = cpuid::BuddyToken::new(cpu);
+            let addr = buddy.alloc_with_token(4, &token).unwrap();
+
+            assert_eq!(buddy.partition_of(addr, 4), Some(cpu % 15));
+        }
+
+        assert_eq!(buddy.partition_of(60 * PAGE_SIZE, 4), Some(0));
+        assert_eq!(buddy.partition_of(0, 64), Some(0));
+
+        // Addresses outside the arena and blocks larger than it have no stripe
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(64 * PAGE_SIZE, 64, &Global).unwrap();
+
+        assert_eq!(buddy.partition_of(0, 4), None);
+        assert_eq!(buddy.partition_of(128 * PAGE_SIZE, 4), None);
+        assert_eq!(buddy.partition_of(64 * PAGE_SIZE, 128), None);
+        assert_eq!(buddy.partition_of(127 * PAGE_SIZE, 1), Some(0));
+    }
+
+    #[test]
+    fn blocks_in_partition() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 64, &Global).unwrap();
+
+        for cpu in [3, 7] {
+            let token = cpuid::BuddyToken::new(cpu);
+
+            buddy.alloc_with_token(4, &token).unwrap();
+            buddy.alloc_with_token(1, &token).unwrap();
+        }
+
+        let blocks = |cpu| {
+            buddy
+                .blocks_in_partition(cpu)
+                .map(|(addr, pages)| (addr / PAGE_SIZE, pages))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(blocks(3), [(3, 1), (12, 4)]);
+        assert_eq!(blocks(7), [(7, 1), (28, 4)]);
+        assert_eq!(blocks(10), []);
 
-    #[derive(Eq, Clone, Copy, Hash, Debug)]
-    pub struct MemRegion {
-        pub start: usize,
-        pub size: usize,
+        buddy.free(12 * PAGE_SIZE, 4).unwrap();
+        assert_eq!(blocks(3), [(3, 1)]);
     }
 
-    impl MemRegion {
-        pub fn new(start: usize, size: usize) -> Self {
-            Self {
-                start: start,
-                size: size,
+    #[test]
+    #[cfg(feature = "yield")]
+    fn yield_on_contention() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static YIELDS: AtomicUsize = AtomicUsize::new(0);
+
+        struct YieldingCpu;
+
+        impl cpuid::Cpu for YieldingCpu {
+            fn current_cpu() -> usize {
+                0
             }
-        }
-    }
 
-    impl PartialEq for MemRegion {
-        fn eq(&self, other: &Self) -> bool {
-            if self.start > other.start {
-                if other.start + other.size > self.start {
-                    true
-                } else {
-                    false
-                }
-            } else if self.start + self.size > other.start {
-                true
-            } else {
-                false
+            fn yield_now() {
+                YIELDS.fetch_add(1, Ordering::Relaxed);
             }
         }
-    }
 
-    pub fn intersection(nums: Vec<MemRegion>) -> bool {
-        for i in 0..nums.len() {
-            let mut new = nums.clone();
+        let buddy = BuddyAlloc::<PAGE_SIZE, YieldingCpu, _>::new(0, 64, &Global).unwrap();
 
-            new.remove(i);
+        buddy.disable_fast_path(true);
+        buddy.fail_updates(9);
 
-            for j in new {
-                if j == nums[i] {
-                    return true;
-                }
+        assert_eq!(buddy.alloc(1), Some(0));
+        assert_eq!(YIELDS.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn flush_cpu_cache() {
+        struct Cpu3;
+
+        impl cpuid::Cpu for Cpu3 {
+            fn current_cpu() -> usize {
+                3
             }
         }
 
-        false
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu3, _>::new(0, 16, &Global).unwrap();
+        let addrs: Vec<_> = (0..16).map(|_| buddy.alloc(1).unwrap()).collect();
+
+        buddy.free_lazy(addrs[7], 1);
+        assert_eq!(buddy.alloc(1), None);
+
+        buddy.flush_cpu_cache(2);
+        assert_eq!(buddy.alloc(1), None);
+
+        buddy.flush_cpu_cache(3);
+        assert_eq!(buddy.alloc(1), Some(addrs[7]));
     }
 
     #[test]
-    fn test_helpers() {
-        {
-            let mut vec = Vec::with_capacity(2);
+    #[cfg(feature = "oplog")]
+    fn recent_ops() {
+        use oplog::{LoggedOp, Op, OPLOG_LEN};
 
-            vec.push(MemRegion::new(1, 10));
-            vec.push(MemRegion::new(2, 5));
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let state = || buddy.container_state(0).unwrap();
 
-            assert!(intersection(vec));
-        }
+        buddy.alloc(16).unwrap();
+        let locked = state();
+        buddy.free(0, 16).unwrap();
 
-        {
-            let mut vec = Vec::with_capacity(2);
+        let ops: Vec<_> = buddy.recent_ops().collect();
+        let entry = |op, old, new| LoggedOp {
+            op,
+            container_root: 1,
+            old,
+            new,
+        };
 
-            vec.push(MemRegion::new(1, 10));
-            vec.push(MemRegion::new(11, 10));
-            vec.push(MemRegion::new(21, 10));
+        assert_eq!(
+            ops,
+            vec![entry(Op::Alloc, 0, locked), entry(Op::Free, locked, 0)]
+        );
 
-            assert!(!intersection(vec));
+        for _ in 0..OPLOG_LEN {
+            let addr = buddy.alloc(1).unwrap();
+            buddy.free(addr, 1).unwrap();
         }
 
-        {
-            let mut vec = Vec::with_capacity(2);
+        // Single threaded, every update starts from the state the last one left behind
+        let ops: Vec<_> = buddy.recent_ops().collect();
+        assert_eq!(ops.len(), OPLOG_LEN);
 
-            vec.push(MemRegion::new(1, 10));
-            vec.push(MemRegion::new(10, 10));
-            vec.push(MemRegion::new(21, 10));
+        for (i, op) in ops.iter().enumerate() {
+            let prev = ops[..i]
+                .iter()
+                .rev()
+                .find(|o| o.container_root == op.container_root);
 
-            assert!(intersection(vec));
+            if let Some(prev) = prev {
+                assert_eq!(prev.new, op.old);
+            }
         }
+
+        assert!(ops.iter().any(|o| o.op == Op::Alloc) && ops.iter().any(|o| o.op == Op::Free));
     }
 
     #[test]
-    fn basic_create() {
-        let _buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 10, &Global).unwrap();
-        let _buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(1000, 1000, &Global).unwrap();
+    fn free_aligned() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let addr = buddy.alloc(4).unwrap();
+
+        buddy.free_aligned(addr, 4, 4);
+        assert_eq!(buddy.alloc(4), Some(addr));
     }
 
     #[test]
-    fn alloc_child() {
-        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1024, &Global).unwrap();
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn free_aligned_mismatch() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let addrs: Vec<_> = (0..4).map(|_| buddy.alloc(4).unwrap()).collect();
+        let addr = addrs
+            .into_iter()
+            .find(|a| a % (8 * PAGE_SIZE) != 0)
+            .unwrap();
 
-        assert!(buddy.__try_alloc_node(513).is_none());
-        assert!(buddy.__try_alloc_node(513 * 2 + 1).is_some());
+        buddy.free_aligned(addr, 4, 8);
     }
 
     #[test]
-    fn basic_alloc() {
+    fn last_leaf_fits() {
+        use state::{NodeState, FIRST_LEAF, STATE_BITS};
+
+        let last = FIRST_LEAF * 2 - 1;
+        let locked = NodeState(0).lock_leaf(last);
+
+        // The occupied bit of the last leaf is the top bit of the state
+        assert_eq!(locked.0.ilog2() as usize, STATE_BITS - 1);
+        assert!(STATE_BITS <= usize::BITS as usize);
+        assert!(locked.is_occupied(last) && !locked.is_allocable(last));
+        assert_eq!(locked.unlock_leaf(last), NodeState(0));
+    }
+
+    #[test]
+    #[cfg(all(debug_assertions, not(feature = "unchecked")))]
+    #[should_panic(expected = "out of range")]
+    fn container_pos_bounds() {
+        use state::{NodeState, FIRST_LEAF};
+
+        let last = FIRST_LEAF * 2 - 1;
+
+        assert!(NodeState(0).lock_leaf(last).is_occupied(last));
+        NodeState(0).lock_leaf(last + 1);
+    }
+
+    #[test]
+    fn concurrent_coalesce() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Pages are roots of the lowest containers, so every free of a page that has a free
+        // buddy coalesces across containers while the other threads allocate around it
         let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
-        let mut vec = Vec::with_capacity(8);
+        let owned = AtomicUsize::new(0);
 
-        for _ in 0..8 {
-            vec.push(MemRegion::new(buddy.alloc(2).unwrap(), 2 * PAGE_SIZE));
-        }
+        thread::scope(|s| {
+            for pages in [1, 1, 2, 4] {
+                let (buddy, owned) = (&buddy, &owned);
 
-        assert!(!intersection(vec));
-        assert!(buddy.alloc(1).is_none());
+                s.spawn(move || {
+                    for _ in 0..5000 {
+                        let Some(addr) = buddy.alloc(pages) else {
+                            continue;
+                        };
+                        let mask = ((1 << pages) - 1) << (addr / PAGE_SIZE);
+
+                        assert_eq!(owned.fetch_or(mask, Ordering::Relaxed) & mask, 0);
+                        owned.fetch_and(!mask, Ordering::Relaxed);
+                        buddy.free(addr, pages).unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(buddy.validate(), Ok(()));
+        assert_eq!(buddy.alloc(16), Some(0));
     }
 
     #[test]
-    fn basic_alloc_1() {
-        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1024, &Global).unwrap();
-        let mut vec = Vec::with_capacity(8);
+    fn concurrent_buddy_free() {
+        use std::sync::Barrier;
 
-        for _ in 0..512 {
-            vec.push(MemRegion::new(buddy.alloc(2).unwrap(), 2 * PAGE_SIZE));
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let barrier = Barrier::new(2);
+
+        while buddy.alloc(1).is_some() {}
+
+        let full: Vec<_> = (0..).map_while(|i| buddy.container_state(i)).collect();
+
+        for _ in 0..2000 {
+            #[cfg(feature = "stats")]
+            let merges = buddy.coalesce_count();
+
+            thread::scope(|s| {
+                for page in [0, 1] {
+                    let (buddy, barrier) = (&buddy, &barrier);
+
+                    s.spawn(move || {
+                        barrier.wait();
+                        buddy.free(page * PAGE_SIZE, 1).unwrap();
+                    });
+                }
+            });
+
+            // The pair merged once, and only into their parent
+            #[cfg(feature = "stats")]
+            assert_eq!(buddy.coalesce_count() - merges, 1);
+            assert_eq!(buddy.view().free_pages(), 2);
+            assert_eq!(buddy.alloc(2), Some(0));
+
+            buddy.free(0, 2).unwrap();
+            assert_eq!(buddy.view().free_blocks(1), 1);
+            assert!(buddy.alloc(1).is_some() && buddy.alloc(1).is_some());
+
+            let states: Vec<_> = (0..).map_while(|i| buddy.container_state(i)).collect();
+            assert_eq!(states, full);
         }
+    }
 
-        assert!(!intersection(vec));
-        assert!(buddy.alloc(1).is_none());
+    // Page bitmap oracle: a block is free if all of its pages are, so splitting and merging
+    // need no bookkeeping at all
+    struct RefBuddy {
+        used: Vec<bool>,
+    }
+
+    impl RefBuddy {
+        fn new(pages: usize) -> Self {
+            Self {
+                used: vec![false; pages],
+            }
+        }
+
+        fn is_free(&self, first: usize, order: usize) -> bool {
+            self.used[first..first + (1 << order)].iter().all(|u| !u)
+        }
+
+        fn has_free(&self, order: usize) -> bool {
+            (0..self.used.len())
+                .step_by(1 << order)
+                .any(|first| self.is_free(first, order))
+        }
+
+        fn take(&mut self, first: usize, order: usize) {
+            assert!(first.is_multiple_of(1 << order) && self.is_free(first, order));
+            self.used[first..first + (1 << order)].fill(true);
+        }
+
+        fn release(&mut self, first: usize, order: usize) {
+            self.used[first..first + (1 << order)].fill(false);
+        }
     }
 
     #[test]
-    fn basic_free() {
-        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
-        let mut addrs = Vec::with_capacity(16);
+    fn validate() {
+        use error::InvariantError;
 
-        for _ in 0..5 {
-            addrs.push(buddy.alloc(2).unwrap());
+        struct Cpu0;
+
+        impl cpuid::Cpu for Cpu0 {
+            fn current_cpu() -> usize {
+                0
+            }
         }
 
-        for i in addrs {
-            buddy.free(i, 2);
+        // With scans starting at the thread id, some ids spread the small blocks over every
+        // quarter and leave no room for the 256 pages
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu0, _>::new(0, 1024, &Global).unwrap();
+        let mut addrs = Vec::new();
+
+        assert_eq!(buddy.validate(), Ok(()));
+
+        for pages in [1, 2, 1, 8, 64, 4, 1, 256, 2] {
+            addrs.push((buddy.alloc(pages).unwrap(), pages));
+            assert_eq!(buddy.validate(), Ok(()));
         }
 
-        for _ in 0..8 {
-            assert!(buddy.alloc(2).is_some())
+        for (addr, pages) in addrs.drain(..).step_by(2) {
+            buddy.free(addr, pages).unwrap();
+            assert_eq!(buddy.validate(), Ok(()));
+        }
+
+        // Locking the root of an empty container hides its pages from `alloc`
+        let index = (0..)
+            .map_while(|i| buddy.container_state(i))
+            .position(|s| s == 0)
+            .unwrap();
+
+        buddy.__set_container_state(index, 1);
+        assert_eq!(
+            buddy.validate(),
+            Err(InvariantError::ContainerState {
+                container: index,
+                expected: 0,
+                found: 1
+            })
+        );
+
+        buddy.__set_container_state(index, 0);
+        assert_eq!(buddy.validate(), Ok(()));
+    }
+
+    // Runs `steps` random allocations of orders up to `max_order` and frees against `RefBuddy`,
+    // checking free pages and `validate` after each. With `phase` allocations and frees take
+    // turns being the more likely ones every `phase` steps, so the arena fills up and drains.
+    fn differential_run(seed: u64, steps: usize, max_order: usize, phase: Option<usize>) {
+        struct Cpu0;
+
+        impl cpuid::Cpu for Cpu0 {
+            fn current_cpu() -> usize {
+                0
+            }
+        }
+
+        // xorshift64, failures report the seed and the step to reproduce them
+        let mut rng = seed;
+        let mut next = move || {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            rng as usize
+        };
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu0, _>::new(0, 256, &Global).unwrap();
+        let mut model = RefBuddy::new(256);
+        let mut live = Vec::new();
+
+        for step in 0..steps {
+            let draining = phase.is_some_and(|p| (step / p) % 2 == 1);
+
+            if live.is_empty() || (next() % 3 != 0) != draining {
+                let order = next() % (max_order + 1);
+
+                match buddy.alloc(1 << order) {
+                    Some(addr) => {
+                        model.take(addr / PAGE_SIZE, order);
+                        live.push((addr, order));
+                    }
+                    None => assert!(!model.has_free(order), "seed {seed:#x}, step {step}"),
+                }
+            } else {
+                let (addr, order) = live.swap_remove(next() % live.len());
+
+                buddy.free(addr, 1 << order).unwrap();
+                model.release(addr / PAGE_SIZE, order);
+            }
+
+            let free: Vec<_> = buddy.view().level_states(0).map(|(_, f)| f).collect();
+            let expected: Vec<_> = model.used.iter().map(|u| !u).collect();
+            assert_eq!(free, expected, "seed {seed:#x}, step {step}");
+            assert_eq!(buddy.validate(), Ok(()), "seed {seed:#x}, step {step}");
         }
     }
 
+    #[test]
+    fn differential() {
+        differential_run(0x9e37_79b9_7f4a_7c15, 5000, 5, None);
+    }
+
+    // BUDDY_STRESS_SEED picks another sequence, given in hex
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn differential_stress() {
+        let seed = std::env::var("BUDDY_STRESS_SEED")
+            .ok()
+            .and_then(|s| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok())
+            .unwrap_or(0x2545_f491_4f6c_dd1d);
+
+        // Validating after every step is slow without optimizations
+        let steps = if cfg!(debug_assertions) {
+            10_000
+        } else {
+            100_000
+        };
+
+        differential_run(seed, steps, 8, Some(1000));
+    }
+
     #[test]
     fn multi_threaded_alloc_same_size() {
         let buddy = Arc::new(BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1024, &Global).unwrap());
@@ -202,7 +3082,7 @@ mod test {
 
         assert!(buddy.alloc(1).is_none());
         assert!(!intersection(
-            Arc::try_unwrap(res_vec).unwrap().into_inner().unwrap()
+            &Arc::try_unwrap(res_vec).unwrap().into_inner().unwrap()
         ));
     }
 
@@ -239,10 +3119,128 @@ mod test {
         assert!(buddy.alloc(1024).is_some());
 
         assert!(!intersection(
-            Arc::try_unwrap(res_vec).unwrap().into_inner().unwrap()
+            &Arc::try_unwrap(res_vec).unwrap().into_inner().unwrap()
         ));
     }
 
+    #[test]
+    fn lazy_free() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let addr = buddy.alloc(16).unwrap();
+
+        buddy.free_lazy(addr, 16);
+        assert!(buddy.alloc(1).is_none());
+
+        buddy.flush();
+        assert_eq!(buddy.alloc(16), Some(addr));
+
+        // The queued block is the whole rounded one
+        buddy.free(addr, 16);
+        let addr = buddy.alloc(3).unwrap();
+
+        buddy.free_lazy(addr, 3);
+        buddy.flush();
+        assert_eq!(buddy.free_pages(), 16);
+        assert_eq!(buddy.validate(), Ok(()));
+
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let addrs: Vec<_> = (0..16).map(|_| buddy.alloc(1).unwrap()).collect();
+
+        // More frees than a single CPU can queue fall through to the tree
+        for i in &addrs {
+            buddy.free_lazy(*i, 1);
+        }
+
+        let mut reused: Vec<_> = (0..8).map(|_| buddy.alloc(1).unwrap()).collect();
+        assert!(buddy.alloc(1).is_none());
+
+        reused.sort();
+        let mut expected = addrs[8..].to_vec();
+        expected.sort();
+        assert_eq!(reused, expected);
+
+        buddy.flush();
+        assert_eq!((0..8).filter_map(|_| buddy.alloc(1)).count(), 8);
+        assert!(buddy.alloc(1).is_none());
+    }
+
+    #[test]
+    fn lazy_free_rejected() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(16 * PAGE_SIZE, 16, &Global).unwrap();
+        let addr = buddy.alloc(4).unwrap();
+
+        // Nothing outside the arena or unaligned is queued
+        assert_eq!(buddy.free_lazy(0, 1), None);
+        assert_eq!(buddy.free_lazy(32 * PAGE_SIZE, 1), None);
+        assert_eq!(buddy.free_lazy(28 * PAGE_SIZE, 8), None);
+        assert_eq!(buddy.free_lazy(addr + PAGE_SIZE, 4), None);
+        assert_eq!(buddy.free_lazy(addr, 32), None);
+
+        assert_eq!(buddy.free_lazy(addr, 4), Some(()));
+
+        if cfg!(debug_assertions) {
+            assert_eq!(buddy.free_lazy(addr, 4), None);
+        }
+
+        buddy.flush();
+        assert_eq!(buddy.validate(), Ok(()));
+        assert_eq!(buddy.alloc(16), Some(16 * PAGE_SIZE));
+    }
+
+    #[test]
+    fn free_after_lazy_free() {
+        let buddy = BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 16, &Global).unwrap();
+        let addr = buddy.alloc(4).unwrap();
+
+        buddy.free_lazy(addr, 4);
+
+        // A queued block is freed by the flush, not by the caller
+        if cfg!(debug_assertions) {
+            assert_eq!(buddy.free(addr, 4), None);
+            assert_eq!(buddy.recycle(addr, 4), None);
+        }
+
+        buddy.flush();
+        assert_eq!(buddy.validate(), Ok(()));
+        assert_eq!(buddy.alloc(16), Some(0));
+    }
+
+    #[test]
+    fn concurrent_dump() {
+        use std::{
+            format,
+            sync::atomic::{AtomicBool, Ordering},
+        };
+
+        let buddy = Arc::new(BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 1024, &Global).unwrap());
+        let done = Arc::new(AtomicBool::new(false));
+
+        let dumper = thread::spawn({
+            let buddy = buddy.clone();
+            let done = done.clone();
+            move || {
+                while !done.load(Ordering::Relaxed) {
+                    assert!(!format!("{:?}", buddy).is_empty());
+                    assert!(!format!("{}", buddy).is_empty());
+                    assert!(buddy.container_state(0).is_some());
+                }
+            }
+        });
+
+        for _ in 0..4 {
+            let addrs: Vec<_> = (0..256).map(|_| buddy.alloc(2).unwrap()).collect();
+
+            for i in addrs {
+                buddy.free(i, 2);
+            }
+        }
+
+        done.store(true, Ordering::Relaxed);
+        dumper.join().unwrap();
+
+        assert!(buddy.container_state(2048).is_none());
+    }
+
     #[test]
     fn buddy_alloc_test() {
         let buddy = Arc::new(BuddyAlloc::<PAGE_SIZE, Cpu, _>::new(0, 10 * 4096, &Global).unwrap());
@@ -263,3 +3261,185 @@ mod test {
         }
     }
 }
+
+// Model checks, run with `RUSTFLAGS="--cfg loom" cargo test --release --lib loom`. The crate
+// takes its atomics from loom then, which runs each model under every interleaving of them.
+#[cfg(all(test, loom))]
+mod loom_test {
+    use super::*;
+    use buddy_alloc::BuddyAlloc;
+    use loom::{sync::Arc, thread};
+    use std::alloc::Global;
+    use std::boxed::Box;
+
+    const PAGE_SIZE: usize = 1 << 12;
+
+    // Single pages are container roots, so frees of them coalesce across containers
+    const PAGES: usize = 1 << state::CONTAINER_LEVELS;
+
+    struct Cpu0;
+
+    impl cpuid::Cpu for Cpu0 {
+        fn current_cpu() -> usize {
+            0
+        }
+    }
+
+    type Buddy = BuddyAlloc<'static, PAGE_SIZE, Cpu0, Global>;
+
+    // Filling the arena before the threads start alone takes more than the default number
+    // of branches. `LOOM_MAX_PREEMPTIONS` overrides the bound.
+    fn model(f: impl Fn() + Sync + Send + 'static) {
+        let mut builder = loom::model::Builder::new();
+
+        builder.max_branches = 100_000;
+        builder.preemption_bound.get_or_insert(3);
+        builder.check(f);
+    }
+
+    #[test]
+    fn loom_buddy_free() {
+        model(|| {
+            let buddy = Arc::new(Buddy::new(0, PAGES, &Global).unwrap());
+
+            for _ in 0..PAGES {
+                buddy.alloc(1).unwrap();
+            }
+
+            let frees: std::vec::Vec<_> = [0, 1]
+                .into_iter()
+                .map(|page| {
+                    let buddy = buddy.clone();
+
+                    thread::spawn(move || buddy.free(page * PAGE_SIZE, 1).unwrap())
+                })
+                .collect();
+
+            for free in frees {
+                free.join().unwrap();
+            }
+
+            // The pair merged once, into a parent that is free and nothing else
+            assert_eq!(buddy.view().free_pages(), 2);
+            assert_eq!(buddy.validate(), Ok(()));
+            assert_eq!(buddy.alloc(1), Some(0));
+            assert_eq!(buddy.alloc(1), Some(PAGE_SIZE));
+            assert_eq!(buddy.alloc(1), None);
+        });
+    }
+
+    #[test]
+    fn loom_alloc_while_coalescing() {
+        model(|| {
+            let buddy = Arc::new(Buddy::new(0, PAGES, &Global).unwrap());
+
+            for _ in 0..PAGES {
+                buddy.alloc(1).unwrap();
+            }
+
+            buddy.free(PAGE_SIZE, 1).unwrap();
+
+            // Freeing page 0 merges it with page 1 into the block the other thread asks for
+            let free = {
+                let buddy = buddy.clone();
+
+                thread::spawn(move || buddy.free(0, 1).unwrap())
+            };
+            let alloc = {
+                let buddy = buddy.clone();
+
+                thread::spawn(move || buddy.alloc(2))
+            };
+
+            free.join().unwrap();
+
+            // The block is either taken or found free afterwards, never both or neither
+            match alloc.join().unwrap() {
+                Some(addr) => assert_eq!(addr, 0),
+                None => assert_eq!(buddy.alloc(2), Some(0)),
+            }
+
+            assert_eq!(buddy.view().free_pages(), 0);
+            assert_eq!(buddy.validate(), Ok(()));
+        });
+    }
+
+    #[test]
+    fn loom_slab_alloc_while_freeing() {
+        type Slab = slab::SlabOnBuddy<
+            'static,
+            'static,
+            { PAGE_SIZE / 2 },
+            PAGE_SIZE,
+            Cpu0,
+            Global,
+            oom::FailFast,
+        >;
+
+        model(|| {
+            let buddy: &'static Buddy = Box::leak(Box::new(Buddy::new(0, PAGES, &Global).unwrap()));
+            let slab = Arc::new(Slab::new(buddy).unwrap());
+
+            // Both objects of a page are taken, so it is off the list
+            let frees: std::vec::Vec<_> = [slab.alloc().unwrap(), slab.alloc().unwrap()]
+                .into_iter()
+                .map(|obj| {
+                    let slab = slab.clone();
+
+                    thread::spawn(move || slab.free(obj))
+                })
+                .collect();
+
+            let obj = slab.alloc().unwrap();
+
+            for free in frees {
+                free.join().unwrap();
+            }
+
+            // The object came from the page made room in or from a new one
+            assert!(matches!(slab.pages(), 1 | 2));
+
+            slab.free(obj);
+            slab.trim();
+            assert_eq!(slab.pages(), 0);
+
+            drop(slab);
+            assert_eq!(buddy.alloc(PAGES), Some(0));
+            assert_eq!(buddy.validate(), Ok(()));
+
+            drop(unsafe { Box::from_raw(buddy as *const Buddy as *mut Buddy) });
+        });
+    }
+
+    #[test]
+    fn loom_static_backend() {
+        static BACKEND: testing::StaticBackend<16384> = testing::StaticBackend::new();
+
+        model(|| {
+            // The allocator of the previous run is gone
+            unsafe { BACKEND.reset() };
+
+            let buddy =
+                Arc::new(BuddyAlloc::<PAGE_SIZE, Cpu0, _>::new(0, PAGES, &BACKEND).unwrap());
+            let threads: std::vec::Vec<_> = (0..2)
+                .map(|_| {
+                    let buddy = buddy.clone();
+
+                    thread::spawn(move || {
+                        let addr = buddy.alloc(1).unwrap();
+
+                        buddy.free(addr, 1).unwrap();
+                    })
+                })
+                .collect();
+
+            for thread in threads {
+                thread.join().unwrap();
+            }
+
+            // Pages sit in the containers below the root one and merged back into it
+            assert_eq!(buddy.alloc(PAGES), Some(0));
+            assert_eq!(buddy.validate(), Ok(()));
+        });
+    }
+}
@@ -0,0 +1,7 @@
+/// Maps pages to the NUMA node that backs them.
+///
+/// Used by [`crate::buddy_alloc::BuddyAlloc::alloc_on_node`].
+pub trait NumaMap {
+    /// Returns the node of the page at address `page_index * PAGE_SIZE`.
+    fn node_of(page_index: usize) -> u32;
+}
@@ -0,0 +1,24 @@
+/// What `alloc` does after a scan found no free block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OomAction {
+    /// Scan the tree again, e.g. after memory was reclaimed
+    Retry,
+    /// Give up and return `None`
+    Fail,
+}
+
+pub trait OomHandler {
+    /// Called when no block of `1 << order` pages is free.
+    fn on_oom(&self, order: usize) -> OomAction;
+}
+
+/// Fails every allocation that doesn't fit.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FailFast;
+
+impl OomHandler for FailFast {
+    #[inline]
+    fn on_oom(&self, _order: usize) -> OomAction {
+        OomAction::Fail
+    }
+}
@@ -0,0 +1,66 @@
+use crate::atomic::{AtomicUsize, Ordering};
+
+/// Number of updates kept by the log.
+pub const OPLOG_LEN: usize = 64;
+
+/// Path that made a logged update.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// Allocation, including the commit into upper containers
+    Alloc,
+    /// Free and coalescing
+    Free,
+}
+
+/// Successful update of a container state, recorded with the `oplog` feature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoggedOp {
+    pub op: Op,
+    /// Tree position of the root of the updated container
+    pub container_root: u32,
+    /// State before the update
+    pub old: usize,
+    /// State after the update
+    pub new: usize,
+}
+
+pub(crate) struct OpLog {
+    next: AtomicUsize,
+    entries: [[AtomicUsize; 3]; OPLOG_LEN],
+}
+
+impl OpLog {
+    pub const fn new() -> Self {
+        Self {
+            next: AtomicUsize::new(0),
+            entries: [const { [const { AtomicUsize::new(0) }; 3] }; OPLOG_LEN],
+        }
+    }
+
+    pub fn record(&self, op: Op, container_root: u32, old: usize, new: usize) {
+        let entry = &self.entries[self.next.fetch_add(1, Ordering::Relaxed) % OPLOG_LEN];
+
+        entry[0].store(
+            ((container_root as usize) << 1) | (op == Op::Free) as usize,
+            Ordering::Relaxed,
+        );
+        entry[1].store(old, Ordering::Relaxed);
+        entry[2].store(new, Ordering::Relaxed);
+    }
+
+    pub fn recent(&self) -> impl Iterator<Item = LoggedOp> + '_ {
+        let end = self.next.load(Ordering::Relaxed);
+
+        (end.saturating_sub(OPLOG_LEN)..end).map(|n| {
+            let entry = &self.entries[n % OPLOG_LEN];
+            let tag = entry[0].load(Ordering::Relaxed);
+
+            LoggedOp {
+                op: if tag & 1 == 0 { Op::Alloc } else { Op::Free },
+                container_root: (tag >> 1) as u32,
+                old: entry[1].load(Ordering::Relaxed),
+                new: entry[2].load(Ordering::Relaxed),
+            }
+        })
+    }
+}
@@ -0,0 +1,57 @@
+use core::alloc::Allocator;
+use core::ptr::NonNull;
+
+use crate::buddy_alloc::BuddyAlloc;
+use crate::cpuid::Cpu;
+use crate::oom::OomHandler;
+
+/// Block returned by [`BuddyAlloc::alloc_owned`], freed when dropped.
+pub struct OwnedPages<'b, 'a, const PAGE_SIZE: usize, C: Cpu, A: Allocator, H: OomHandler> {
+    buddy: &'b BuddyAlloc<'a, PAGE_SIZE, C, A, H>,
+    addr: usize,
+    pages: usize,
+}
+
+impl<'b, 'a, const PAGE_SIZE: usize, C: Cpu, A: Allocator, H: OomHandler>
+    OwnedPages<'b, 'a, PAGE_SIZE, C, A, H>
+{
+    pub(crate) fn new(
+        buddy: &'b BuddyAlloc<'a, PAGE_SIZE, C, A, H>,
+        addr: usize,
+        pages: usize,
+    ) -> Self {
+        Self { buddy, addr, pages }
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    /// Returns the block as a byte slice pointer, or `None` for a block at address 0.
+    ///
+    /// The pointer is only dereferenceable if the arena is mapped memory.
+    pub fn as_non_null(&self) -> Option<NonNull<[u8]>> {
+        NonNull::new(self.addr as *mut u8)
+            .map(|ptr| NonNull::slice_from_raw_parts(ptr, self.pages * PAGE_SIZE))
+    }
+
+    /// Gives up the ownership and returns the address, the block has to be freed manually.
+    pub fn into_raw(self) -> usize {
+        let addr = self.addr;
+
+        core::mem::forget(self);
+        addr
+    }
+}
+
+impl<const PAGE_SIZE: usize, C: Cpu, A: Allocator, H: OomHandler> Drop
+    for OwnedPages<'_, '_, PAGE_SIZE, C, A, H>
+{
+    fn drop(&mut self) {
+        self.buddy.free(self.addr, self.pages);
+    }
+}
@@ -0,0 +1,72 @@
+use core::alloc::Allocator;
+
+use crate::buddy_alloc::BuddyAlloc;
+use crate::cpuid::Cpu;
+use crate::oom::OomHandler;
+
+/// Block returned by [`BuddyAlloc::alloc_region`] to be split up by its owner, freed as a
+/// whole when dropped.
+///
+/// Pieces are carved from the start of the block on, each aligned to its own size like a
+/// buddy block. The region is owned, so carving doesn't touch the tree, but pieces can't be
+/// freed one by one.
+pub struct RegionHandle<'b, 'a, const PAGE_SIZE: usize, C: Cpu, A: Allocator, H: OomHandler> {
+    buddy: &'b BuddyAlloc<'a, PAGE_SIZE, C, A, H>,
+    addr: usize,
+    pages: usize,
+    // Pages carved so far, including alignment gaps
+    carved: usize,
+}
+
+impl<'b, 'a, const PAGE_SIZE: usize, C: Cpu, A: Allocator, H: OomHandler>
+    RegionHandle<'b, 'a, PAGE_SIZE, C, A, H>
+{
+    pub(crate) fn new(
+        buddy: &'b BuddyAlloc<'a, PAGE_SIZE, C, A, H>,
+        addr: usize,
+        pages: usize,
+    ) -> Self {
+        Self {
+            buddy,
+            addr,
+            pages,
+            carved: 0,
+        }
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    /// Returns the number of pages not carved yet.
+    pub fn remaining(&self) -> usize {
+        self.pages - self.carved
+    }
+
+    /// Takes the next `pages` pages, rounded up to a power of two, and returns their address.
+    ///
+    /// Pages skipped to align the piece are lost until the region is dropped.
+    pub fn carve(&mut self, pages: usize) -> Option<usize> {
+        let pages = pages.checked_next_power_of_two()?;
+        let first = self.carved.next_multiple_of(pages);
+
+        if first.checked_add(pages)? > self.pages {
+            return None;
+        }
+
+        self.carved = first + pages;
+        Some(self.addr + first * PAGE_SIZE)
+    }
+}
+
+impl<const PAGE_SIZE: usize, C: Cpu, A: Allocator, H: OomHandler> Drop
+    for RegionHandle<'_, '_, PAGE_SIZE, C, A, H>
+{
+    fn drop(&mut self) {
+        self.buddy.free(self.addr, self.pages);
+    }
+}
@@ -0,0 +1,333 @@
+use crate::atomic::{AtomicUsize, Ordering};
+use core::alloc::{Allocator, Layout};
+use core::ptr::NonNull;
+
+use crate::buddy_alloc::BuddyAlloc;
+use crate::cpuid::Cpu;
+use crate::oom::OomHandler;
+use crate::slots::Slots;
+
+const WORD_BITS: usize = usize::BITS as usize;
+
+// Pages with free objects kept in slots. Those that don't fit go into a bitmap, which is
+// only searched once the slots are empty.
+const PARTIAL_SLOTS: usize = 64;
+
+/// Fixed-size objects of `OBJ` bytes carved out of single pages of a [`BuddyAlloc`].
+///
+/// Objects aren't linked into a free-list, every page the slab owns has a bitmap of taken
+/// objects kept out of the arena instead, so the arena doesn't have to be mapped memory.
+/// Pages with free objects are put on a list `alloc` takes them from, and a page is taken from
+/// the buddy only when the list is empty.
+///
+/// A page holding a single object goes back to the buddy as soon as it is freed. Other pages
+/// emptied while listed stay on the list until `alloc` takes them off and gives them back, or
+/// until [`Self::trim`] is called.
+pub struct SlabOnBuddy<
+    'b,
+    'a,
+    const OBJ: usize,
+    const PAGE_SIZE: usize,
+    C: Cpu,
+    A: Allocator,
+    H: OomHandler,
+> {
+    buddy: &'b BuddyAlloc<'a, PAGE_SIZE, C, A, H>,
+    // A bitmap per arena page, followed by one bit per page telling which pages are owned
+    // and one bit per page telling which pages didn't fit into `partial`
+    words: NonNull<AtomicUsize>,
+    // Indices plus one of listed pages
+    partial: Slots<PARTIAL_SLOTS>,
+    // Number of pages in the bitmap of those that didn't fit
+    spilled: AtomicUsize,
+}
+
+impl<'b, 'a, const OBJ: usize, const PAGE_SIZE: usize, C: Cpu, A: Allocator, H: OomHandler>
+    SlabOnBuddy<'b, 'a, OBJ, PAGE_SIZE, C, A, H>
+{
+    /// Number of objects in a page.
+    pub const PER_PAGE: usize = PAGE_SIZE / OBJ;
+
+    // Bits past the last object of a page stay set, so a page is full once its bitmap is
+    // all ones. A full page is neither listed nor held by a thread, the free making room in
+    // it holds it afterwards. Any other page is one of the two.
+    const EMPTY: usize = if Self::PER_PAGE == WORD_BITS {
+        0
+    } else {
+        !0 << Self::PER_PAGE
+    };
+    const FULL: usize = !0;
+
+    const OBJ_OK: () = assert!(
+        OBJ != 0 && OBJ <= PAGE_SIZE && PAGE_SIZE / OBJ <= WORD_BITS,
+        "a page must hold between 1 and usize::BITS objects"
+    );
+
+    /// Creates an empty slab, its bitmaps are allocated from the buddy's backend.
+    pub fn new(buddy: &'b BuddyAlloc<'a, PAGE_SIZE, C, A, H>) -> Option<Self> {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::OBJ_OK;
+        let layout = Self::layout(buddy.pages())?;
+
+        let words = match buddy.backend().allocate(layout) {
+            Ok(ptr) if ptr.len() >= layout.size() => ptr.cast::<AtomicUsize>(),
+            Ok(ptr) => {
+                unsafe { buddy.backend().deallocate(ptr.cast(), layout) };
+                return None;
+            }
+            Err(_) => return None,
+        };
+
+        for i in 0..layout.size() / core::mem::size_of::<AtomicUsize>() {
+            unsafe { words.as_ptr().add(i).write(AtomicUsize::new(0)) };
+        }
+
+        Some(Self {
+            buddy,
+            words,
+            partial: Slots::new(),
+            spilled: AtomicUsize::new(0),
+        })
+    }
+
+    fn layout(pages: usize) -> Option<Layout> {
+        Layout::array::<AtomicUsize>(pages.checked_add(pages.div_ceil(WORD_BITS) * 2)?).ok()
+    }
+
+    #[inline]
+    fn words(&self) -> &[AtomicUsize] {
+        let pages = self.buddy.pages();
+
+        unsafe {
+            core::slice::from_raw_parts(self.words.as_ptr(), pages + pages.div_ceil(WORD_BITS) * 2)
+        }
+    }
+
+    #[inline]
+    fn owned(&self) -> &[AtomicUsize] {
+        let pages = self.buddy.pages();
+
+        &self.words()[pages..pages + pages.div_ceil(WORD_BITS)]
+    }
+
+    #[inline]
+    fn spill(&self) -> &[AtomicUsize] {
+        let pages = self.buddy.pages();
+
+        &self.words()[pages + pages.div_ceil(WORD_BITS)..]
+    }
+
+    #[inline]
+    fn set_owned(&self, page: usize, owned: bool) {
+        let word = &self.owned()[page / WORD_BITS];
+        let bit = 1 << (page % WORD_BITS);
+
+        if owned {
+            word.fetch_or(bit, Ordering::Relaxed);
+        } else {
+            word.fetch_and(!bit, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    fn is_owned(&self, page: usize) -> bool {
+        self.owned()[page / WORD_BITS].load(Ordering::Relaxed) & (1 << (page % WORD_BITS)) != 0
+    }
+
+    #[inline]
+    fn page_addr(&self, page: usize) -> usize {
+        self.buddy.start() + page * PAGE_SIZE
+    }
+
+    /// Allocates an object and returns its address.
+    ///
+    /// The object comes from a listed page, only if there is none a new page is taken from
+    /// the buddy. Listed pages found empty are given back on the way.
+    pub fn alloc(&self) -> Option<usize> {
+        // Taking a page off the list makes this thread the only one allocating from it
+        while let Some(page) = self.unlist() {
+            if self.words()[page].load(Ordering::Acquire) == Self::EMPTY {
+                self.give_back(page);
+            } else {
+                return Some(self.take_from(page));
+            }
+        }
+
+        let addr = self.buddy.alloc(1)?;
+        let page = (addr - self.buddy.start()) / PAGE_SIZE;
+
+        self.set_owned(page, true);
+        self.words()[page].store(Self::EMPTY | 1, Ordering::Release);
+
+        if Self::PER_PAGE != 1 {
+            self.list(page);
+        }
+
+        Some(addr)
+    }
+
+    // Takes an object of a page held by this thread. Frees only make room in it, so it has
+    // one left.
+    fn take_from(&self, page: usize) -> usize {
+        let word = &self.words()[page];
+        let mut cur = word.load(Ordering::Relaxed);
+
+        loop {
+            hot_assert!(cur != Self::FULL);
+
+            let bit = (!cur).trailing_zeros() as usize;
+
+            match word.compare_exchange_weak(
+                cur,
+                cur | 1 << bit,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    // A full page is let go by taking its last object
+                    if cur | 1 << bit != Self::FULL {
+                        self.list(page);
+                    }
+
+                    return self.page_addr(page) + bit * OBJ;
+                }
+                Err(val) => cur = val,
+            }
+        }
+    }
+
+    // Lets go of a page held by this thread that isn't full. Once it is listed another thread
+    // may take it, so it isn't looked at afterwards.
+    fn list(&self, page: usize) {
+        if self.partial.push(0..PARTIAL_SLOTS, page + 1) {
+            return;
+        }
+
+        self.spill()[page / WORD_BITS].fetch_or(1 << (page % WORD_BITS), Ordering::Release);
+        self.spilled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Takes a page off the list, this thread holds it afterwards
+    fn unlist(&self) -> Option<usize> {
+        if let Some(entry) = self.partial.pop(0..PARTIAL_SLOTS) {
+            return Some(entry - 1);
+        }
+
+        if self.spilled.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+
+        self.spill().iter().enumerate().find_map(|(i, word)| {
+            let mut cur = word.load(Ordering::Relaxed);
+
+            while cur != 0 {
+                let bit = 1 << cur.trailing_zeros();
+
+                cur = word.fetch_and(!bit, Ordering::Acquire);
+
+                if cur & bit != 0 {
+                    self.spilled.fetch_sub(1, Ordering::Relaxed);
+                    return Some(i * WORD_BITS + bit.trailing_zeros() as usize);
+                }
+            }
+
+            None
+        })
+    }
+
+    fn give_back(&self, page: usize) {
+        self.set_owned(page, false);
+        self.buddy.free(self.page_addr(page), 1);
+    }
+
+    /// Frees the object at `addr`, giving its page back to the buddy if that held a single
+    /// object.
+    pub fn free(&self, addr: usize) {
+        let offset = addr - self.buddy.start();
+        let (page, bit) = (offset / PAGE_SIZE, 1 << (offset % PAGE_SIZE / OBJ));
+        let old = self.words()[page].fetch_and(!bit, Ordering::AcqRel);
+
+        hot_assert!(old & bit != 0, "double free of a slab object");
+
+        // Nobody else holds a full page, so making room in it makes this thread hold it
+        if old != Self::FULL {
+            return;
+        }
+
+        if old & !bit == Self::EMPTY {
+            self.give_back(page);
+        } else {
+            self.list(page);
+        }
+    }
+
+    /// Gives the empty pages on the list back to the buddy.
+    ///
+    /// Pages emptied meanwhile by other threads may be missed.
+    pub fn trim(&self) {
+        // Pages listed again go into a slot at most the current one
+        for i in 0..PARTIAL_SLOTS {
+            if let Some(entry) = self.partial.take(i) {
+                self.settle(entry - 1);
+            }
+        }
+
+        for (i, word) in self.spill().iter().enumerate() {
+            let mut seen = word.load(Ordering::Relaxed);
+
+            while seen != 0 {
+                let bit = 1 << seen.trailing_zeros();
+
+                seen &= !bit;
+
+                if word.fetch_and(!bit, Ordering::Acquire) & bit != 0 {
+                    self.spilled.fetch_sub(1, Ordering::Relaxed);
+                    self.settle(i * WORD_BITS + bit.trailing_zeros() as usize);
+                }
+            }
+        }
+    }
+
+    // Gives back a page held by this thread if it's empty, lists it again otherwise
+    fn settle(&self, page: usize) {
+        if self.words()[page].load(Ordering::Acquire) == Self::EMPTY {
+            self.give_back(page);
+        } else {
+            self.list(page);
+        }
+    }
+
+    /// Returns the number of pages currently taken from the buddy.
+    pub fn pages(&self) -> usize {
+        self.owned()
+            .iter()
+            .map(|w| w.load(Ordering::Relaxed).count_ones() as usize)
+            .sum()
+    }
+}
+
+impl<const OBJ: usize, const PAGE_SIZE: usize, C: Cpu, A: Allocator, H: OomHandler> Drop
+    for SlabOnBuddy<'_, '_, OBJ, PAGE_SIZE, C, A, H>
+{
+    // Pages still holding objects go back to the buddy as well
+    fn drop(&mut self) {
+        for page in 0..self.buddy.pages() {
+            if self.is_owned(page) {
+                self.buddy.free(self.page_addr(page), 1);
+            }
+        }
+
+        if let Some(layout) = Self::layout(self.buddy.pages()) {
+            unsafe { self.buddy.backend().deallocate(self.words.cast(), layout) };
+        }
+    }
+}
+
+unsafe impl<const OBJ: usize, const PAGE_SIZE: usize, C: Cpu, A: Allocator, H: OomHandler + Sync>
+    Send for SlabOnBuddy<'_, '_, OBJ, PAGE_SIZE, C, A, H>
+{
+}
+unsafe impl<const OBJ: usize, const PAGE_SIZE: usize, C: Cpu, A: Allocator, H: OomHandler + Sync>
+    Sync for SlabOnBuddy<'_, '_, OBJ, PAGE_SIZE, C, A, H>
+{
+}
@@ -0,0 +1,50 @@
+use crate::atomic::{AtomicUsize, Ordering};
+
+/// Fixed array of non-zero words that can be pushed and taken concurrently.
+pub struct Slots<const N: usize> {
+    slots: [AtomicUsize; N],
+}
+
+impl<const N: usize> Slots<N> {
+    pub fn new() -> Self {
+        Self {
+            slots: core::array::from_fn(|_| AtomicUsize::new(0)),
+        }
+    }
+
+    /// Stores `val` into the first empty slot of `range`. Returns false if all of them are taken.
+    pub fn push(&self, range: core::ops::Range<usize>, val: usize) -> bool {
+        debug_assert!(val != 0);
+
+        self.slots[range].iter().any(|s| {
+            s.compare_exchange(0, val, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        })
+    }
+
+    /// Takes the value of the first non-empty slot of `range`.
+    pub fn pop(&self, range: core::ops::Range<usize>) -> Option<usize> {
+        range.into_iter().find_map(|i| self.take(i))
+    }
+
+    /// Empties the slot of `range` holding `val`. Returns false if there is none.
+    pub fn remove(&self, range: core::ops::Range<usize>, val: usize) -> bool {
+        self.slots[range].iter().any(|s| {
+            s.compare_exchange(val, 0, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        })
+    }
+
+    pub fn contains(&self, range: core::ops::Range<usize>, val: usize) -> bool {
+        self.slots[range]
+            .iter()
+            .any(|s| s.load(Ordering::Relaxed) == val)
+    }
+
+    pub fn take(&self, idx: usize) -> Option<usize> {
+        match self.slots[idx].swap(0, Ordering::AcqRel) {
+            0 => None,
+            val => Some(val),
+        }
+    }
+}
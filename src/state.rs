@@ -0,0 +1,264 @@
+// Each container packs the state of several tree levels into a single word. Nodes above the
+// container leaves take one "locked" bit each, container leaves take LEAF_BITS bits:
+//
+//   | OCCUPIED | COALESCE_LEFT | COALESCE_RIGHT | LEFT_OCCUPIED | RIGHT_OCCUPIED |
+//
+// With `no-coalesce-state` the coalescing bits are dropped, which leaves room for one more
+// level per container:
+//
+//   | OCCUPIED | LEFT_OCCUPIED | RIGHT_OCCUPIED |
+//
+// The number of levels is picked so that the whole state fits into usize.
+
+const RIGHT_OCCUPIED: usize = 0x1;
+const LEFT_OCCUPIED: usize = 0x2;
+
+#[cfg(not(feature = "no-coalesce-state"))]
+const COALESCE_RIGHT: usize = 0x4;
+#[cfg(not(feature = "no-coalesce-state"))]
+const COALESCE_LEFT: usize = 0x8;
+#[cfg(not(feature = "no-coalesce-state"))]
+const OCCUPIED: usize = 0x10;
+#[cfg(not(feature = "no-coalesce-state"))]
+const LEAF_MASK: usize = 0x1F;
+#[cfg(not(feature = "no-coalesce-state"))]
+const LEAF_BITS: usize = 5;
+
+#[cfg(feature = "no-coalesce-state")]
+const OCCUPIED: usize = 0x4;
+#[cfg(feature = "no-coalesce-state")]
+const LEAF_MASK: usize = 0x7;
+#[cfg(feature = "no-coalesce-state")]
+const LEAF_BITS: usize = 3;
+
+const LOCKED_LEAF: usize = OCCUPIED | LEFT_OCCUPIED | RIGHT_OCCUPIED;
+
+/// Number of tree levels covered by a single container.
+pub const CONTAINER_LEVELS: usize = if cfg!(feature = "narrow-state") || usize::BITS < 64 {
+    3
+} else {
+    4
+} + cfg!(feature = "no-coalesce-state") as usize;
+
+/// `container_pos` of the first container leaf.
+pub const FIRST_LEAF: u8 = 1 << (CONTAINER_LEVELS - 1);
+
+/// Number of bits used by the state of a single container.
+pub const STATE_BITS: usize = (FIRST_LEAF as usize - 1) + FIRST_LEAF as usize * LEAF_BITS;
+
+const _: () = assert!(STATE_BITS <= usize::BITS as usize);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct NodeState(pub usize);
+
+// Leaf fields start right after the lock bits and the last one ends at STATE_BITS
+const _: () = assert!(NodeState::leaf_offset(FIRST_LEAF) == FIRST_LEAF as usize - 1);
+const _: () = assert!(NodeState::leaf_offset(FIRST_LEAF * 2 - 1) + LEAF_BITS == STATE_BITS);
+
+impl NodeState {
+    // Positions past the last container leaf would shift the masks out of the word
+    #[inline]
+    #[cfg_attr(feature = "unchecked", allow(unused_variables))]
+    fn check_pos(pos: u8) {
+        hot_assert!(
+            pos != 0 && pos < FIRST_LEAF * 2,
+            "container position {pos} out of range"
+        );
+    }
+
+    #[inline]
+    const fn leaf_offset(pos: u8) -> usize {
+        let offset = (FIRST_LEAF as usize - 1) + LEAF_BITS * (pos - FIRST_LEAF) as usize;
+
+        // A wider leaf field or more levels would silently shift bits out of the word
+        hot_assert!(
+            offset + LEAF_BITS <= usize::BITS as usize,
+            "leaf state shifted out of the word"
+        );
+        offset
+    }
+
+    #[inline]
+    fn set_leaf(self, bits: usize, pos: u8) -> Self {
+        Self::check_pos(pos);
+
+        Self(self.0 | (bits << Self::leaf_offset(pos)))
+    }
+
+    #[inline]
+    fn clear_leaf(self, bits: usize, pos: u8) -> Self {
+        Self::check_pos(pos);
+
+        Self(self.0 & !(bits << Self::leaf_offset(pos)))
+    }
+
+    // A container leaf with a coalescing bit set is not allocable: a free below it is
+    // merging into it and only that free may release it. `alloc` skips such a leaf and
+    // may take a child of it through `check_parent`, which clears the bit.
+    #[inline]
+    pub fn is_allocable(self, pos: u8) -> bool {
+        Self::check_pos(pos);
+
+        if pos < FIRST_LEAF {
+            (self.0 & (0x1 << (pos - 1))) == 0
+        } else {
+            self.0 & (LEAF_MASK << Self::leaf_offset(pos)) == 0
+        }
+    }
+
+    // Only an allocated node is occupied. `occupy_left` and `occupy_rigth` mark a child of
+    // a container leaf with separate bits, which keeps the leaf itself unallocable but not
+    // occupied.
+    #[inline]
+    pub fn is_occupied(self, pos: u8) -> bool {
+        Self::check_pos(pos);
+
+        if pos < FIRST_LEAF {
+            (self.0 & (0x1 << (pos - 1))) != 0
+        } else {
+            self.0 & (OCCUPIED << Self::leaf_offset(pos)) != 0
+        }
+    }
+
+    #[inline]
+    pub fn lock_not_leaf(self, pos: u8) -> Self {
+        Self::check_pos(pos);
+
+        Self(self.0 | (0x1 << (pos as usize - 1)))
+    }
+
+    #[inline]
+    pub fn lock_leaf(self, pos: u8) -> Self {
+        self.set_leaf(LOCKED_LEAF, pos)
+    }
+
+    #[inline]
+    pub fn unlock_not_leaf(self, pos: u8) -> Self {
+        Self::check_pos(pos);
+
+        Self(self.0 & !(0x1 << (pos as usize - 1)))
+    }
+
+    #[inline]
+    pub fn unlock_leaf(self, pos: u8) -> Self {
+        self.clear_leaf(LOCKED_LEAF, pos)
+    }
+
+    #[cfg(not(feature = "no-coalesce-state"))]
+    #[inline]
+    pub fn clean_left_coalesce(self, pos: u8) -> Self {
+        self.clear_leaf(COALESCE_LEFT, pos)
+    }
+
+    #[cfg(not(feature = "no-coalesce-state"))]
+    #[inline]
+    pub fn clean_rigth_coalesce(self, pos: u8) -> Self {
+        self.clear_leaf(COALESCE_RIGHT, pos)
+    }
+
+    #[cfg(not(feature = "no-coalesce-state"))]
+    #[inline]
+    pub fn left_coalesce(self, pos: u8) -> Self {
+        self.set_leaf(COALESCE_LEFT, pos)
+    }
+
+    #[cfg(not(feature = "no-coalesce-state"))]
+    #[inline]
+    pub fn rigth_coalesce(self, pos: u8) -> Self {
+        self.set_leaf(COALESCE_RIGHT, pos)
+    }
+
+    #[inline]
+    pub fn occupy_left(self, pos: u8) -> Self {
+        self.set_leaf(LEFT_OCCUPIED, pos)
+    }
+
+    #[inline]
+    pub fn occupy_rigth(self, pos: u8) -> Self {
+        self.set_leaf(RIGHT_OCCUPIED, pos)
+    }
+
+    #[cfg(not(feature = "no-coalesce-state"))]
+    #[inline]
+    pub fn is_left_coalescing(self, pos: u8) -> bool {
+        self == self.left_coalesce(pos)
+    }
+
+    #[cfg(not(feature = "no-coalesce-state"))]
+    #[inline]
+    pub fn is_right_coalescing(self, pos: u8) -> bool {
+        self == self.rigth_coalesce(pos)
+    }
+
+    #[inline]
+    pub fn clean_left(self, pos: u8) -> Self {
+        self.clear_leaf(LEFT_OCCUPIED, pos)
+    }
+
+    #[inline]
+    pub fn clean_rigth(self, pos: u8) -> Self {
+        self.clear_leaf(RIGHT_OCCUPIED, pos)
+    }
+
+    #[inline]
+    pub fn is_occupied_rigth(self, pos: u8) -> bool {
+        self == self.occupy_rigth(pos)
+    }
+
+    #[inline]
+    pub fn is_occupied_left(self, pos: u8) -> bool {
+        self == self.occupy_left(pos)
+    }
+
+    // Only container leaves have coalescing bits, so nodes above them never coalesce
+    #[inline]
+    #[cfg_attr(feature = "no-coalesce-state", allow(unused_variables))]
+    pub fn is_coalescing(self, pos: u8) -> bool {
+        #[cfg(not(feature = "no-coalesce-state"))]
+        return pos >= FIRST_LEAF
+            && (self.is_left_coalescing(pos) || self.is_right_coalescing(pos));
+
+        #[cfg(feature = "no-coalesce-state")]
+        return false;
+    }
+
+    // Whether a free emptying the left child of `pos` still has to merge into it. Without
+    // the coalescing mark the free only knows the child is in use from below and `pos`
+    // isn't allocated as a whole.
+    #[inline]
+    pub fn is_left_merging(self, pos: u8) -> bool {
+        #[cfg(not(feature = "no-coalesce-state"))]
+        return self.is_left_coalescing(pos);
+
+        #[cfg(feature = "no-coalesce-state")]
+        return self.is_occupied_left(pos) && !self.is_occupied(pos);
+    }
+
+    #[inline]
+    pub fn is_right_merging(self, pos: u8) -> bool {
+        #[cfg(not(feature = "no-coalesce-state"))]
+        return self.is_right_coalescing(pos);
+
+        #[cfg(feature = "no-coalesce-state")]
+        return self.is_occupied_rigth(pos) && !self.is_occupied(pos);
+    }
+
+    // Frees the left child of `pos`, clearing its mark if there is one
+    #[inline]
+    pub fn merge_left(self, pos: u8) -> Self {
+        #[cfg(not(feature = "no-coalesce-state"))]
+        return self.clean_left_coalesce(pos).clean_left(pos);
+
+        #[cfg(feature = "no-coalesce-state")]
+        return self.clean_left(pos);
+    }
+
+    #[inline]
+    pub fn merge_right(self, pos: u8) -> Self {
+        #[cfg(not(feature = "no-coalesce-state"))]
+        return self.clean_rigth_coalesce(pos).clean_rigth(pos);
+
+        #[cfg(feature = "no-coalesce-state")]
+        return self.clean_rigth(pos);
+    }
+}
@@ -0,0 +1,111 @@
+use crate::atomic::{AtomicUsize, Ordering};
+
+/// Snapshot of the counters collected with the `stats` feature.
+///
+/// There is no clock in `no_std`, so the cost of an operation is reported as the number of
+/// compare-and-swap attempts it took.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BuddyStats {
+    /// Successful allocations
+    pub allocs: usize,
+    /// Calls to `free`
+    pub frees: usize,
+    /// Compare-and-swap attempts on container states
+    pub cas: usize,
+    /// Failed compare-and-swap attempts on the free path
+    pub free_retries: usize,
+    /// Merges of two free buddies into a free parent
+    pub coalesces: usize,
+    /// Bytes lost to rounding `alloc_tracked` requests up to whole blocks
+    pub internal_fragmentation_bytes: usize,
+    /// Allocations given up after hitting the attempt limit
+    pub no_progress: usize,
+    /// Scans that reached the end of the arena and continued from its start
+    pub scan_restarts: usize,
+}
+
+impl BuddyStats {
+    /// Returns the counts accumulated since `prev` was taken, tolerating counter wraparound.
+    pub fn since(&self, prev: &BuddyStats) -> BuddyStats {
+        BuddyStats {
+            allocs: self.allocs.wrapping_sub(prev.allocs),
+            frees: self.frees.wrapping_sub(prev.frees),
+            cas: self.cas.wrapping_sub(prev.cas),
+            free_retries: self.free_retries.wrapping_sub(prev.free_retries),
+            coalesces: self.coalesces.wrapping_sub(prev.coalesces),
+            internal_fragmentation_bytes: self
+                .internal_fragmentation_bytes
+                .wrapping_sub(prev.internal_fragmentation_bytes),
+            no_progress: self.no_progress.wrapping_sub(prev.no_progress),
+            scan_restarts: self.scan_restarts.wrapping_sub(prev.scan_restarts),
+        }
+    }
+}
+
+pub(crate) struct Stats {
+    pub allocs: AtomicUsize,
+    pub frees: AtomicUsize,
+    pub cas: AtomicUsize,
+    pub free_retries: AtomicUsize,
+    pub coalesces: AtomicUsize,
+    pub internal_fragmentation_bytes: AtomicUsize,
+    pub no_progress: AtomicUsize,
+    pub scan_restarts: AtomicUsize,
+    attempts: [AtomicUsize; 8],
+}
+
+impl Stats {
+    pub const fn new() -> Self {
+        Self {
+            allocs: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+            cas: AtomicUsize::new(0),
+            free_retries: AtomicUsize::new(0),
+            coalesces: AtomicUsize::new(0),
+            internal_fragmentation_bytes: AtomicUsize::new(0),
+            no_progress: AtomicUsize::new(0),
+            scan_restarts: AtomicUsize::new(0),
+            attempts: [const { AtomicUsize::new(0) }; 8],
+        }
+    }
+
+    pub fn reset(&self) {
+        self.allocs.store(0, Ordering::Relaxed);
+        self.frees.store(0, Ordering::Relaxed);
+        self.cas.store(0, Ordering::Relaxed);
+        self.free_retries.store(0, Ordering::Relaxed);
+        self.coalesces.store(0, Ordering::Relaxed);
+        self.internal_fragmentation_bytes
+            .store(0, Ordering::Relaxed);
+        self.no_progress.store(0, Ordering::Relaxed);
+        self.scan_restarts.store(0, Ordering::Relaxed);
+
+        for bucket in &self.attempts {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+
+    // Bucket `i` counts allocations that took `2^i..2^(i+1)` updates, none counts as one
+    pub fn record_attempts(&self, attempts: usize) {
+        let bucket = (attempts.max(1).ilog2() as usize).min(self.attempts.len() - 1);
+
+        self.attempts[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn histogram(&self) -> [usize; 8] {
+        self.attempts.each_ref().map(|b| b.load(Ordering::Relaxed))
+    }
+
+    pub fn snapshot(&self) -> BuddyStats {
+        BuddyStats {
+            allocs: self.allocs.load(Ordering::Relaxed),
+            frees: self.frees.load(Ordering::Relaxed),
+            cas: self.cas.load(Ordering::Relaxed),
+            free_retries: self.free_retries.load(Ordering::Relaxed),
+            coalesces: self.coalesces.load(Ordering::Relaxed),
+            internal_fragmentation_bytes: self.internal_fragmentation_bytes.load(Ordering::Relaxed),
+            no_progress: self.no_progress.load(Ordering::Relaxed),
+            scan_restarts: self.scan_restarts.load(Ordering::Relaxed),
+        }
+    }
+}
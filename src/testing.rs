@@ -0,0 +1,140 @@
+//! Helpers for checking allocation results in tests.
+
+#[cfg(not(all(test, loom)))]
+use crate::atomic::{AtomicUsize, Ordering};
+use core::alloc::{AllocError, Allocator, Layout};
+use core::cell::UnsafeCell;
+use core::ptr::NonNull;
+// Loom models don't look into the backend, which then still fits into a `static`
+#[cfg(all(test, loom))]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A range of `size` bytes starting at `start`.
+///
+/// Two regions compare equal when they overlap, so a region is "found" in a set of
+/// allocations by any allocation sharing at least one byte with it. This isn't an
+/// equivalence relation and is only meant for overlap checks.
+#[derive(Eq, Clone, Copy, Debug)]
+pub struct MemRegion {
+    pub start: usize,
+    pub size: usize,
+}
+
+impl MemRegion {
+    pub fn new(start: usize, size: usize) -> Self {
+        Self { start, size }
+    }
+}
+
+// Empty regions never overlap anything, whichever side they are on
+impl PartialEq for MemRegion {
+    fn eq(&self, other: &Self) -> bool {
+        self.start < other.start + other.size && other.start < self.start + self.size
+    }
+}
+
+/// Returns whether any two of `regions` overlap.
+///
+/// ```
+/// #![feature(allocator_api)]
+/// use lock_free_buddy_allocator::{buddy_alloc::BuddyAlloc, cpuid::Cpu};
+/// use lock_free_buddy_allocator::testing::{intersection, MemRegion};
+///
+/// struct Cpu0;
+///
+/// impl Cpu for Cpu0 {
+///     fn current_cpu() -> usize {
+///         0
+///     }
+/// }
+///
+/// let buddy = BuddyAlloc::<4096, Cpu0, _>::new(0, 16, &std::alloc::Global).unwrap();
+/// let mut regions = Vec::new();
+///
+/// for pages in [1, 2, 4, 8] {
+///     regions.push(MemRegion::new(buddy.alloc(pages).unwrap(), pages * 4096));
+/// }
+///
+/// assert!(!intersection(&regions));
+///
+/// regions.push(MemRegion::new(regions[2].start + 4096, 4096));
+/// assert!(intersection(&regions));
+/// ```
+pub fn intersection(regions: &[MemRegion]) -> bool {
+    regions
+        .iter()
+        .enumerate()
+        .any(|(i, a)| regions[i + 1..].iter().any(|b| a == b))
+}
+
+/// Metadata backend carving allocations out of `N` bytes of its own, so that it can sit in
+/// a `static` and back an allocator in tests running without a heap.
+///
+/// Allocations only move a bump pointer and deallocations do nothing, so every allocator
+/// built on it uses up some of the `N` bytes for good until [`Self::reset`].
+#[repr(C, align(64))]
+pub struct StaticBackend<const N: usize> {
+    bytes: UnsafeCell<[u8; N]>,
+    used: AtomicUsize,
+}
+
+unsafe impl<const N: usize> Sync for StaticBackend<N> {}
+
+impl<const N: usize> StaticBackend<N> {
+    pub const fn new() -> Self {
+        Self {
+            bytes: UnsafeCell::new([0; N]),
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of bytes handed out, including padding for alignment.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Makes the whole buffer available again.
+    ///
+    /// # Safety
+    ///
+    /// Nothing allocated from the backend may be used afterwards.
+    pub unsafe fn reset(&self) {
+        self.used.store(0, Ordering::Relaxed);
+    }
+}
+
+impl<const N: usize> Default for StaticBackend<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl<const N: usize> Allocator for StaticBackend<N> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let base = self.bytes.get() as *mut u8;
+        let mut used = self.used.load(Ordering::Relaxed);
+
+        loop {
+            let start = (base as usize + used).next_multiple_of(layout.align()) - base as usize;
+            let end = start.checked_add(layout.size()).filter(|end| *end <= N);
+
+            let Some(end) = end else {
+                return Err(AllocError);
+            };
+
+            match self
+                .used
+                .compare_exchange_weak(used, end, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => {
+                    let ptr = unsafe { NonNull::new_unchecked(base.add(start)) };
+
+                    return Ok(NonNull::slice_from_raw_parts(ptr, layout.size()));
+                }
+                Err(val) => used = val,
+            }
+        }
+    }
+
+    unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) {}
+}
@@ -1,226 +1,673 @@
+use crate::atomic::{AtomicU8, AtomicUsize, Ordering};
 use core::alloc::{Allocator, Layout};
 use core::mem::{align_of, size_of};
-use core::sync::atomic::AtomicUsize;
+use core::ptr::NonNull;
 
-pub struct NodeContainer<'a> {
+use crate::state::{NodeState, CONTAINER_LEVELS, FIRST_LEAF};
+
+// Set in a page order on top of the order plus one, which never reaches it
+const RESERVED: u8 = 0x80;
+
+// A container is just its state, so containers start out as zeroed memory
+pub struct NodeContainer {
     pub nodes: AtomicUsize,
-    pub node: &'a Node<'a>,
 }
 
-pub struct Node<'a> {
+// Nodes refer to each other and to their containers by index only, so the metadata holds
+// no pointers into itself and stays valid when copied to another address. Every field
+// follows from `pos`, with `lazy-init` nodes are computed on each access instead of being
+// kept in an array.
+#[derive(Clone, Copy, Debug)]
+pub struct Node {
     pub start: usize,
     pub size: usize,
     pub pos: u32,
     pub container_pos: u8,
-    pub container: &'a NodeContainer<'a>,
+    pub container: u32,
 }
 
 pub struct Tree<'a, const PAGE_SIZE: usize, A: Allocator> {
-    tree: &'a mut [Node<'a>],
-    container: &'a mut [NodeContainer<'a>],
+    tree: NonNull<Node>,
+    container: NonNull<NodeContainer>,
+    num_containers: usize,
+    dirty: NonNull<AtomicUsize>,
+    orders: NonNull<AtomicU8>,
     height: usize,
     num_nodes: usize,
+    // Leaves are blocks of `1 << leaf_order` pages, page indices are shifted down by it
+    leaf_order: usize,
     backend: &'a A,
 }
 
-impl<'a> PartialEq for Node<'a> {
+impl NodeContainer {
+    #[inline]
+    pub fn get_state(&self) -> usize {
+        self.nodes.load(Ordering::Relaxed)
+    }
+}
+
+impl PartialEq for Node {
     fn eq(&self, other: &Self) -> bool {
         self.pos == other.pos
     }
 }
 
 impl<'a, const PAGE_SIZE: usize, A: Allocator> Tree<'a, PAGE_SIZE, A> {
-    fn allocate_space(pages: usize, backend: &A) -> Option<(&mut [Node], &mut [NodeContainer])> {
+    const fn container_count(pages: usize) -> usize {
+        let height = pages.ilog2() as usize + 1;
+        let mut level = 1;
+        let mut count = 0;
+
+        // Every container is rooted at a level congruent to 1 mod CONTAINER_LEVELS
+        while level <= height {
+            count += 1 << (level - 1);
+            level += CONTAINER_LEVELS;
+        }
+
+        count
+    }
+
+    const fn dirty_words(pages: usize) -> usize {
+        pages.div_ceil(usize::BITS as usize)
+    }
+
+    const fn node_bytes(pages: usize) -> usize {
+        if cfg!(feature = "lazy-init") {
+            0
+        } else {
+            pages * 2 * size_of::<Node>()
+        }
+    }
+
+    /// Bytes of metadata [`Self::new`] allocates for the same arguments.
+    pub const fn metadata_bytes(pages: usize, leaf_order: usize) -> usize {
+        let num_leaves = pages.next_power_of_two() >> leaf_order;
+
+        Self::node_bytes(num_leaves)
+            + Self::container_count(num_leaves) * size_of::<NodeContainer>()
+            + Self::dirty_words(num_leaves) * size_of::<AtomicUsize>()
+            + num_leaves * size_of::<AtomicU8>()
+    }
+
+    /// Alignments of the node array and the containers, the other regions need no more
+    /// than the containers.
+    pub const fn metadata_alignment() -> (usize, usize) {
+        (align_of::<Node>(), align_of::<NodeContainer>())
+    }
+
+    // Layouts of nodes, containers, the dirty bitmap and page orders
+    fn layouts(pages: usize) -> Option<[Layout; 4]> {
         let num_pages = pages.next_power_of_two();
-        let nodes_count = num_pages * 2 - 1;
 
         let tree_layout =
-            Layout::from_size_align((nodes_count + 1) * size_of::<Node>(), align_of::<Node>())
+            Layout::from_size_align(Self::node_bytes(num_pages), Self::metadata_alignment().0)
                 .ok()?;
 
         let con_layout = Layout::from_size_align(
-            (nodes_count - 1) * size_of::<NodeContainer>(),
-            align_of::<NodeContainer>(),
+            Self::container_count(num_pages) * size_of::<NodeContainer>(),
+            Self::metadata_alignment().1,
         )
         .ok()?;
 
-        let tree = backend.allocate_zeroed(tree_layout).ok()?;
-
-        let tree = unsafe {
-            core::slice::from_raw_parts_mut(
-                tree.as_ptr().as_mut_ptr() as *mut Node,
-                nodes_count + 1,
-            )
-        };
-
-        let container = backend.allocate_zeroed(con_layout).ok()?;
+        let dirty_layout = Layout::array::<AtomicUsize>(Self::dirty_words(num_pages)).ok()?;
+        let orders_layout = Layout::array::<AtomicU8>(num_pages).ok()?;
 
-        let container = unsafe {
-            core::slice::from_raw_parts_mut(
-                container.as_ptr().as_mut_ptr() as *mut NodeContainer,
-                nodes_count - 1,
-            )
-        };
-
-        Some((tree, container))
+        Some([tree_layout, con_layout, dirty_layout, orders_layout])
     }
 
-    unsafe fn init_tree(
-        tree: *mut Node<'a>,
-        nodes: *mut NodeContainer<'a>,
-        size: usize,
-        num_pages: usize,
-        height: usize,
-    ) {
-        let mut container_num = 0;
-        let root = tree.offset(1).as_mut().unwrap();
-
-        root.start = 0;
-        root.size = size;
-        root.pos = 1;
-        root.container_pos = 1;
-
-        let mut node = nodes.offset(container_num).as_mut().unwrap();
+    // Everything but the nodes starts zeroed. An empty region isn't allocated at all and
+    // stays dangling.
+    fn allocate_space(pages: usize, backend: &A) -> Option<[NonNull<u8>; 4]> {
+        let layouts = Self::layouts(pages)?;
+        let mut space = [NonNull::dangling(); 4];
 
-        node.node = tree.offset(1).as_ref().unwrap();
-        root.container = nodes.offset(container_num).as_ref().unwrap();
-
-        container_num += 1;
+        for (i, layout) in layouts.iter().enumerate() {
+            if layout.size() == 0 {
+                continue;
+            }
 
-        for i in 2..num_pages * 2 {
-            let node = tree.offset(i as isize).as_mut().unwrap();
-            let parent = tree.offset(i as isize / 2).as_ref().unwrap();
+            let res = if i == 0 {
+                backend.allocate(*layout)
+            } else {
+                backend.allocate_zeroed(*layout)
+            };
+
+            // A block shorter than requested would let metadata accesses run past its end
+            match res {
+                Ok(ptr) if ptr.len() >= layout.size() => space[i] = ptr.cast(),
+                res => {
+                    if let Ok(ptr) = res {
+                        unsafe { backend.deallocate(ptr.cast(), *layout) };
+                    }
+
+                    Self::deallocate_space(&space[..i], &layouts, backend);
+                    return None;
+                }
+            }
+        }
 
-            node.pos = i as u32;
-            node.size = parent.size / 2;
+        Some(space)
+    }
 
-            if (height - (node.size / PAGE_SIZE).ilog2() as usize) % 4 == 1 {
-                let mut n = nodes.offset(container_num).as_mut().unwrap();
+    // Loom atomics carry more than their value, so zeroed memory doesn't make them
+    #[cfg(all(test, loom))]
+    unsafe fn init_atomics(space: &[NonNull<u8>; 4], pages: usize) {
+        for i in 0..Self::container_count(pages) {
+            space[1]
+                .cast::<NodeContainer>()
+                .as_ptr()
+                .add(i)
+                .write(NodeContainer {
+                    nodes: AtomicUsize::new(0),
+                });
+        }
 
-                n.node = node;
+        for i in 0..Self::dirty_words(pages) {
+            space[2]
+                .cast::<AtomicUsize>()
+                .as_ptr()
+                .add(i)
+                .write(AtomicUsize::new(0));
+        }
 
-                tree.offset(i as isize).as_mut().unwrap().container =
-                    nodes.offset(container_num).as_ref().unwrap();
-                container_num += 1;
+        for i in 0..pages {
+            space[3]
+                .cast::<AtomicU8>()
+                .as_ptr()
+                .add(i)
+                .write(AtomicU8::new(0));
+        }
+    }
 
-                tree.offset(i as isize).as_mut().unwrap().container_pos = 1;
-            } else {
-                node.container = parent.container;
+    fn deallocate_space(space: &[NonNull<u8>], layouts: &[Layout; 4], backend: &A) {
+        for (ptr, layout) in space.iter().zip(layouts) {
+            if layout.size() != 0 {
+                unsafe { backend.deallocate(*ptr, *layout) };
+            }
+        }
+    }
 
-                if parent.pos * 2 == i as u32 {
-                    node.container_pos = parent.container_pos * 2;
+    #[cfg(not(feature = "lazy-init"))]
+    unsafe fn init_tree(
+        tree: *mut Node,
+        containers: usize,
+        size: usize,
+        num_leaves: usize,
+        height: usize,
+        leaf_order: usize,
+    ) {
+        tree.write(Node {
+            start: 0,
+            size: 0,
+            pos: 0,
+            container_pos: 0,
+            container: 0,
+        });
+
+        tree.add(1).write(Node {
+            start: 0,
+            size,
+            pos: 1,
+            container_pos: 1,
+            container: 0,
+        });
+
+        let mut container_num = 1;
+
+        for i in 2..num_leaves * 2 {
+            let parent = &*tree.add(i / 2);
+            let size = parent.size / 2;
+
+            let (container, container_pos) =
+                if (height + leaf_order - (size / PAGE_SIZE).ilog2() as usize) % CONTAINER_LEVELS
+                    == 1
+                {
+                    container_num += 1;
+                    (container_num as u32 - 1, 1)
+                } else if parent.pos * 2 == i as u32 {
+                    (parent.container, parent.container_pos * 2)
                 } else {
-                    node.container_pos = (parent.container_pos * 2) + 1;
-                }
-            }
+                    (parent.container, (parent.container_pos * 2) + 1)
+                };
 
-            if parent.pos as usize * 2 == i {
-                tree.offset(i as isize).as_mut().unwrap().start = parent.start;
+            let start = if parent.pos as usize * 2 == i {
+                parent.start
             } else {
-                tree.offset(i as isize).as_mut().unwrap().start = parent.start + node.size;
-            }
+                parent.start + size
+            };
+
+            tree.add(i).write(Node {
+                start,
+                size,
+                pos: i as u32,
+                container_pos,
+                container,
+            });
         }
 
-        for i in 1..num_pages * 2 {
-            assert!(tree.offset(i as isize).as_mut().unwrap().container_pos != 0);
-            assert!(tree.offset(i as isize).as_mut().unwrap().pos != 0);
+        debug_assert_eq!(container_num, containers);
 
-            // println!(
-            //     "Node: pos {} offset {} level {}, cont_pos {}",
-            //     tree.offset(i as isize).as_mut().unwrap().pos,
-            //     tree.offset(i as isize).as_mut().unwrap().start,
-            //     height
-            //         - (tree.offset(i as isize).as_mut().unwrap().size / PAGE_SIZE).ilog2() as usize,
-            //     tree.offset(i as isize).as_mut().unwrap().container_pos
-            // );
+        for i in 1..num_leaves * 2 {
+            assert!((*tree.add(i)).container_pos != 0);
+            assert!((*tree.add(i)).pos != 0);
         }
     }
 
-    pub fn new(pages: usize, backend: &'a A) -> Option<Self> {
-        let heigth = pages.ilog2() as usize + 1;
-        let (tree, nodes) = Self::allocate_space(pages, backend)?;
+    /// Creates the tree for `pages` pages split down to blocks of `1 << leaf_order` pages,
+    /// which has `pages >> leaf_order` leaves.
+    pub fn new(pages: usize, leaf_order: usize, backend: &'a A) -> Option<Self> {
+        let leaves = pages >> leaf_order;
+        let heigth = leaves.ilog2() as usize + 1;
+        let space = Self::allocate_space(leaves, backend)?;
+        let [tree, nodes, dirty, orders] = space;
+
+        #[cfg(all(test, loom))]
+        unsafe {
+            Self::init_atomics(&space, leaves)
+        };
 
+        #[cfg(not(feature = "lazy-init"))]
         unsafe {
             Self::init_tree(
-                tree.as_mut_ptr(),
-                nodes.as_mut_ptr(),
+                tree.cast().as_ptr(),
+                Self::container_count(leaves),
                 pages * PAGE_SIZE,
-                pages,
+                leaves,
                 heigth,
+                leaf_order,
             );
         }
 
         Some(Self {
-            tree: tree,
-            container: nodes,
+            tree: tree.cast(),
+            container: nodes.cast(),
+            num_containers: Self::container_count(leaves),
+            dirty: dirty.cast(),
+            orders: orders.cast(),
             height: heigth,
-            num_nodes: pages * 2 - 1,
-            backend: backend,
+            num_nodes: leaves * 2 - 1,
+            leaf_order,
+            backend,
+        })
+    }
+
+    #[inline]
+    fn regions(&self) -> [NonNull<u8>; 4] {
+        [
+            self.tree.cast(),
+            self.container.cast(),
+            self.dirty.cast(),
+            self.orders.cast(),
+        ]
+    }
+
+    // Copies the metadata byte by byte into fresh memory from the same backend, as if it
+    // was moved there
+    #[cfg(test)]
+    pub fn __relocated(&self) -> Option<Self> {
+        let pages = self.num_nodes.div_ceil(2);
+        let layouts = Self::layouts(pages)?;
+        let space = Self::allocate_space(pages, self.backend)?;
+
+        for ((src, dst), layout) in self.regions().iter().zip(&space).zip(&layouts) {
+            unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dst.as_ptr(), layout.size()) };
+        }
+
+        Some(Self {
+            tree: space[0].cast(),
+            container: space[1].cast(),
+            dirty: space[2].cast(),
+            orders: space[3].cast(),
+            ..*self
         })
     }
 
+    // Derives the node at `pos` from its position alone. Containers are numbered level by
+    // level, so `(2^(k * L) - 1) / (2^L - 1)` of them are rooted above level `k * L`.
+    pub fn compute_node(&self, pos: usize) -> Node {
+        if pos == 0 {
+            return Node {
+                start: 0,
+                size: 0,
+                pos: 0,
+                container_pos: 0,
+                container: 0,
+            };
+        }
+
+        let depth = pos.ilog2() as usize;
+        let size = ((self.num_nodes.div_ceil(2) * PAGE_SIZE) << self.leaf_order) >> depth;
+        let in_container = depth % CONTAINER_LEVELS;
+        let (root, root_depth) = (pos >> in_container, depth - in_container);
+        let above = ((1 << root_depth) - 1) / ((1 << CONTAINER_LEVELS) - 1);
+
+        Node {
+            start: (pos - (1 << depth)) * size,
+            size,
+            pos: pos as u32,
+            container_pos: ((1 << in_container) | (pos & ((1 << in_container) - 1))) as u8,
+            container: (above + root - (1 << root_depth)) as u32,
+        }
+    }
+
+    #[cfg(not(feature = "lazy-init"))]
+    #[inline]
+    fn nodes(&self) -> &[Node] {
+        unsafe { core::slice::from_raw_parts(self.tree.as_ptr(), self.num_nodes + 1) }
+    }
+
+    #[inline]
+    pub fn backend(&self) -> &'a A {
+        self.backend
+    }
+
     #[inline]
     pub fn height(&self) -> usize {
         self.height
     }
 
+    #[inline]
+    pub fn leaf_order(&self) -> usize {
+        self.leaf_order
+    }
+
     #[inline]
     pub fn node_count(&self) -> usize {
         self.num_nodes
     }
 
     #[inline]
-    pub fn node(&self, pos: usize) -> &Node {
-        &self.tree[pos]
+    pub fn node(&self, pos: usize) -> Node {
+        #[cfg(feature = "lazy-init")]
+        {
+            hot_assert!(pos <= self.num_nodes);
+            self.compute_node(pos)
+        }
+
+        #[cfg(not(feature = "lazy-init"))]
+        self.nodes()[pos]
     }
 
     #[inline]
-    pub fn root(&self) -> &Node {
-        &self.tree[1]
+    pub fn root(&self) -> Node {
+        self.node(1)
     }
 
     #[inline]
-    pub fn parent_of(&self, node: &Node) -> &Node {
-        &self.tree[node.pos as usize / 2]
+    pub fn parent_of(&self, node: &Node) -> Node {
+        self.node(node.pos as usize / 2)
     }
 
     #[inline]
-    pub fn left_of(&self, node: &Node) -> &Node {
-        &self.tree[node.pos as usize * 2]
+    pub fn left_of(&self, node: &Node) -> Node {
+        self.node(node.pos as usize * 2)
     }
 
     #[inline]
-    pub fn right_of(&self, node: &Node) -> &Node {
-        &self.tree[node.pos as usize * 2 + 1]
+    pub fn right_of(&self, node: &Node) -> Node {
+        self.node(node.pos as usize * 2 + 1)
     }
 
     pub fn is_leaf(&self, node: &Node) -> bool {
-        node.container_pos >= 8
+        node.container_pos >= FIRST_LEAF
     }
-}
 
-impl<'a, const PAGE_SIZE: usize, A: Allocator> Drop for Tree<'_, PAGE_SIZE, A> {
-    fn drop(&mut self) {
-        use core::ptr::NonNull;
+    #[inline]
+    pub fn containers(&self) -> &[NodeContainer] {
+        unsafe { core::slice::from_raw_parts(self.container.as_ptr(), self.num_containers) }
+    }
 
-        let nodes_count = self.node_count();
+    #[inline]
+    pub fn container_of(&self, node: &Node) -> &NodeContainer {
+        &self.containers()[node.container as usize]
+    }
 
-        let tree_layout =
-            Layout::from_size_align((nodes_count + 1) * size_of::<Node>(), align_of::<Node>())
-                .unwrap();
+    #[inline]
+    pub fn container_index(&self, node: &Node) -> usize {
+        node.container as usize
+    }
 
-        let con_layout = Layout::from_size_align(
-            (nodes_count - 1) * size_of::<NodeContainer>(),
-            align_of::<NodeContainer>(),
-        )
-        .unwrap();
+    /// Returns the heap index of `node`, the root is 1 and the children of `i` are `2 * i`
+    /// and `2 * i + 1`.
+    #[inline]
+    pub fn node_pos(&self, node: &Node) -> u32 {
+        node.pos
+    }
 
+    #[inline]
+    fn dirty(&self) -> &[AtomicUsize] {
         unsafe {
-            let slice = NonNull::new(self.tree.as_mut_ptr() as *mut u8).unwrap();
-            self.backend.deallocate(slice, tree_layout);
+            core::slice::from_raw_parts(
+                self.dirty.as_ptr(),
+                Self::dirty_words(self.num_nodes.div_ceil(2)),
+            )
+        }
+    }
+
+    // Returns the range of leaves covering `pages` pages starting at page `first`
+    #[inline]
+    fn leaves_of(&self, first: usize, pages: usize) -> (usize, usize) {
+        let leaf = first >> self.leaf_order;
+
+        (leaf, (first + pages).div_ceil(1 << self.leaf_order) - leaf)
+    }
+
+    /// Marks `pages` pages starting at page `first` as possibly holding data.
+    ///
+    /// Dirty bits are kept per leaf, so the rest of the leaves covering the pages is marked
+    /// as well.
+    pub fn mark_dirty(&self, first: usize, pages: usize) {
+        let (first, pages) = self.leaves_of(first, pages);
+        let bits = usize::BITS as usize;
+
+        for page in (first..first + pages).step_by(bits) {
+            let n = (first + pages - page).min(bits);
+            let mask = if n == bits {
+                !0
+            } else {
+                ((1 << n) - 1) << (page % bits)
+            };
+
+            self.dirty()[page / bits].fetch_or(mask, Ordering::Release);
+        }
+    }
+
+    /// Returns true if none of `pages` pages starting at page `first` was ever freed.
+    pub fn is_clean(&self, first: usize, pages: usize) -> bool {
+        let (first, pages) = self.leaves_of(first, pages);
+        let bits = usize::BITS as usize;
+
+        (first..first + pages).step_by(bits).all(|page| {
+            let n = (first + pages - page).min(bits);
+            let mask = if n == bits {
+                !0
+            } else {
+                ((1 << n) - 1) << (page % bits)
+            };
+
+            self.dirty()[page / bits].load(Ordering::Acquire) & mask == 0
+        })
+    }
+
+    /// Records that a block of `1 << order` pages starting at page `first` is allocated,
+    /// `None` clears the record. Either way the block is no longer a reservation.
+    #[inline]
+    pub fn set_order(&self, first: usize, order: Option<usize>) {
+        hot_assert!(first.is_multiple_of(1 << self.leaf_order));
+        self.orders()[first >> self.leaf_order]
+            .store(order.map_or(0, |o| o as u8 + 1), Ordering::Relaxed);
+    }
+
+    /// Returns the order of the allocated block starting at page `first`.
+    ///
+    /// Blocks start at leaves, so there is none at a page inside a leaf.
+    #[inline]
+    pub fn order_at(&self, first: usize) -> Option<usize> {
+        if !first.is_multiple_of(1 << self.leaf_order) {
+            return None;
+        }
+
+        let val = self.orders()[first >> self.leaf_order].load(Ordering::Relaxed);
+
+        (val & !RESERVED).checked_sub(1).map(usize::from)
+    }
+
+    /// Marks the allocated block starting at page `first` as a reservation that
+    /// [`Self::reset`] keeps.
+    #[inline]
+    pub fn mark_reserved(&self, first: usize) {
+        hot_assert!(self.order_at(first).is_some());
+        self.orders()[first >> self.leaf_order].fetch_or(RESERVED, Ordering::Relaxed);
+    }
+
+    /// Drops the reservation mark of the block starting at page `first`, if there is one.
+    /// Pages outside of the tree or inside a leaf are ignored.
+    #[inline]
+    pub fn unmark_reserved(&self, first: usize) {
+        if let Some(order) = self
+            .orders()
+            .get(first >> self.leaf_order)
+            .filter(|_| first.is_multiple_of(1 << self.leaf_order))
+        {
+            order.fetch_and(!RESERVED, Ordering::Relaxed);
+        }
+    }
 
-            let slice = NonNull::new(self.container.as_mut_ptr() as *mut u8).unwrap();
-            self.backend.deallocate(slice, con_layout);
+    /// Returns the order of the reservation starting at page `first`.
+    #[inline]
+    pub fn reserved_at(&self, first: usize) -> Option<usize> {
+        let val = self.orders()[first >> self.leaf_order].load(Ordering::Relaxed);
+
+        (val & RESERVED != 0).then(|| usize::from(val & !RESERVED) - 1)
+    }
+
+    #[inline]
+    fn orders(&self) -> &[AtomicU8] {
+        unsafe { core::slice::from_raw_parts(self.orders.as_ptr(), self.num_nodes.div_ceil(2)) }
+    }
+
+    /// Copies container states, dirty bits and page orders from `other` of the same size.
+    pub fn copy_from(&self, other: &Tree<'_, PAGE_SIZE, A>) {
+        for (dst, src) in self.containers().iter().zip(other.containers()) {
+            dst.nodes.store(src.get_state(), Ordering::Relaxed);
+        }
+
+        for (dst, src) in self.dirty().iter().zip(other.dirty()) {
+            dst.store(src.load(Ordering::Acquire), Ordering::Release);
         }
+
+        for (dst, src) in self.orders().iter().zip(other.orders()) {
+            dst.store(src.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+
+    /// Marks every node free and clears page orders but those of reservations, which are
+    /// left for the caller to take again. Dirty bits stay, the pages may still hold data.
+    pub fn reset(&self) {
+        for container in self.containers() {
+            container.nodes.store(0, Ordering::Relaxed);
+        }
+
+        for order in self.orders() {
+            if order.load(Ordering::Relaxed) & RESERVED == 0 {
+                order.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Returns true if `node` is free and none of its ancestors is allocated.
+    pub fn is_free(&self, node: &Node) -> bool {
+        if !NodeState(self.container_of(node).get_state()).is_allocable(node.container_pos) {
+            return false;
+        }
+
+        // Ancestors inside the container are reflected in its state, those above are
+        // only known to the leaves over each container root
+        let mut root = self.container_root(node);
+
+        while root.pos != 1 {
+            let leaf = self.parent_of(&root);
+
+            if NodeState(self.container_of(&leaf).get_state()).is_occupied(leaf.container_pos) {
+                return false;
+            }
+
+            root = self.container_root(&leaf);
+        }
+
+        true
+    }
+
+    /// Calls `f` for every free node whose parent isn't free.
+    ///
+    /// States are loaded container by container, so under concurrent updates the result
+    /// isn't a consistent snapshot.
+    pub fn for_each_free(&self, mut f: impl FnMut(&Node)) {
+        self.visit_free(&self.root(), &mut f);
+    }
+
+    fn visit_free(&self, node: &Node, f: &mut impl FnMut(&Node)) {
+        let state = NodeState(self.container_of(node).get_state());
+        let pos = node.container_pos;
+
+        if state.is_allocable(pos) {
+            return f(node);
+        }
+
+        if node.pos as usize * 2 > self.num_nodes || (self.is_leaf(node) && state.is_occupied(pos))
+        {
+            return;
+        }
+
+        if self.is_leaf(node) {
+            // Children live in the next container, the leaf only knows which of them is taken
+            let (left, right) = (&self.left_of(node), &self.right_of(node));
+
+            if state.is_occupied_left(pos) {
+                self.visit_free(left, f);
+            } else {
+                f(left);
+            }
+
+            if state.is_occupied_rigth(pos) {
+                self.visit_free(right, f);
+            } else {
+                f(right);
+            }
+        } else {
+            self.visit_free(&self.left_of(node), f);
+            self.visit_free(&self.right_of(node), f);
+        }
+    }
+
+    #[inline]
+    pub fn container_root(&self, node: &Node) -> Node {
+        self.node(self.container_root_pos(node) as usize)
+    }
+
+    /// Returns the position of the root of the container holding `node`.
+    #[inline]
+    pub fn container_root_pos(&self, node: &Node) -> u32 {
+        node.pos >> (node.pos.ilog2() as usize % CONTAINER_LEVELS)
+    }
+
+    /// Returns the position of the root of container `index`.
+    pub fn container_root_at(&self, index: usize) -> u32 {
+        let (mut depth, mut first) = (0, 0);
+
+        while first + (1 << depth) <= index {
+            first += 1 << depth;
+            depth += CONTAINER_LEVELS;
+        }
+
+        ((1 << depth) + index - first) as u32
+    }
+}
+
+impl<const PAGE_SIZE: usize, A: Allocator> Drop for Tree<'_, PAGE_SIZE, A> {
+    fn drop(&mut self) {
+        let layouts = Self::layouts(self.node_count().div_ceil(2)).unwrap();
+
+        Self::deallocate_space(&self.regions(), &layouts, self.backend);
     }
 }
@@ -0,0 +1,57 @@
+/// Address of a byte, what `alloc` returns and `free` takes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteAddr(pub usize);
+
+/// Index of a page counted from address 0, i.e. the address divided by the page size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PageIndex(pub usize);
+
+/// Returns the order of the smallest block of `page_size` byte pages holding `bytes`
+/// bytes, the one `alloc` hands out for them.
+///
+/// ```
+/// use lock_free_buddy_allocator::units::order_for_bytes;
+///
+/// // Even an empty request takes a whole page
+/// assert_eq!(order_for_bytes(0, 4096), 0);
+/// assert_eq!(order_for_bytes(4096, 4096), 0);
+/// assert_eq!(order_for_bytes(4097, 4096), 1);
+/// assert_eq!(order_for_bytes(3 * 4096, 4096), 2);
+/// ```
+pub const fn order_for_bytes(bytes: usize, page_size: usize) -> usize {
+    bytes.div_ceil(page_size).next_power_of_two().ilog2() as usize
+}
+
+impl ByteAddr {
+    /// Returns the index of the page starting at this address, `None` if it isn't aligned
+    /// to `PAGE_SIZE`.
+    pub const fn to_page<const PAGE_SIZE: usize>(self) -> Option<PageIndex> {
+        if self.0.is_multiple_of(PAGE_SIZE) {
+            Some(PageIndex(self.0 / PAGE_SIZE))
+        } else {
+            None
+        }
+    }
+}
+
+impl PageIndex {
+    /// Returns the address of the page, `None` if it doesn't fit into `usize`.
+    pub const fn to_addr<const PAGE_SIZE: usize>(self) -> Option<ByteAddr> {
+        match self.0.checked_mul(PAGE_SIZE) {
+            Some(addr) => Some(ByteAddr(addr)),
+            None => None,
+        }
+    }
+}
+
+impl From<ByteAddr> for usize {
+    fn from(addr: ByteAddr) -> usize {
+        addr.0
+    }
+}
+
+impl From<PageIndex> for usize {
+    fn from(page: PageIndex) -> usize {
+        page.0
+    }
+}
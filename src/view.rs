@@ -0,0 +1,98 @@
+use core::alloc::Allocator;
+
+use crate::tree::Tree;
+
+/// Read-only access to the allocator state, see [`crate::buddy_alloc::BuddyAlloc::view`].
+///
+/// Every query reads container states with atomic loads, so a view can be used while other
+/// threads allocate and free, but the results are only snapshots.
+pub struct BuddyView<'t, 'a, const PAGE_SIZE: usize, A: Allocator> {
+    tree: &'t Tree<'a, PAGE_SIZE, A>,
+}
+
+impl<'t, 'a, const PAGE_SIZE: usize, A: Allocator> BuddyView<'t, 'a, PAGE_SIZE, A> {
+    pub(crate) fn new(tree: &'t Tree<'a, PAGE_SIZE, A>) -> Self {
+        Self { tree }
+    }
+
+    /// Returns the number of free pages.
+    pub fn free_pages(&self) -> usize {
+        let mut pages = 0;
+
+        self.tree
+            .for_each_free(|node| pages += node.size / PAGE_SIZE);
+        pages
+    }
+
+    /// Returns the order of the largest free block, if any.
+    pub fn largest_available_order(&self) -> Option<usize> {
+        let mut order = None;
+
+        self.tree.for_each_free(|node| {
+            order = order.max(Some((node.size / PAGE_SIZE).ilog2() as usize))
+        });
+        order
+    }
+
+    /// Returns the number of free bytes.
+    ///
+    /// ```
+    /// #![feature(allocator_api)]
+    /// use lock_free_buddy_allocator::{buddy_alloc::BuddyAlloc, cpuid::Cpu};
+    ///
+    /// struct Cpu0;
+    ///
+    /// impl Cpu for Cpu0 {
+    ///     fn current_cpu() -> usize {
+    ///         0
+    ///     }
+    /// }
+    ///
+    /// let buddy = BuddyAlloc::<4096, Cpu0, _>::new(0, 16, &std::alloc::Global).unwrap();
+    /// buddy.alloc(4).unwrap();
+    ///
+    /// assert_eq!(buddy.view().free_bytes(), 12 * 4096);
+    /// assert_eq!(buddy.view().largest_free_bytes(), 8 * 4096);
+    /// ```
+    pub fn free_bytes(&self) -> usize {
+        self.free_pages() * PAGE_SIZE
+    }
+
+    /// Returns the size in bytes of the largest block `alloc` can currently return, 0 if
+    /// everything is allocated.
+    pub fn largest_free_bytes(&self) -> usize {
+        self.largest_available_order()
+            .map_or(0, |order| PAGE_SIZE << order)
+    }
+
+    /// Returns the number of free blocks of `1 << order` pages that can't be merged into
+    /// a larger free block.
+    pub fn free_blocks(&self, order: usize) -> usize {
+        let mut count = 0;
+
+        self.tree.for_each_free(|node| {
+            if node.size == PAGE_SIZE << order {
+                count += 1;
+            }
+        });
+        count
+    }
+
+    /// Returns the tree position of every block of `1 << order` pages, from the start of the
+    /// arena, together with whether it can be allocated. Orders below the minimum one give
+    /// the leaves.
+    pub fn level_states(&self, order: usize) -> impl Iterator<Item = (u32, bool)> + '_ {
+        let first =
+            self.tree.node_count().div_ceil(2) >> order.saturating_sub(self.tree.leaf_order());
+
+        (first..first * 2).map(|pos| (pos as u32, self.tree.is_free(&self.tree.node(pos))))
+    }
+
+    /// Returns the raw state word of the container at `index`.
+    pub fn container_state(&self, index: usize) -> Option<usize> {
+        self.tree.containers().get(index).map(|c| c.get_state())
+    }
+}
+
+unsafe impl<const PAGE_SIZE: usize, A: Allocator> Send for BuddyView<'_, '_, PAGE_SIZE, A> {}
+unsafe impl<const PAGE_SIZE: usize, A: Allocator> Sync for BuddyView<'_, '_, PAGE_SIZE, A> {}